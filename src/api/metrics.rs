@@ -0,0 +1,151 @@
+//! The `/metrics` page, in the Prometheus text format.
+
+use std::fmt::Write;
+
+use crate::network::stats::Stats;
+
+/// Current values, sampled when the page is requested.
+pub struct Gauges {
+    pub tip_height: usize,
+    pub orphan_blocks: usize,
+    pub mempool_transactions: usize,
+    pub peers: usize,
+    pub hash_rate: f64,
+}
+
+/// Writes metric families, each one with its help and type lines.
+struct Page(String);
+
+impl Page {
+    fn family(&mut self, name: &str, kind: &str, help: &str) {
+        writeln!(self.0, "# HELP {} {}", name, help).unwrap();
+        writeln!(self.0, "# TYPE {} {}", name, kind).unwrap();
+    }
+
+    fn sample(&mut self, name: &str, labels: &str, value: f64) {
+        if labels.is_empty() {
+            writeln!(self.0, "{} {}", name, value).unwrap();
+        } else {
+            writeln!(self.0, "{}{{{}}} {}", name, labels, value).unwrap();
+        }
+    }
+
+    fn gauge(&mut self, name: &str, help: &str, value: f64) {
+        self.family(name, "gauge", help);
+        self.sample(name, "", value);
+    }
+}
+
+pub fn render(stats: &Stats, gauges: &Gauges) -> String {
+    let mut page = Page(String::new());
+
+    page.family("bitcoin_blocks_received_total", "counter", "Blocks received from peers.");
+    page.sample("bitcoin_blocks_received_total", "", stats.blocks_received() as f64);
+    page.family(
+        "bitcoin_blocks_accepted_total",
+        "counter",
+        "Blocks from peers inserted into the blockchain.",
+    );
+    page.sample("bitcoin_blocks_accepted_total", "", stats.blocks_accepted() as f64);
+    page.family(
+        "bitcoin_blocks_rejected_total",
+        "counter",
+        "Invalid blocks from peers, by reason.",
+    );
+    for (reason, n) in stats.blocks_rejected() {
+        page.sample(
+            "bitcoin_blocks_rejected_total",
+            &format!("reason=\"{}\"", reason),
+            n as f64,
+        );
+    }
+
+    let traffic = [
+        ("in", stats.received_by_type()),
+        ("out", stats.sent_by_type()),
+    ];
+    page.family(
+        "bitcoin_network_messages_total",
+        "counter",
+        "Messages exchanged with peers, by direction and type.",
+    );
+    for (direction, by_type) in &traffic {
+        for (kind, traffic) in by_type {
+            page.sample(
+                "bitcoin_network_messages_total",
+                &format!("direction=\"{}\",type=\"{}\"", direction, kind),
+                traffic.messages as f64,
+            );
+        }
+    }
+    page.family(
+        "bitcoin_network_bytes_total",
+        "counter",
+        "Bytes of the messages exchanged with peers, envelope included, by direction and type.",
+    );
+    for (direction, by_type) in &traffic {
+        for (kind, traffic) in by_type {
+            page.sample(
+                "bitcoin_network_bytes_total",
+                &format!("direction=\"{}\",type=\"{}\"", direction, kind),
+                traffic.bytes as f64,
+            );
+        }
+    }
+
+    page.gauge(
+        "bitcoin_tip_height",
+        "Height of the tip of the longest chain.",
+        gauges.tip_height as f64,
+    );
+    page.gauge(
+        "bitcoin_orphan_blocks",
+        "Blocks waiting for their parent.",
+        gauges.orphan_blocks as f64,
+    );
+    page.gauge(
+        "bitcoin_mempool_transactions",
+        "Transactions in the mempool.",
+        gauges.mempool_transactions as f64,
+    );
+    page.gauge("bitcoin_peers", "Connected peers.", gauges.peers as f64);
+    page.gauge(
+        "bitcoin_miner_hash_rate",
+        "Hashes per second of the miner over the last few seconds.",
+        gauges.hash_rate,
+    );
+    page.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_counters_and_gauges() {
+        let stats = Stats::new();
+        stats.received(4, 1200);
+        stats.sent(2, 60);
+        stats.block_received();
+        stats.block_rejected("insufficient_work");
+        let gauges = Gauges {
+            tip_height: 12,
+            orphan_blocks: 3,
+            mempool_transactions: 0,
+            peers: 2,
+            hash_rate: 1500.5,
+        };
+        let page = render(&stats, &gauges);
+        let lines: Vec<&str> = page.lines().collect();
+        assert!(lines.contains(&"# TYPE bitcoin_blocks_received_total counter"));
+        assert!(lines.contains(&"bitcoin_blocks_received_total 1"));
+        assert!(lines.contains(&"bitcoin_blocks_accepted_total 0"));
+        assert!(lines.contains(&"bitcoin_blocks_rejected_total{reason=\"insufficient_work\"} 1"));
+        assert!(lines.contains(&"bitcoin_network_messages_total{direction=\"in\",type=\"Blocks\"} 1"));
+        assert!(lines.contains(&"bitcoin_network_bytes_total{direction=\"in\",type=\"Blocks\"} 1200"));
+        assert!(lines.contains(&"bitcoin_network_bytes_total{direction=\"out\",type=\"NewBlockHashes\"} 60"));
+        assert!(lines.contains(&"bitcoin_tip_height 12"));
+        assert!(lines.contains(&"bitcoin_peers 2"));
+        assert!(lines.contains(&"bitcoin_miner_hash_rate 1500.5"));
+    }
+}
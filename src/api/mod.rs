@@ -1,13 +1,21 @@
+use crate::blockchain::archive;
+use crate::blockchain::snapshot::Snapshot;
 use crate::blockchain::Blockchain;
+use crate::generator::Handle as GeneratorHandle;
+use crate::miner::external::Handle as ExternalMinerHandle;
 use crate::miner::Handle as MinerHandle;
+use crate::network::fault;
 use crate::network::message::Message;
 use crate::network::server::Handle as NetworkServerHandle;
-use crate::types::hash::Hashable;
-use crate::types::transaction::{Mempool, TransactionGenerator};
+use crate::types::address::Address;
+use crate::types::block::Block;
+use crate::types::hash::{Hashable, H256};
+use crate::types::transaction::Mempool;
 use serde::Serialize;
 
 use log::info;
 use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use tiny_http::Header;
@@ -15,12 +23,21 @@ use tiny_http::Response;
 use tiny_http::Server as HTTPServer;
 use url::Url;
 
+mod metrics;
+
 pub struct Server {
     handle: HTTPServer,
     miner: MinerHandle,
+    external_miner: ExternalMinerHandle,
+    generator: GeneratorHandle,
     network: NetworkServerHandle,
     blockchain: Arc<Mutex<Blockchain>>,
     mempool: Arc<Mutex<Mempool>>,
+    orphan_buffer: Arc<Mutex<HashMap<H256, Block>>>,
+    /// Blocks needed on top of a block for it to be final
+    finality_depth: usize,
+    /// Directory the files the API is asked to write go to
+    data_dir: PathBuf,
 }
 
 #[derive(Serialize)]
@@ -51,27 +68,43 @@ macro_rules! respond_json {
 }
 
 impl Server {
+    #[allow(clippy::too_many_arguments)]
     pub fn start(
         addr: std::net::SocketAddr,
         miner: &MinerHandle,
+        external_miner: &ExternalMinerHandle,
+        generator: &GeneratorHandle,
         network: &NetworkServerHandle,
         blockchain: &Arc<Mutex<Blockchain>>,
         mempool: &Arc<Mutex<Mempool>>,
+        orphan_buffer: &Arc<Mutex<HashMap<H256, Block>>>,
+        finality_depth: usize,
+        data_dir: &Path,
     ) {
         let handle = HTTPServer::http(&addr).unwrap();
         let server = Self {
             handle,
             miner: miner.clone(),
+            external_miner: external_miner.clone(),
+            generator: generator.clone(),
             network: network.clone(),
             blockchain: Arc::clone(blockchain),
             mempool: Arc::clone(mempool),
+            orphan_buffer: Arc::clone(orphan_buffer),
+            finality_depth,
+            data_dir: data_dir.to_path_buf(),
         };
         thread::spawn(move || {
             for req in server.handle.incoming_requests() {
                 let miner = server.miner.clone();
+                let external_miner = server.external_miner.clone();
+                let generator = server.generator.clone();
                 let network = server.network.clone();
                 let blockchain = Arc::clone(&server.blockchain);
                 let mempool = Arc::clone(&server.mempool);
+                let orphan_buffer = Arc::clone(&server.orphan_buffer);
+                let finality_depth = server.finality_depth;
+                let data_dir = server.data_dir.clone();
                 thread::spawn(move || {
                     // a valid url requires a base
                     let base_url = Url::parse(&format!("http://{}/", &addr)).unwrap();
@@ -107,23 +140,302 @@ impl Server {
                             miner.start(lambda);
                             respond_result!(req, true, "ok");
                         }
+                        "/miner/status" => {
+                            respond_json!(req, miner.status());
+                        }
+                        "/miner/pause" => {
+                            miner.pause();
+                            respond_result!(req, true, "ok");
+                        }
+                        "/miner/stop" => {
+                            miner.exit();
+                            respond_result!(req, true, "ok");
+                        }
+                        "/miner/lambda" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let lambda = match params.get("lambda") {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, false, "missing lambda");
+                                    return;
+                                }
+                            };
+                            let lambda = match lambda.parse::<u64>() {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error parsing lambda: {}", e)
+                                    );
+                                    return;
+                                }
+                            };
+                            miner.set_lambda(lambda);
+                            respond_result!(req, true, "ok");
+                        }
+                        "/miner/generate" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let n = match params.get("n") {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, false, "missing n");
+                                    return;
+                                }
+                            };
+                            let n = match n.parse::<usize>() {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error parsing n: {}", e)
+                                    );
+                                    return;
+                                }
+                            };
+                            match external_miner.generate(n) {
+                                Ok(hashes) => {
+                                    let v_string: Vec<String> =
+                                        hashes.into_iter().map(|h| h.to_string()).collect();
+                                    respond_json!(req, v_string);
+                                }
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        "/mining/template" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let coinbase = match params.get("coinbase").map(|v| v.parse::<Address>()) {
+                                Some(Ok(v)) => Some(v),
+                                Some(Err(e)) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error parsing coinbase: {}", e)
+                                    );
+                                    return;
+                                }
+                                None => None,
+                            };
+                            respond_json!(req, external_miner.template(coinbase));
+                        }
+                        "/mining/submit" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let header = match params.get("header") {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, false, "missing header");
+                                    return;
+                                }
+                            };
+                            let nonce = match params.get("nonce") {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, false, "missing nonce");
+                                    return;
+                                }
+                            };
+                            let nonce = match nonce.parse::<u32>() {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error parsing nonce: {}", e)
+                                    );
+                                    return;
+                                }
+                            };
+                            match external_miner.submit(header, nonce) {
+                                Ok(hash) => respond_result!(req, true, hash),
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
                         "/tx-generator/start" => {
                             let params = url.query_pairs();
                             let params: HashMap<_, _> = params.into_owned().collect();
                             let theta = match params.get("theta") {
-                                Some(v) => v.parse::<u64>(),
+                                Some(v) => v,
                                 None => {
                                     respond_result!(req, false, "missing theta");
                                     return;
                                 }
                             };
-                            TransactionGenerator::start(theta.unwrap(), network, mempool);
+                            let theta = match theta.parse::<u64>() {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error parsing theta: {}", e)
+                                    );
+                                    return;
+                                }
+                            };
+                            match generator.start(theta) {
+                                Ok(()) => respond_result!(req, true, "ok"),
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        "/tx-generator/stop" => {
+                            generator.stop();
+                            respond_result!(req, true, "ok");
+                        }
+                        "/tx-generator/configure" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let mut values = Vec::new();
+                            for name in &["theta", "max_value"] {
+                                match params.get(*name).map(|v| v.parse::<u64>()) {
+                                    Some(Ok(v)) => values.push(Some(v)),
+                                    Some(Err(e)) => {
+                                        respond_result!(
+                                            req,
+                                            false,
+                                            format!("error parsing {}: {}", name, e)
+                                        );
+                                        return;
+                                    }
+                                    None => values.push(None),
+                                }
+                            }
+                            generator.configure(values[0], values[1]);
                             respond_result!(req, true, "ok");
                         }
                         "/network/ping" => {
                             network.broadcast(Message::Ping(String::from("Test ping")));
                             respond_result!(req, true, "ok");
                         }
+                        "/network/bans" => {
+                            respond_json!(req, network.bans());
+                        }
+                        "/network/bans/clear" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let ip = match params.get("ip") {
+                                Some(v) => match v.parse::<std::net::IpAddr>() {
+                                    Ok(ip) => Some(ip),
+                                    Err(e) => {
+                                        respond_result!(
+                                            req,
+                                            false,
+                                            format!("error parsing ip: {}", e)
+                                        );
+                                        return;
+                                    }
+                                },
+                                None => None,
+                            };
+                            let cleared = network.clear_bans(ip);
+                            respond_result!(req, true, format!("cleared {} bans", cleared));
+                        }
+                        "/network/peers" => {
+                            respond_json!(req, network.peers());
+                        }
+                        "/network/fault" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let peer = match params.get("peer").map(|v| v.parse::<std::net::SocketAddr>()) {
+                                Some(Ok(v)) => Some(v),
+                                Some(Err(e)) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error parsing peer: {}", e)
+                                    );
+                                    return;
+                                }
+                                None => None,
+                            };
+                            let latency = match params.get("latency").map(|v| v.parse::<u64>()) {
+                                Some(Ok(v)) if v <= fault::MAX_LATENCY_MS => Some(v),
+                                Some(Ok(v)) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!(
+                                            "latency must be at most {} ms, got {}",
+                                            fault::MAX_LATENCY_MS,
+                                            v
+                                        )
+                                    );
+                                    return;
+                                }
+                                Some(Err(e)) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error parsing latency: {}", e)
+                                    );
+                                    return;
+                                }
+                                None => None,
+                            };
+                            let drop = match params.get("drop").map(|v| v.parse::<f64>()) {
+                                Some(Ok(v)) if (0.0..=100.0).contains(&v) => Some(v),
+                                Some(Ok(v)) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("drop must be between 0 and 100, got {}", v)
+                                    );
+                                    return;
+                                }
+                                Some(Err(e)) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error parsing drop: {}", e)
+                                    );
+                                    return;
+                                }
+                                None => None,
+                            };
+                            network.faults().set(peer, latency, drop);
+                            respond_result!(req, true, "ok");
+                        }
+                        "/network/partition" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let peers = match params.get("peers") {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, false, "missing peers");
+                                    return;
+                                }
+                            };
+                            let peers: Result<Vec<std::net::SocketAddr>, _> =
+                                peers.split(',').map(|peer| peer.trim().parse()).collect();
+                            let peers = match peers {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error parsing peers: {}", e)
+                                    );
+                                    return;
+                                }
+                            };
+                            network.faults().partition(&peers);
+                            respond_result!(
+                                req,
+                                true,
+                                format!("partitioned from {} peers", peers.len())
+                            );
+                        }
+                        "/network/heal" => {
+                            network.faults().heal();
+                            respond_result!(req, true, "ok");
+                        }
+                        "/blockchain/forks" => {
+                            let blockchain = blockchain.lock().unwrap();
+                            respond_json!(req, blockchain.fork_stats());
+                        }
                         "/blockchain/longest-chain" => {
                             let blockchain = blockchain.lock().unwrap();
                             let v = blockchain.all_blocks_in_longest_chain();
@@ -131,6 +443,15 @@ impl Server {
                                 v.into_iter().map(|h| h.to_string()).collect();
                             respond_json!(req, v_string);
                         }
+                        "/blockchain/longest-chain-work" => {
+                            let blockchain = blockchain.lock().unwrap();
+                            let v = blockchain.all_blocks_in_longest_chain();
+                            let blocks: Vec<_> = v
+                                .iter()
+                                .map(|h| blockchain.block_info(h).unwrap())
+                                .collect();
+                            respond_json!(req, blocks);
+                        }
                         "/blockchain/longest-chain-tx" => {
                             let blockchain = blockchain.lock().unwrap();
                             let v = blockchain.all_blocks_in_longest_chain();
@@ -145,6 +466,212 @@ impl Server {
                             }
                             respond_json!(req, final_output);
                         }
+                        "/blockchain/state" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let block = match params.get("block") {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, false, "missing block");
+                                    return;
+                                }
+                            };
+                            let block = match block.parse::<usize>() {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error parsing block: {}", e)
+                                    );
+                                    return;
+                                }
+                            };
+                            let blockchain = blockchain.lock().unwrap();
+                            let state = blockchain
+                                .ancestor(&blockchain.tip(), block)
+                                .and_then(|hash| blockchain.state(&hash));
+                            match state {
+                                Some(state) => respond_json!(req, state.accounts()),
+                                None => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("no state at height {}", block)
+                                    );
+                                }
+                            }
+                        }
+                        "/state/snapshot" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let path = match params.get("path") {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, false, "missing path");
+                                    return;
+                                }
+                            };
+                            let block = match params.get("block").map(|v| v.parse::<usize>()) {
+                                Some(Ok(v)) => Some(v),
+                                Some(Err(e)) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error parsing block: {}", e)
+                                    );
+                                    return;
+                                }
+                                None => None,
+                            };
+                            let snapshot = {
+                                let blockchain = blockchain.lock().unwrap();
+                                let tip = blockchain.tip();
+                                match block {
+                                    Some(height) => blockchain.ancestor(&tip, height),
+                                    None => Some(tip),
+                                }
+                                .and_then(|hash| Snapshot::take(&blockchain, &hash))
+                            };
+                            let snapshot = match snapshot {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("no state at height {}", block.unwrap_or(0))
+                                    );
+                                    return;
+                                }
+                            };
+                            let saved = data_path(&data_dir, path)
+                                .and_then(|path| snapshot.save(&path));
+                            match saved {
+                                Ok(()) => respond_json!(req, snapshot.info()),
+                                Err(e) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error writing snapshot: {}", e)
+                                    );
+                                }
+                            }
+                        }
+                        "/blockchain/export" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let all = match params.get("all").map(|v| v.parse::<bool>()) {
+                                Some(Ok(v)) => v,
+                                Some(Err(e)) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error parsing all: {}", e)
+                                    );
+                                    return;
+                                }
+                                None => false,
+                            };
+                            // the chain file itself, for the caller to write
+                            let mut bytes = Vec::new();
+                            let written = {
+                                let blockchain = blockchain.lock().unwrap();
+                                let blocks = archive::blocks(&blockchain, all);
+                                archive::write(&mut bytes, &blocks)
+                            };
+                            match written {
+                                Ok(_) => {
+                                    let content_type = "Content-Type: application/octet-stream"
+                                        .parse::<Header>()
+                                        .unwrap();
+                                    let resp = Response::from_data(bytes).with_header(content_type);
+                                    req.respond(resp).unwrap();
+                                }
+                                Err(e) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error encoding chain: {}", e)
+                                    );
+                                }
+                            }
+                        }
+                        "/blockchain/finalized" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let depth = match params.get("k") {
+                                Some(v) => match v.parse::<usize>() {
+                                    Ok(v) => v,
+                                    Err(e) => {
+                                        respond_result!(
+                                            req,
+                                            false,
+                                            format!("error parsing k: {}", e)
+                                        );
+                                        return;
+                                    }
+                                },
+                                None => finality_depth,
+                            };
+                            let blockchain = blockchain.lock().unwrap();
+                            match blockchain.finalized(depth) {
+                                Some(hash) => {
+                                    respond_json!(req, blockchain.block_info(&hash).unwrap());
+                                }
+                                None => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("no block has {} confirmations yet", depth)
+                                    );
+                                }
+                            }
+                        }
+                        "/tx/status" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let hash = match params.get("hash") {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, false, "missing hash");
+                                    return;
+                                }
+                            };
+                            let hash = match hash.parse::<H256>() {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error parsing hash: {}", e)
+                                    );
+                                    return;
+                                }
+                            };
+                            let blockchain = blockchain.lock().unwrap();
+                            let mempool = mempool.lock().unwrap();
+                            respond_json!(req, blockchain.tx_status(&hash, &mempool));
+                        }
+                        "/metrics" => {
+                            let tip_height = {
+                                let blockchain = blockchain.lock().unwrap();
+                                blockchain.block_heights[&blockchain.tip()]
+                            };
+                            let gauges = metrics::Gauges {
+                                tip_height,
+                                orphan_blocks: orphan_buffer.lock().unwrap().len(),
+                                mempool_transactions: mempool.lock().unwrap().tx_map.len(),
+                                peers: network.peers().len(),
+                                hash_rate: miner.status().hash_rate,
+                            };
+                            let content_type = "Content-Type: text/plain; version=0.0.4"
+                                .parse::<Header>()
+                                .unwrap();
+                            let resp =
+                                Response::from_string(metrics::render(network.stats(), &gauges))
+                                    .with_header(content_type);
+                            req.respond(resp).unwrap();
+                        }
                         "/blockchain/longest-chain-tx-count" => {
                             // unimplemented!()
                             respond_result!(req, false, "unimplemented!");
@@ -170,3 +697,42 @@ impl Server {
         info!("API server listening at {}", &addr);
     }
 }
+
+/// Resolve a file name given to the API in `data_dir`, creating the directories it needs. Only
+/// relative paths that stay in `data_dir` are accepted.
+fn data_path(data_dir: &Path, name: &str) -> Result<PathBuf, String> {
+    let name = Path::new(name);
+    let inside = name
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)));
+    if name.as_os_str().is_empty() || !inside {
+        return Err(format!(
+            "{} is not a relative path in the data directory",
+            name.display()
+        ));
+    }
+    let path = data_dir.join(name);
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+    }
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_stay_in_data_dir() {
+        let data_dir = std::env::temp_dir().join(format!("api-data-{}", std::process::id()));
+        assert_eq!(
+            data_path(&data_dir, "runs/snapshot.bin").unwrap(),
+            data_dir.join("runs").join("snapshot.bin")
+        );
+        assert!(data_dir.join("runs").is_dir());
+        for name in &["", "/etc/passwd", "../outside", "runs/../../outside", "./snapshot.bin"] {
+            assert!(data_path(&data_dir, name).is_err(), "{}", name);
+        }
+        std::fs::remove_dir_all(&data_dir).unwrap();
+    }
+}
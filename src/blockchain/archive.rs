@@ -0,0 +1,112 @@
+//! Chain files, so that the same blocks can be replayed into fresh nodes.
+//!
+//! A chain file is a sequence of blocks, each one the big-endian `u32` length of its bincode
+//! encoding followed by the encoding. Parents always come before their children, and the genesis
+//! block is left out since it follows from the chain spec.
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::Path;
+
+use super::Blockchain;
+use crate::types::block::Block;
+use crate::types::hash::{Hashable, H256};
+
+/// The blocks to export, parents first: the ones of the longest chain, or with `all` every block
+/// we have, side forks included. Blocks we only know the header of are left out.
+pub fn blocks(chain: &Blockchain, all: bool) -> Vec<&Block> {
+    let mut hashes: Vec<H256> = if all {
+        chain.block_map.keys().copied().collect()
+    } else {
+        chain.all_blocks_in_longest_chain()
+    };
+    hashes.retain(|hash| chain.block_heights[hash] > 0);
+    hashes.sort_by_key(|hash| (chain.block_heights[hash], *hash));
+    hashes.iter().filter_map(|hash| chain.block(hash)).collect()
+}
+
+/// Write blocks in the chain file format, and return how many were written.
+pub fn write<W: Write>(writer: W, blocks: &[&Block]) -> Result<usize, String> {
+    let mut writer = BufWriter::new(writer);
+    for block in blocks {
+        let bytes = bincode::serialize(block).map_err(|e| e.to_string())?;
+        writer
+            .write_all(&(bytes.len() as u32).to_be_bytes())
+            .and_then(|_| writer.write_all(&bytes))
+            .map_err(|e| e.to_string())?;
+    }
+    writer.flush().map_err(|e| e.to_string())?;
+    Ok(blocks.len())
+}
+
+/// Insert the blocks of a chain file, with the same validation as the blocks we get from peers,
+/// and return how many were new. Stops at the first invalid block.
+pub fn import(chain: &mut Blockchain, path: &Path) -> Result<usize, String> {
+    let file = File::open(path).map_err(|e| e.to_string())?;
+    let mut reader = BufReader::new(file);
+    let mut imported = 0;
+    for index in 0.. {
+        let mut length = [0u8; 4];
+        match reader.read_exact(&mut length) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.to_string()),
+        }
+        // no valid block is larger than the chain spec allows
+        let length = u32::from_be_bytes(length) as usize;
+        if length > chain.spec().max_block_size {
+            return Err(format!("block {} is too large ({} bytes)", index, length));
+        }
+        let mut bytes = vec![0u8; length];
+        reader
+            .read_exact(&mut bytes)
+            .map_err(|e| format!("error reading block {}: {}", index, e))?;
+        let block: Block = bincode::deserialize(&bytes)
+            .map_err(|e| format!("error decoding block {}: {}", index, e))?;
+        let hash = block.hash();
+        if chain.block_map.contains_key(&hash) {
+            continue;
+        }
+        chain
+            .insert(&block)
+            .map_err(|e| format!("invalid block {} ({}): {}", index, hash, e))?;
+        imported += 1;
+    }
+    Ok(imported)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::block::generate_random_block;
+
+    #[test]
+    fn export_and_import() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        for _ in 0..5 {
+            let block = generate_random_block(&blockchain.tip());
+            blockchain.insert(&block).unwrap();
+        }
+        let fork = generate_random_block(&genesis_hash);
+        blockchain.insert(&fork).unwrap();
+        assert_eq!(blocks(&blockchain, false).len(), 5);
+        assert_eq!(blocks(&blockchain, true).len(), 6);
+
+        let path = std::env::temp_dir().join(format!("chain-{}.bin", blockchain.tip()));
+        let file = File::create(&path).unwrap();
+        assert_eq!(write(file, &blocks(&blockchain, true)).unwrap(), 6);
+        let mut replayed = Blockchain::new();
+        assert_eq!(import(&mut replayed, &path).unwrap(), 6);
+        assert_eq!(import(&mut replayed, &path).unwrap(), 0);
+        assert_eq!(replayed.tip(), blockchain.tip());
+        assert!(replayed.block_map.contains_key(&fork.hash()));
+
+        // a block that does not connect stops the import
+        let orphan = generate_random_block(&[7u8; 32].into());
+        write(File::create(&path).unwrap(), &[&orphan]).unwrap();
+        let result = import(&mut Blockchain::new(), &path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(result.unwrap_err().contains("unknown parent"));
+    }
+}
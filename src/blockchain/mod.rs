@@ -1,9 +1,177 @@
-use std::collections::HashMap;
+pub mod archive;
+pub mod pruner;
+pub mod snapshot;
+pub mod spec;
+pub mod state;
+
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
 use std::error::Error;
 
-use crate::types::block::{Block, Header};
+use crate::types::block::{unix_millis, Block};
 use crate::types::hash::{Hashable, H256};
 use crate::types::merkle::MerkleTree;
+use crate::types::transaction::Mempool;
+use spec::ChainSpec;
+use state::{State, TxError};
+
+/// Number of blocks whose median timestamp a new block has to exceed.
+pub const MEDIAN_TIME_SPAN: usize = 11;
+
+/// Reasons a block cannot be inserted into the blockchain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// The block is in the blockchain already.
+    Duplicate,
+    /// The parent is not in the blockchain.
+    UnknownParent,
+    /// The difficulty differs from the one of the parent.
+    WrongDifficulty,
+    /// The hash is above the difficulty.
+    InsufficientWork,
+    /// The merkle root does not match the transactions.
+    BadMerkleRoot,
+    /// The timestamp is not above the median time past of the parent.
+    TimestampTooOld,
+    /// The timestamp is too far ahead of our clock.
+    TimestampInFuture,
+    /// The parent predates the snapshot we started from, so there is no state to execute on.
+    NoParentState,
+    /// The block is not on the chain of the checkpoints.
+    CheckpointConflict,
+    /// The block holds more transactions than the chain spec allows.
+    TooManyTransactions,
+    /// The serialized block is larger than the chain spec allows.
+    TooLarge,
+    /// The block reward overflows the balance of the coinbase account.
+    RewardOverflow,
+    /// One of the transactions cannot be executed.
+    Transaction(TxError),
+}
+
+impl std::fmt::Display for BlockError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            BlockError::Duplicate => write!(f, "already in the blockchain"),
+            BlockError::UnknownParent => write!(f, "unknown parent"),
+            BlockError::WrongDifficulty => write!(f, "wrong difficulty"),
+            BlockError::InsufficientWork => write!(f, "hash above difficulty"),
+            BlockError::BadMerkleRoot => write!(f, "merkle root mismatch"),
+            BlockError::TimestampTooOld => write!(f, "timestamp not above median time past"),
+            BlockError::TimestampInFuture => write!(f, "timestamp too far in the future"),
+            BlockError::NoParentState => write!(f, "parent predates the snapshot"),
+            BlockError::CheckpointConflict => write!(f, "conflicts with a checkpoint"),
+            BlockError::TooManyTransactions => write!(f, "too many transactions"),
+            BlockError::TooLarge => write!(f, "block too large"),
+            BlockError::RewardOverflow => write!(f, "block reward overflows the coinbase balance"),
+            BlockError::Transaction(e) => write!(f, "invalid transaction: {}", e),
+        }
+    }
+}
+
+impl BlockError {
+    /// Short name of the error, without its details, as used in metric labels.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            BlockError::Duplicate => "duplicate",
+            BlockError::UnknownParent => "unknown_parent",
+            BlockError::WrongDifficulty => "wrong_difficulty",
+            BlockError::InsufficientWork => "insufficient_work",
+            BlockError::BadMerkleRoot => "bad_merkle_root",
+            BlockError::TimestampTooOld => "timestamp_too_old",
+            BlockError::TimestampInFuture => "timestamp_in_future",
+            BlockError::NoParentState => "no_parent_state",
+            BlockError::CheckpointConflict => "checkpoint_conflict",
+            BlockError::TooManyTransactions => "too_many_transactions",
+            BlockError::TooLarge => "too_large",
+            BlockError::RewardOverflow => "reward_overflow",
+            BlockError::Transaction(_) => "invalid_transaction",
+        }
+    }
+
+    /// Whether the block is invalid for every node, so that the peer sending it misbehaves. A
+    /// block may be from the future for our clock only, or have a parent or a checkpoint
+    /// conflict that only we lack or configured, and sending a block twice is no offense.
+    pub fn is_misbehavior(&self) -> bool {
+        !matches!(
+            self,
+            BlockError::Duplicate
+                | BlockError::UnknownParent
+                | BlockError::TimestampInFuture
+                | BlockError::NoParentState
+                | BlockError::CheckpointConflict
+        )
+    }
+}
+
+impl Error for BlockError {}
+
+impl From<TxError> for BlockError {
+    fn from(e: TxError) -> Self {
+        BlockError::Transaction(e)
+    }
+}
+
+/// Expected number of hashes to find a block with this difficulty, that is 2^256 / (difficulty + 1).
+/// Only the top 128 bits of the difficulty are used, which is exact enough for any difficulty
+/// above 2^128; harder ones saturate.
+pub fn block_work(difficulty: &H256) -> u128 {
+    let bytes: &[u8] = difficulty.as_ref();
+    let top = u128::from_be_bytes(bytes[..16].try_into().unwrap());
+    match top.checked_add(1) {
+        // 2^128 / (top + 1), computed without overflowing
+        Some(divisor) => (!top / divisor).saturating_add(1),
+        None => 1,
+    }
+}
+
+/// A block as shown by the explorer API.
+#[derive(Serialize, Debug, Clone)]
+pub struct BlockInfo {
+    pub hash: String,
+    pub parent: String,
+    pub height: usize,
+    pub timestamp: u64,
+    pub transactions: usize,
+    /// Work of the block itself, in decimal.
+    pub work: String,
+    /// Total work of the chain ending with the block, in decimal.
+    pub chain_work: String,
+}
+
+/// Where a transaction is, as reported by `/tx/status`.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum TxStatus {
+    Unknown,
+    /// Waiting in the mempool, and in no block of the longest chain.
+    Mempool,
+    /// Only in blocks off the longest chain.
+    SideFork { blocks: Vec<String> },
+    /// In the longest chain, `confirmations` counts the block itself and the ones on top of it.
+    Confirmed {
+        block: String,
+        height: usize,
+        confirmations: usize,
+    },
+}
+
+/// How often the blocks we received ended up off the longest chain, as reported by
+/// `/blockchain/forks`.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ForkStats {
+    /// Blocks inserted since genesis, or since the snapshot we started from
+    pub blocks: usize,
+    /// Inserted blocks that are not in the longest chain
+    pub stale_blocks: usize,
+    /// Share of the inserted blocks that are stale
+    pub fork_rate: f64,
+    /// Times the tip moved to a block that does not extend the previous tip
+    pub reorgs: usize,
+    /// Most blocks of the longest chain abandoned by a single reorg
+    pub deepest_reorg: usize,
+}
 
 pub struct Blockchain {
     // hashmap to store blocks
@@ -12,52 +180,455 @@ pub struct Blockchain {
     pub block_heights: HashMap<H256, usize>,
     // latest block
     latest_block: H256,
+    // hashmap from block hash to the total work of the chain ending with the block
+    chain_work: HashMap<H256, u128>,
+    // hashmap from transaction hash to the blocks holding the transaction
+    tx_blocks: HashMap<H256, Vec<H256>>,
+    // hashmap from block hash to the ledger state after executing the block
+    states: HashMap<H256, State>,
+    // parameters of the chain, including the block reward and size limits
+    spec: ChainSpec,
+    // height of the block we started from, genesis or the block of a snapshot
+    base_height: usize,
+    // hashmap from block hash to the highest checkpoint on the chain ending with the block, for
+    // the blocks that descend from a checkpoint
+    checkpoint_of: HashMap<H256, H256>,
+    // hashes of blocks proven to lead to a checkpoint, whose transactions are trusted
+    vouched: HashSet<H256>,
+    // blocks inserted since the start, and the reorgs they caused
+    inserted: usize,
+    reorgs: usize,
+    deepest_reorg: usize,
 }
 
 impl Blockchain {
-    /// Create a new blockchain, only containing the genesis block
+    /// Create a new blockchain, only containing the genesis block of the default chain spec
     pub fn new() -> Self {
-        let parent: H256 = [255u8; 32].into();
-        let nonce = 0u32;
-        let bytes = [255u8; 32];
-        // bytes[2] = 1u8;
-        let difficulty: H256 = bytes.into(); // remember the difficulty is the number of zeros on the left until it hits the first nonzero value
-        let tx: Vec<H256> = Vec::new();
-        let empty_tree = MerkleTree::new(&tx);
-        let merkle_root = empty_tree.root();
-        let genesis_block = Block {
-            header: Header {
-                parent,
-                nonce,
-                difficulty,
-                timestamp: 0,
-                merkle_root,
-            },
-            data: { Vec::new() },
-        };
+        Self::from_spec(&ChainSpec::default())
+    }
+
+    /// Create a new blockchain, only containing the genesis block of `spec`
+    pub fn from_spec(spec: &ChainSpec) -> Self {
+        let genesis_block = spec.genesis_block();
         let mut blocks = HashMap::new();
         let genesis_block_hash = genesis_block.hash();
         blocks.insert(genesis_block_hash, genesis_block);
         let mut block_heights = HashMap::new();
         block_heights.insert(genesis_block_hash, 0);
+        let mut states = HashMap::new();
+        states.insert(genesis_block_hash, spec.genesis_state());
+        let mut chain_work = HashMap::new();
+        chain_work.insert(genesis_block_hash, block_work(&spec.difficulty));
+        let mut checkpoint_of = HashMap::new();
+        if spec.checkpoints.iter().any(|(_, c)| *c == genesis_block_hash) {
+            checkpoint_of.insert(genesis_block_hash, genesis_block_hash);
+        }
 
         Self {
             block_map: blocks,
             block_heights,
             latest_block: genesis_block_hash,
+            chain_work,
+            tx_blocks: HashMap::new(),
+            states,
+            spec: spec.clone(),
+            base_height: 0,
+            checkpoint_of,
+            vouched: HashSet::new(),
+            inserted: 0,
+            reorgs: 0,
+            deepest_reorg: 0,
+        }
+    }
+
+    /// Create a blockchain starting from a verified snapshot instead of genesis. Its blocks
+    /// only have their header, and only the state after the last one is known
+    pub fn from_snapshot(spec: &ChainSpec, snapshot: &snapshot::Snapshot) -> Self {
+        let mut block_map = HashMap::new();
+        let mut block_heights = HashMap::new();
+        let mut chain_work = HashMap::new();
+        let mut height = snapshot.height;
+        let mut work = snapshot.chain_work;
+        for header in snapshot.headers.iter().rev() {
+            let hash = header.hash();
+            block_heights.insert(hash, height);
+            chain_work.insert(hash, work);
+            work = work.saturating_sub(block_work(&header.difficulty));
+            height = height.saturating_sub(1);
+            let block = Block {
+                header: header.clone(),
+                data: Vec::new(),
+            };
+            block_map.insert(hash, block);
+        }
+        let mut states = HashMap::new();
+        states.insert(snapshot.block, State::from_entries(&snapshot.accounts));
+        // the headers go from the oldest to the snapshot block
+        let mut checkpoint_of = HashMap::new();
+        let mut checkpoint = None;
+        for header in &snapshot.headers {
+            let hash = header.hash();
+            if spec.checkpoints.iter().any(|(_, c)| *c == hash) {
+                checkpoint = Some(hash);
+            }
+            if let Some(checkpoint) = checkpoint {
+                checkpoint_of.insert(hash, checkpoint);
+            }
+        }
+
+        Self {
+            block_map,
+            block_heights,
+            latest_block: snapshot.block,
+            chain_work,
+            tx_blocks: HashMap::new(),
+            states,
+            spec: spec.clone(),
+            base_height: snapshot.height,
+            checkpoint_of,
+            vouched: HashSet::new(),
+            inserted: 0,
+            reorgs: 0,
+            deepest_reorg: 0,
+        }
+    }
+
+    /// Check a block against its parent, and return the state after executing it
+    pub fn validate(&self, block: &Block) -> Result<State, BlockError> {
+        let parent = match self.block_map.get(&block.header.parent) {
+            Some(parent) => parent,
+            None => return Err(BlockError::UnknownParent),
+        };
+        let height = self.block_heights[&block.header.parent] + 1;
+        self.check_checkpoints(block, height)?;
+        if block.get_difficulty() != parent.get_difficulty() {
+            return Err(BlockError::WrongDifficulty);
+        }
+        if block.hash() > block.get_difficulty() {
+            return Err(BlockError::InsufficientWork);
+        }
+        if block.header.timestamp <= self.median_time_past(&block.header.parent) {
+            return Err(BlockError::TimestampTooOld);
+        }
+        if block.header.timestamp > unix_millis().saturating_add(self.spec.max_future_drift) {
+            return Err(BlockError::TimestampInFuture);
+        }
+        if block.data.len() > self.spec.max_block_transactions {
+            return Err(BlockError::TooManyTransactions);
+        }
+        if bincode::serialized_size(block).unwrap() > self.spec.max_block_size as u64 {
+            return Err(BlockError::TooLarge);
+        }
+        if MerkleTree::new(&block.data).root() != block.header.merkle_root {
+            return Err(BlockError::BadMerkleRoot);
+        }
+        let mut state = match self.states.get(&block.header.parent) {
+            Some(state) => state.clone(),
+            None => return Err(BlockError::NoParentState),
+        };
+        // the network validated the blocks of the checkpoint chain already
+        let hash = block.hash();
+        if self.vouched.contains(&hash) || self.is_checkpoint(&hash) {
+            for tx in &block.data {
+                state.execute_trusted(tx);
+            }
+        } else {
+            for tx in &block.data {
+                state.apply(tx)?;
+            }
+        }
+        if !state.credit(&block.header.coinbase, self.spec.block_reward) {
+            return Err(BlockError::RewardOverflow);
+        }
+        Ok(state)
+    }
+
+    /// Check that a block at `height` matches the checkpoint at its height, if any, and that it
+    /// does not fork off below the last checkpoint we have
+    fn check_checkpoints(&self, block: &Block, height: usize) -> Result<(), BlockError> {
+        let hash = block.hash();
+        let checkpoints = &self.spec.checkpoints;
+        if checkpoints.iter().any(|(h, c)| *h == height && *c != hash) {
+            return Err(BlockError::CheckpointConflict);
+        }
+        let reached = checkpoints
+            .iter()
+            .rev()
+            .find(|(_, c)| self.block_map.contains_key(c));
+        if let Some((checkpoint_height, checkpoint)) = reached {
+            // we have every block of the checkpoint chain up to the checkpoint, so a new block
+            // at these heights forks off below it
+            if height <= *checkpoint_height
+                || self.checkpoint_of.get(&block.header.parent) != Some(checkpoint)
+            {
+                return Err(BlockError::CheckpointConflict);
+            }
+        }
+        Ok(())
+    }
+
+    fn is_checkpoint(&self, hash: &H256) -> bool {
+        self.spec.checkpoints.iter().any(|(_, c)| c == hash)
+    }
+
+    /// Take note of a block whose parent is missing. If the block is a checkpoint, or leads to
+    /// one, so does its parent, whose transactions are then trusted once it arrives.
+    pub fn vouch(&mut self, block: &Block) {
+        let hash = block.hash();
+        if self.vouched.contains(&hash) || self.is_checkpoint(&hash) {
+            self.vouched.insert(block.get_parent());
         }
     }
 
-    /// Insert a block into blockchain
-    pub fn insert(&mut self, block: &Block) {
+    /// Insert a block into blockchain. Fails if the block is not valid or already in the
+    /// blockchain, in which case the blockchain is left untouched
+    pub fn insert(&mut self, block: &Block) -> Result<(), BlockError> {
         let parent = block.header.parent;
         let hash = block.hash();
+        if self.block_map.contains_key(&hash) {
+            return Err(BlockError::Duplicate);
+        }
+        let state = self.validate(block)?;
+        self.states.insert(hash, state);
+        self.vouched.remove(&hash);
+        let checkpoint = if self.is_checkpoint(&hash) {
+            Some(hash)
+        } else {
+            self.checkpoint_of.get(&parent).copied()
+        };
+        if let Some(checkpoint) = checkpoint {
+            self.checkpoint_of.insert(hash, checkpoint);
+        }
+        for tx in &block.data {
+            self.tx_blocks.entry(tx.hash()).or_default().push(hash);
+        }
         self.block_map.insert(hash, block.clone());
         let new_block_height = self.block_heights[&parent] + 1;
         self.block_heights.insert(hash, new_block_height);
-        if new_block_height > self.block_heights[&self.latest_block] {
+        let work = self.chain_work[&parent].saturating_add(block_work(&block.get_difficulty()));
+        self.chain_work.insert(hash, work);
+        self.inserted += 1;
+        // the chain with the most work wins, the lowest hash breaks ties so that every node
+        // picks the same tip whatever the order it received the blocks in
+        let tip_work = self.chain_work[&self.latest_block];
+        if work > tip_work || (work == tip_work && hash < self.latest_block) {
+            if parent != self.latest_block {
+                let depth = self.block_heights[&self.latest_block]
+                    - self.fork_height(&self.latest_block, &hash);
+                self.reorgs += 1;
+                self.deepest_reorg = self.deepest_reorg.max(depth);
+            }
             self.latest_block = hash;
         }
+        Ok(())
+    }
+
+    /// Height of the last block two chains have in common
+    fn fork_height(&self, a: &H256, b: &H256) -> usize {
+        let (mut a, mut b) = (*a, *b);
+        while a != b {
+            let (height_a, height_b) = (self.block_heights[&a], self.block_heights[&b]);
+            let higher = if height_a >= height_b { &mut a } else { &mut b };
+            match self.block_map.get(higher).map(|block| block.get_parent()) {
+                Some(parent) if self.block_heights.contains_key(&parent) => *higher = parent,
+                // the chains only meet below the snapshot we started from
+                _ => return height_a.min(height_b).saturating_sub(1),
+            }
+        }
+        self.block_heights[&a]
+    }
+
+    pub fn fork_stats(&self) -> ForkStats {
+        let main_blocks = self.block_heights[&self.latest_block] - self.base_height;
+        let stale_blocks = self.inserted.saturating_sub(main_blocks);
+        ForkStats {
+            blocks: self.inserted,
+            stale_blocks,
+            fork_rate: if self.inserted == 0 {
+                0.0
+            } else {
+                stale_blocks as f64 / self.inserted as f64
+            },
+            reorgs: self.reorgs,
+            deepest_reorg: self.deepest_reorg,
+        }
+    }
+
+    /// Get the median timestamp of a block and the blocks before it, up to
+    /// `MEDIAN_TIME_SPAN` of them. A child of the block needs a later timestamp
+    pub fn median_time_past(&self, hash: &H256) -> u64 {
+        let mut timestamps = Vec::with_capacity(MEDIAN_TIME_SPAN);
+        let mut curr = self.block_map.get(hash);
+        while let Some(block) = curr {
+            if timestamps.len() == MEDIAN_TIME_SPAN {
+                break;
+            }
+            timestamps.push(block.header.timestamp);
+            curr = self.block_map.get(&block.header.parent);
+        }
+        timestamps.sort_unstable();
+        timestamps.get(timestamps.len() / 2).copied().unwrap_or(0)
+    }
+
+    /// Get the parameters of the chain
+    pub fn spec(&self) -> &ChainSpec {
+        &self.spec
+    }
+
+    /// Remove the side branches that fork off the longest chain more than `depth` blocks below
+    /// the tip, and return the hashes of the removed blocks
+    pub fn prune_forks(&mut self, depth: usize) -> Vec<H256> {
+        let tip_height = self.block_heights[&self.latest_block];
+        if tip_height <= depth {
+            return Vec::new();
+        }
+        let limit = tip_height - depth;
+        let longest_chain: HashSet<H256> =
+            self.all_blocks_in_longest_chain().into_iter().collect();
+        // height of the block of the longest chain each side block descends from
+        let mut fork_heights: HashMap<H256, usize> = HashMap::new();
+        for hash in self.block_map.keys() {
+            let mut branch = Vec::new();
+            let mut curr = *hash;
+            let fork_height = loop {
+                if longest_chain.contains(&curr) {
+                    break self.block_heights[&curr];
+                }
+                if let Some(height) = fork_heights.get(&curr) {
+                    break *height;
+                }
+                branch.push(curr);
+                curr = self.block_map[&curr].get_parent();
+            };
+            for block in branch {
+                fork_heights.insert(block, fork_height);
+            }
+        }
+        let pruned: Vec<H256> = fork_heights
+            .into_iter()
+            .filter(|(_, fork_height)| *fork_height < limit)
+            .map(|(hash, _)| hash)
+            .collect();
+        for hash in &pruned {
+            self.block_map.remove(hash);
+            self.block_heights.remove(hash);
+            self.chain_work.remove(hash);
+            self.states.remove(hash);
+            self.checkpoint_of.remove(hash);
+        }
+        let pruned_set: HashSet<&H256> = pruned.iter().collect();
+        self.tx_blocks.retain(|_, blocks| {
+            blocks.retain(|block| !pruned_set.contains(block));
+            !blocks.is_empty()
+        });
+        pruned
+    }
+
+    /// Drop the states of the blocks of the longest chain more than `depth` blocks below the
+    /// tip, which a reorg that deep would need. Blocks forking off below them can no longer be
+    /// executed
+    pub fn prune_states(&mut self, depth: usize) {
+        let tip_height = self.block_heights[&self.latest_block];
+        let limit = match tip_height.checked_sub(depth) {
+            Some(limit) => limit,
+            None => return,
+        };
+        let block_heights = &self.block_heights;
+        let latest_block = self.latest_block;
+        self.states.retain(|hash, _| {
+            *hash == latest_block || matches!(block_heights.get(hash), Some(h) if *h >= limit)
+        });
+    }
+
+    /// Get the ancestor of a block at some height, or the block itself at its own height
+    pub fn ancestor(&self, hash: &H256, height: usize) -> Option<H256> {
+        let mut curr_height = *self.block_heights.get(hash)?;
+        if height > curr_height {
+            return None;
+        }
+        let mut curr = *hash;
+        while curr_height > height {
+            // below the oldest block of a snapshot
+            curr = self.block_map.get(&curr)?.get_parent();
+            curr_height -= 1;
+        }
+        if !self.block_map.contains_key(&curr) {
+            return None;
+        }
+        Some(curr)
+    }
+
+    /// Whether a block is part of the longest chain
+    pub fn is_in_longest_chain(&self, hash: &H256) -> bool {
+        match self.block_heights.get(hash) {
+            Some(height) => self.ancestor(&self.latest_block, *height) == Some(*hash),
+            None => false,
+        }
+    }
+
+    /// Number of confirmations of a block of the longest chain at `height`: the block itself
+    /// and the ones on top of it
+    fn confirmations(&self, height: usize) -> usize {
+        self.block_heights[&self.latest_block] + 1 - height
+    }
+
+    /// Get the last block of the longest chain with at least `depth` confirmations, the tip
+    /// having one
+    pub fn finalized(&self, depth: usize) -> Option<H256> {
+        let tip_height = self.block_heights[&self.latest_block];
+        let height = (tip_height + 1).checked_sub(depth.max(1))?;
+        self.ancestor(&self.latest_block, height)
+    }
+
+    /// Find where a transaction is, in the blocks we know or in `mempool`
+    pub fn tx_status(&self, hash: &H256, mempool: &Mempool) -> TxStatus {
+        let blocks = self.tx_blocks.get(hash).map_or(&[][..], |blocks| &blocks[..]);
+        if let Some(block) = blocks.iter().find(|b| self.is_in_longest_chain(b)) {
+            let height = self.block_heights[block];
+            return TxStatus::Confirmed {
+                block: block.to_string(),
+                height,
+                confirmations: self.confirmations(height),
+            };
+        }
+        if mempool.tx_map.contains_key(hash) {
+            return TxStatus::Mempool;
+        }
+        if !blocks.is_empty() {
+            return TxStatus::SideFork {
+                blocks: blocks.iter().map(|b| b.to_string()).collect(),
+            };
+        }
+        TxStatus::Unknown
+    }
+
+    /// Get the total work of the chain ending with a block
+    pub fn chain_work(&self, hash: &H256) -> Option<u128> {
+        self.chain_work.get(hash).copied()
+    }
+
+    /// Describe a block for the explorer API
+    pub fn block_info(&self, hash: &H256) -> Option<BlockInfo> {
+        let block = self.block_map.get(hash)?;
+        Some(BlockInfo {
+            hash: hash.to_string(),
+            parent: block.get_parent().to_string(),
+            height: self.block_heights[hash],
+            timestamp: block.header.timestamp,
+            transactions: block.data.len(),
+            work: block_work(&block.get_difficulty()).to_string(),
+            chain_work: self.chain_work[hash].to_string(),
+        })
+    }
+
+    /// Get the ledger state after executing a block
+    pub fn state(&self, hash: &H256) -> Option<&State> {
+        self.states.get(hash)
+    }
+
+    /// Get the ledger state at the tip of the longest chain
+    pub fn tip_state(&self) -> &State {
+        &self.states[&self.latest_block]
     }
 
     /// Get the last block's hash of the longest chain
@@ -65,14 +636,23 @@ impl Blockchain {
         self.latest_block
     }
 
-    /// Get all blocks' hashes of the longest chain, ordered from genesis to the tip
+    /// Get a block with its transactions. Blocks before a snapshot we started from only have
+    /// their header
+    pub fn block(&self, hash: &H256) -> Option<&Block> {
+        if *self.block_heights.get(hash)? < self.base_height {
+            return None;
+        }
+        self.block_map.get(hash)
+    }
+
+    /// Get all blocks' hashes of the longest chain, ordered from genesis (or the oldest block
+    /// of a snapshot) to the tip
     pub fn all_blocks_in_longest_chain(&self) -> Vec<H256> {
         let mut list = Vec::new();
-        let genesis_parent: H256 = [255u8; 32].into();
         let mut curr_block_hash = self.latest_block;
-        while genesis_parent != curr_block_hash {
+        while let Some(block) = self.block_map.get(&curr_block_hash) {
             list.push(curr_block_hash.clone());
-            curr_block_hash = self.block_map[&curr_block_hash].get_parent();
+            curr_block_hash = block.get_parent();
         }
         list.reverse();
         list
@@ -84,17 +664,302 @@ impl Blockchain {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::blockchain::state::ico_key;
+    use crate::types::address::Address;
     use crate::types::block::generate_random_block;
     use crate::types::hash::Hashable;
+    use crate::types::transaction::{SignedTransaction, Transaction};
+    use ring::signature::KeyPair;
 
     #[test]
     fn insert_one() {
         let mut blockchain = Blockchain::new();
         let genesis_hash = blockchain.tip();
         let block = generate_random_block(&genesis_hash);
-        blockchain.insert(&block);
+        blockchain.insert(&block).unwrap();
         assert_eq!(blockchain.tip(), block.hash());
     }
+
+    #[test]
+    fn reject_oversized_block() {
+        let spec = ChainSpec {
+            max_block_transactions: 1,
+            max_block_size: 300,
+            ..Default::default()
+        };
+        let mut blockchain = Blockchain::from_spec(&spec);
+        let mut block = generate_random_block(&blockchain.tip());
+        block.data = vec![Default::default(), Default::default()];
+        block.header.merkle_root = MerkleTree::new(&block.data).root();
+        assert_eq!(
+            blockchain.insert(&block),
+            Err(BlockError::TooManyTransactions)
+        );
+        block.data.truncate(1);
+        block.data[0].signature = vec![0; 200];
+        block.header.merkle_root = MerkleTree::new(&block.data).root();
+        assert_eq!(blockchain.insert(&block), Err(BlockError::TooLarge));
+        assert_eq!(blockchain.tip(), spec.genesis_block().hash());
+    }
+
+    #[test]
+    fn compute_work() {
+        assert_eq!(block_work(&[255u8; 32].into()), 1);
+        let mut difficulty = [255u8; 32];
+        difficulty[0] = 0;
+        assert_eq!(block_work(&difficulty.into()), 256);
+        difficulty[1] = 0;
+        assert_eq!(block_work(&difficulty.into()), 65536);
+        assert_eq!(block_work(&[0u8; 32].into()), u128::MAX);
+    }
+
+    #[test]
+    fn most_work_wins() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let a = generate_random_block(&genesis_hash);
+        let b = generate_random_block(&genesis_hash);
+        blockchain.insert(&a).unwrap();
+        blockchain.insert(&b).unwrap();
+        // equal work, the lower hash wins whatever the order
+        assert_eq!(blockchain.tip(), a.hash().min(b.hash()));
+        let mut other = Blockchain::new();
+        other.insert(&b).unwrap();
+        other.insert(&a).unwrap();
+        assert_eq!(other.tip(), blockchain.tip());
+
+        let loser = if a.hash() < b.hash() { &b } else { &a };
+        let c = generate_random_block(&loser.hash());
+        blockchain.insert(&c).unwrap();
+        assert_eq!(blockchain.tip(), c.hash());
+        assert_eq!(blockchain.chain_work(&c.hash()), Some(3));
+        assert_eq!(blockchain.block_info(&c.hash()).unwrap().height, 2);
+    }
+
+    #[test]
+    fn count_forks() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let mut tip = genesis_hash;
+        for _ in 0..3 {
+            let block = generate_random_block(&tip);
+            tip = block.hash();
+            blockchain.insert(&block).unwrap();
+        }
+        assert_eq!(blockchain.fork_stats().stale_blocks, 0);
+        // a longer fork from genesis takes over the 3 blocks of the longest chain
+        let mut fork_tip = genesis_hash;
+        for _ in 0..4 {
+            let block = generate_random_block(&fork_tip);
+            fork_tip = block.hash();
+            blockchain.insert(&block).unwrap();
+        }
+        assert_eq!(blockchain.tip(), fork_tip);
+        let stats = blockchain.fork_stats();
+        assert_eq!(stats.blocks, 7);
+        assert_eq!(stats.stale_blocks, 3);
+        assert!((stats.fork_rate - 3.0 / 7.0).abs() < 1e-9);
+        assert_eq!(stats.reorgs, 1);
+        assert_eq!(stats.deepest_reorg, 3);
+
+        // a block received twice is only counted once
+        let block = blockchain.block_map[&fork_tip].clone();
+        assert!(matches!(blockchain.insert(&block), Err(BlockError::Duplicate)));
+        assert!(!BlockError::Duplicate.is_misbehavior());
+        assert_eq!(blockchain.fork_stats().blocks, 7);
+        for tx in &block.data {
+            assert_eq!(blockchain.tx_blocks[&tx.hash()], vec![fork_tip]);
+        }
+    }
+
+    #[test]
+    fn prune_deep_forks() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let mut main = vec![genesis_hash];
+        for _ in 0..5 {
+            let block = generate_random_block(main.last().unwrap());
+            blockchain.insert(&block).unwrap();
+            main.push(block.hash());
+        }
+        // a two block fork off height 1, and a one block fork off height 3
+        let deep = generate_random_block(&main[1]);
+        let deeper = generate_random_block(&deep.hash());
+        let shallow = generate_random_block(&main[3]);
+        for block in &[&deep, &deeper, &shallow] {
+            blockchain.insert(block).unwrap();
+        }
+        assert_eq!(blockchain.tip(), main[5]);
+
+        assert!(blockchain.prune_forks(5).is_empty());
+        let mut pruned = blockchain.prune_forks(3);
+        pruned.sort();
+        let mut expected = vec![deep.hash(), deeper.hash()];
+        expected.sort();
+        assert_eq!(pruned, expected);
+        assert!(!blockchain.block_heights.contains_key(&deeper.hash()));
+        assert!(blockchain.state(&deep.hash()).is_none());
+        assert!(blockchain.block_map.contains_key(&shallow.hash()));
+        assert_eq!(blockchain.all_blocks_in_longest_chain(), main);
+
+        // the states of the main chain go too, but for the last blocks
+        blockchain.prune_states(2);
+        assert!(blockchain.state(&main[2]).is_none());
+        assert!(blockchain.state(&main[3]).is_some());
+        assert!(blockchain.state(&shallow.hash()).is_some());
+        assert!(blockchain.block(&main[2]).is_some());
+        assert!(matches!(
+            blockchain.insert(&generate_random_block(&main[2])),
+            Err(BlockError::NoParentState)
+        ));
+        blockchain.prune_states(0);
+        assert_eq!(blockchain.tip_state(), blockchain.state(&main[5]).unwrap());
+        assert!(blockchain.state(&main[4]).is_none());
+    }
+
+    #[test]
+    fn find_transactions() {
+        let mut blockchain = Blockchain::new();
+        let mut mempool = Mempool::new();
+        let tx = SignedTransaction::new(
+            Transaction {
+                sender: Address::from_public_key_bytes(ico_key(0).public_key().as_ref()),
+                receiver: Address::default(),
+                value: 1,
+                account_nonce: 1,
+            },
+            &ico_key(0),
+        );
+        let hash = tx.hash();
+        assert_eq!(blockchain.tx_status(&hash, &mempool), TxStatus::Unknown);
+        mempool.insert(&tx);
+        assert_eq!(blockchain.tx_status(&hash, &mempool), TxStatus::Mempool);
+
+        let genesis_hash = blockchain.tip();
+        let mut block = generate_random_block(&genesis_hash);
+        block.data = vec![tx];
+        block.header.merkle_root = MerkleTree::new(&block.data).root();
+        blockchain.insert(&block).unwrap();
+        mempool.prune(blockchain.tip_state());
+        let mut tip = block.hash();
+        for _ in 0..2 {
+            let next = generate_random_block(&tip);
+            blockchain.insert(&next).unwrap();
+            tip = next.hash();
+        }
+        let confirmed = TxStatus::Confirmed {
+            block: block.hash().to_string(),
+            height: 1,
+            confirmations: 3,
+        };
+        assert_eq!(blockchain.tx_status(&hash, &mempool), confirmed);
+        // the same count of confirmations makes the block final
+        assert_eq!(blockchain.finalized(1), Some(tip));
+        assert_eq!(blockchain.finalized(3), Some(block.hash()));
+        assert_eq!(blockchain.finalized(4), Some(genesis_hash));
+        assert_eq!(blockchain.finalized(5), None);
+
+        // a longer fork without the transaction leaves it on a side fork only
+        let mut fork_tip = genesis_hash;
+        for _ in 0..4 {
+            let next = generate_random_block(&fork_tip);
+            blockchain.insert(&next).unwrap();
+            fork_tip = next.hash();
+        }
+        assert_eq!(
+            blockchain.tx_status(&hash, &mempool),
+            TxStatus::SideFork {
+                blocks: vec![block.hash().to_string()]
+            }
+        );
+    }
+
+    #[test]
+    fn enforce_checkpoints() {
+        let mut main = Blockchain::new();
+        let mut blocks = Vec::new();
+        for _ in 0..3 {
+            let block = generate_random_block(&main.tip());
+            main.insert(&block).unwrap();
+            blocks.push(block);
+        }
+        let mut spec = ChainSpec::default();
+        spec.add_checkpoint(2, blocks[1].hash()).unwrap();
+        let mut blockchain = Blockchain::from_spec(&spec);
+        let genesis_hash = blockchain.tip();
+
+        // a sibling of the checkpoint
+        blockchain.insert(&blocks[0]).unwrap();
+        let sibling = generate_random_block(&blocks[0].hash());
+        assert_eq!(blockchain.insert(&sibling), Err(BlockError::CheckpointConflict));
+        // forks off below the checkpoint are fine until we have it, and refused after
+        let early = generate_random_block(&genesis_hash);
+        blockchain.insert(&early).unwrap();
+        blockchain.insert(&blocks[1]).unwrap();
+        let late = generate_random_block(&genesis_hash);
+        assert_eq!(blockchain.insert(&late), Err(BlockError::CheckpointConflict));
+        let fork = generate_random_block(&early.hash());
+        assert_eq!(blockchain.insert(&fork), Err(BlockError::CheckpointConflict));
+        blockchain.insert(&blocks[2]).unwrap();
+        assert_eq!(blockchain.tip(), blocks[2].hash());
+
+        // the transactions of blocks proven to lead to a checkpoint are trusted, the ones of
+        // other blocks are checked, checkpoint or not
+        let mut tx = SignedTransaction::new(
+            Transaction {
+                sender: Address::from_public_key_bytes(ico_key(0).public_key().as_ref()),
+                receiver: Address::default(),
+                value: 1,
+                account_nonce: 1,
+            },
+            &ico_key(0),
+        );
+        tx.signature[0] ^= 1;
+        let mut block = generate_random_block(&genesis_hash);
+        block.data = vec![tx];
+        block.header.merkle_root = MerkleTree::new(&block.data).root();
+        let child = generate_random_block(&block.hash());
+        let grandchild = generate_random_block(&child.hash());
+        let mut spec = ChainSpec::default();
+        spec.add_checkpoint(3, grandchild.hash()).unwrap();
+        let invalid = Err(BlockError::Transaction(TxError::BadSignature));
+        assert_eq!(Blockchain::new().insert(&block), invalid);
+        let mut blockchain = Blockchain::from_spec(&spec);
+        assert_eq!(blockchain.insert(&block), invalid);
+        // the blocks arrive from the checkpoint down, like when syncing from a peer
+        blockchain.vouch(&grandchild);
+        blockchain.vouch(&child);
+        let mut other = Blockchain::from_spec(&spec);
+        other.vouch(&child);
+        assert_eq!(other.insert(&block), invalid);
+        for block in &[&block, &child, &grandchild] {
+            blockchain.insert(block).unwrap();
+        }
+        assert_eq!(blockchain.tip(), grandchild.hash());
+        assert!(blockchain.vouched.is_empty());
+        assert_eq!(blockchain.checkpoint_of[&grandchild.hash()], grandchild.hash());
+    }
+
+    #[test]
+    fn check_timestamps() {
+        let mut blockchain = Blockchain::new();
+        for timestamp in (1..=11).map(|i| i * 10) {
+            let mut block = generate_random_block(&blockchain.tip());
+            block.header.timestamp = timestamp;
+            blockchain.insert(&block).unwrap();
+        }
+        // the median of 10, 20, ..., 110 is 60, a block may still be older than its parent
+        assert_eq!(blockchain.median_time_past(&blockchain.tip()), 60);
+        let mut block = generate_random_block(&blockchain.tip());
+        block.header.timestamp = 60;
+        assert_eq!(blockchain.insert(&block), Err(BlockError::TimestampTooOld));
+        block.header.timestamp = 61;
+        blockchain.insert(&block).unwrap();
+
+        let mut block = generate_random_block(&blockchain.tip());
+        block.header.timestamp = unix_millis() + blockchain.spec().max_future_drift + 60_000;
+        assert_eq!(blockchain.insert(&block), Err(BlockError::TimestampInFuture));
+    }
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
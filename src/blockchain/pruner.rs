@@ -0,0 +1,210 @@
+//! Background removal of stale forks and old states, so that long runs do not keep every block
+//! they ever saw, nor the ledger after each of them.
+
+use log::{debug, info};
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time;
+
+use super::Blockchain;
+use crate::types::block::Block;
+use crate::types::hash::{Hashable, H256};
+
+/// How often stale forks are pruned.
+const PRUNE_INTERVAL: time::Duration = time::Duration::from_secs(10);
+/// Orphans kept at most, the ones with the oldest timestamps go first.
+const MAX_ORPHANS: usize = 1024;
+
+pub struct Pruner {
+    blockchain: Arc<Mutex<Blockchain>>,
+    orphan_buffer: Arc<Mutex<HashMap<H256, Block>>>,
+    /// Side branches forking off more than this many blocks below the tip are removed, and so
+    /// are the states of the blocks below it
+    depth: usize,
+}
+
+impl Pruner {
+    pub fn new(
+        blockchain: &Arc<Mutex<Blockchain>>,
+        orphan_buffer: &Arc<Mutex<HashMap<H256, Block>>>,
+        depth: usize,
+    ) -> Self {
+        Self {
+            blockchain: Arc::clone(blockchain),
+            orphan_buffer: Arc::clone(orphan_buffer),
+            depth,
+        }
+    }
+
+    pub fn start(self) {
+        info!("Pruning forks more than {} blocks deep", self.depth);
+        thread::Builder::new()
+            .name("pruner".to_string())
+            .spawn(move || loop {
+                thread::sleep(PRUNE_INTERVAL);
+                self.prune();
+            })
+            .unwrap();
+    }
+
+    /// Prune the stale forks and old states, then the orphans that can no longer be connected.
+    pub fn prune(&self) {
+        let mut chain = self.blockchain.lock().unwrap();
+        let pruned: HashSet<H256> = chain.prune_forks(self.depth).into_iter().collect();
+        chain.prune_states(self.depth);
+        let mut orphans = self.orphan_buffer.lock().unwrap();
+        let before = orphans.len();
+        prune_orphans(&mut orphans, &chain, &pruned, self.depth);
+        debug!(
+            "Pruned {} blocks and {} orphans",
+            pruned.len(),
+            before - orphans.len()
+        );
+    }
+}
+
+/// Keep a block whose parent is missing until the parent arrives. The buffer maps the hash of
+/// every orphan to the orphan, and never holds more than `MAX_ORPHANS`: the one with the oldest
+/// timestamp goes, which may be the new one.
+pub fn add_orphan(orphans: &mut HashMap<H256, Block>, block: Block) {
+    orphans.insert(block.hash(), block);
+    if orphans.len() > MAX_ORPHANS {
+        let oldest = orphans
+            .iter()
+            .min_by_key(|(hash, block)| (block.header.timestamp, **hash))
+            .map(|(hash, _)| *hash)
+            .unwrap();
+        orphans.remove(&oldest);
+    }
+}
+
+/// Remove and return the orphans waiting for `parent`.
+pub fn take_orphans(orphans: &mut HashMap<H256, Block>, parent: &H256) -> Vec<Block> {
+    let children: Vec<H256> = orphans
+        .iter()
+        .filter(|(_, block)| block.get_parent() == *parent)
+        .map(|(hash, _)| *hash)
+        .collect();
+    children
+        .iter()
+        .filter_map(|hash| orphans.remove(hash))
+        .collect()
+}
+
+/// Drop the orphans that are in the blockchain anyway, or that would only connect to a fork
+/// pruned at `depth`, and the oldest ones beyond `MAX_ORPHANS`.
+///
+/// The median time past never decreases along a chain, so an orphan whose timestamp is not above
+/// the median time past of the longest chain `depth` blocks below the tip descends from a block
+/// deeper than that.
+fn prune_orphans(
+    orphans: &mut HashMap<H256, Block>,
+    chain: &Blockchain,
+    pruned: &HashSet<H256>,
+    depth: usize,
+) {
+    let longest_chain = chain.all_blocks_in_longest_chain();
+    let oldest = match longest_chain.len().checked_sub(depth + 1) {
+        Some(height) => chain.median_time_past(&longest_chain[height]),
+        None => 0,
+    };
+    orphans.retain(|hash, block| {
+        !pruned.contains(&block.get_parent())
+            && !chain.block_map.contains_key(hash)
+            && block.header.timestamp > oldest
+    });
+    if orphans.len() > MAX_ORPHANS {
+        let mut by_age: Vec<(u64, H256)> = orphans
+            .iter()
+            .map(|(hash, block)| (block.header.timestamp, *hash))
+            .collect();
+        by_age.sort();
+        for (_, hash) in &by_age[..orphans.len() - MAX_ORPHANS] {
+            orphans.remove(hash);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::block::generate_random_block;
+
+    #[test]
+    fn prune_forks_and_orphans() {
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        let orphan_buffer = Arc::new(Mutex::new(HashMap::new()));
+        let genesis_hash = blockchain.lock().unwrap().tip();
+        let stale = generate_random_block(&genesis_hash);
+        // an orphan as old as the stale fork, and one with a recent timestamp
+        let old = generate_random_block(&[7u8; 32].into());
+        let mut tip = genesis_hash;
+        {
+            let mut chain = blockchain.lock().unwrap();
+            chain.insert(&stale).unwrap();
+            for _ in 0..3 {
+                let block = generate_random_block(&tip);
+                tip = block.hash();
+                chain.insert(&block).unwrap();
+            }
+        }
+        let recent = generate_random_block(&[8u8; 32].into());
+        {
+            let mut orphans = orphan_buffer.lock().unwrap();
+            add_orphan(&mut orphans, old);
+            add_orphan(&mut orphans, recent.clone());
+        }
+
+        Pruner::new(&blockchain, &orphan_buffer, 3).prune();
+        assert!(blockchain.lock().unwrap().block_map.contains_key(&stale.hash()));
+        assert_eq!(orphan_buffer.lock().unwrap().len(), 2);
+        Pruner::new(&blockchain, &orphan_buffer, 1).prune();
+        let chain = blockchain.lock().unwrap();
+        assert!(!chain.block_map.contains_key(&stale.hash()));
+        assert!(!chain.block_heights.contains_key(&stale.hash()));
+        assert!(chain.state(&genesis_hash).is_none());
+        let orphans = orphan_buffer.lock().unwrap();
+        assert_eq!(orphans.len(), 1);
+        assert!(orphans.contains_key(&recent.hash()));
+    }
+
+    #[test]
+    fn bound_orphans() {
+        let mut orphans = HashMap::new();
+        let parent: H256 = [7u8; 32].into();
+        let orphan = |parent: &H256, timestamp| {
+            let mut block = generate_random_block(parent);
+            block.header.timestamp = timestamp;
+            block
+        };
+        // two orphans of the same parent are both kept
+        let (a, b) = (orphan(&parent, 10), orphan(&parent, 11));
+        add_orphan(&mut orphans, a.clone());
+        add_orphan(&mut orphans, b.clone());
+        for i in 0..MAX_ORPHANS - 2 {
+            add_orphan(&mut orphans, orphan(&[8u8; 32].into(), 100 + i as u64));
+        }
+        assert_eq!(orphans.len(), MAX_ORPHANS);
+
+        // once full, the oldest orphan makes room, even if it is the new one
+        let old = orphan(&parent, 0);
+        add_orphan(&mut orphans, old.clone());
+        assert!(!orphans.contains_key(&old.hash()));
+        let recent = orphan(&parent, u64::MAX);
+        add_orphan(&mut orphans, recent.clone());
+        assert!(!orphans.contains_key(&a.hash()));
+        assert_eq!(orphans.len(), MAX_ORPHANS);
+
+        let mut children: Vec<H256> = take_orphans(&mut orphans, &parent)
+            .iter()
+            .map(|block| block.hash())
+            .collect();
+        children.sort();
+        let mut expected = vec![b.hash(), recent.hash()];
+        expected.sort();
+        assert_eq!(children, expected);
+        assert!(take_orphans(&mut orphans, &parent).is_empty());
+    }
+}
@@ -0,0 +1,212 @@
+//! Ledger state at a block, so that a node can start from it instead of replaying every block
+//! since genesis.
+//!
+//! A snapshot holds the header of the block and of up to `MEDIAN_TIME_SPAN - 1` of its
+//! ancestors, so that the timestamps of the next blocks can be checked, and the accounts of the
+//! state after the block. It names the genesis block of its chain, so that it is only loaded
+//! with the chain spec it was taken with. Its content hash is the SHA256 of the bincode encoding
+//! of everything else.
+
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+use super::spec::ChainSpec;
+use super::{Blockchain, MEDIAN_TIME_SPAN};
+use crate::types::address::Address;
+use crate::types::block::Header;
+use crate::types::hash::{Hashable, H256};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Snapshot {
+    /// Hash of the genesis block of the chain
+    pub genesis: H256,
+    pub block: H256,
+    pub height: usize,
+    /// Total work of the chain ending with the block
+    pub chain_work: u128,
+    /// Headers of the block and its last ancestors, oldest first
+    pub headers: Vec<Header>,
+    /// (address, nonce, balance) of every account, ordered by address
+    pub accounts: Vec<(Address, u32, u64)>,
+    pub content_hash: H256,
+}
+
+/// What `/state/snapshot` reports about a snapshot it wrote.
+#[derive(Serialize, Debug, Clone)]
+pub struct SnapshotInfo {
+    pub block: String,
+    pub height: usize,
+    pub accounts: usize,
+    pub content_hash: String,
+}
+
+impl Snapshot {
+    /// Take a snapshot of the state after a block, if we have that state.
+    pub fn take(chain: &Blockchain, hash: &H256) -> Option<Self> {
+        let state = chain.state(hash)?;
+        let mut headers = Vec::new();
+        let mut curr = *hash;
+        while headers.len() < MEDIAN_TIME_SPAN {
+            match chain.block_map.get(&curr) {
+                Some(block) => {
+                    headers.push(block.header.clone());
+                    curr = block.get_parent();
+                }
+                None => break,
+            }
+        }
+        headers.reverse();
+        let mut snapshot = Self {
+            genesis: chain.spec().genesis_block().hash(),
+            block: *hash,
+            height: chain.block_heights[hash],
+            chain_work: chain.chain_work(hash).unwrap(),
+            headers,
+            accounts: state.entries(),
+            content_hash: H256::default(),
+        };
+        snapshot.content_hash = snapshot.compute_content_hash();
+        Some(snapshot)
+    }
+
+    fn compute_content_hash(&self) -> H256 {
+        let content = (
+            &self.genesis,
+            &self.block,
+            self.height,
+            self.chain_work,
+            &self.headers,
+            &self.accounts,
+        );
+        let bytes = bincode::serialize(&content).unwrap();
+        digest::digest(&digest::SHA256, &bytes).into()
+    }
+
+    /// Check that the content hash matches, and that the headers form a chain ending with the
+    /// block.
+    pub fn verify(&self) -> Result<(), String> {
+        if self.compute_content_hash() != self.content_hash {
+            return Err("content hash mismatch".to_string());
+        }
+        let last = match self.headers.last() {
+            Some(header) => header,
+            None => return Err("no headers".to_string()),
+        };
+        if last.hash() != self.block {
+            return Err("the last header is not the one of the block".to_string());
+        }
+        if self.headers.len() > self.height + 1 {
+            return Err("more headers than blocks".to_string());
+        }
+        for pair in self.headers.windows(2) {
+            if pair[1].parent != pair[0].hash() {
+                return Err("the headers do not form a chain".to_string());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn info(&self) -> SnapshotInfo {
+        SnapshotInfo {
+            block: self.block.to_string(),
+            height: self.height,
+            accounts: self.accounts.len(),
+            content_hash: self.content_hash.to_string(),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let bytes = bincode::serialize(self).map_err(|e| e.to_string())?;
+        std::fs::write(path, bytes).map_err(|e| e.to_string())
+    }
+
+    /// Read and verify a snapshot of the chain of `spec`. It has to match the checkpoint at its
+    /// height, if there is one.
+    pub fn load(path: &Path, spec: &ChainSpec) -> Result<Self, String> {
+        let bytes = std::fs::read(path).map_err(|e| e.to_string())?;
+        let snapshot: Self = bincode::deserialize(&bytes).map_err(|e| e.to_string())?;
+        snapshot.verify()?;
+        if snapshot.genesis != spec.genesis_block().hash() {
+            return Err("the snapshot is of the chain of another spec".to_string());
+        }
+        let checkpoint = spec.checkpoints.iter().find(|(h, _)| *h == snapshot.height);
+        if matches!(checkpoint, Some((_, hash)) if *hash != snapshot.block) {
+            return Err(format!("the snapshot conflicts with the checkpoint at height {}", snapshot.height));
+        }
+        Ok(snapshot)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::spec::ChainSpec;
+    use crate::blockchain::state::ico_key;
+    use crate::types::block::generate_random_block;
+    use crate::types::merkle::MerkleTree;
+    use crate::types::transaction::{SignedTransaction, Transaction};
+    use ring::signature::KeyPair;
+
+    #[test]
+    fn restore_snapshot() {
+        let mut blockchain = Blockchain::new();
+        for _ in 0..15 {
+            let block = generate_random_block(&blockchain.tip());
+            blockchain.insert(&block).unwrap();
+        }
+        let mut block = generate_random_block(&blockchain.tip());
+        let key = ico_key(0);
+        let transaction = Transaction {
+            sender: Address::from_public_key_bytes(key.public_key().as_ref()),
+            receiver: Address::default(),
+            value: 5,
+            account_nonce: 1,
+        };
+        block.data = vec![SignedTransaction::new(transaction, &key)];
+        block.header.merkle_root = MerkleTree::new(&block.data).root();
+        blockchain.insert(&block).unwrap();
+        let tip = blockchain.tip();
+
+        let path = std::env::temp_dir().join(format!("snapshot-{}.bin", tip));
+        let snapshot = Snapshot::take(&blockchain, &tip).unwrap();
+        assert_eq!(snapshot.headers.len(), MEDIAN_TIME_SPAN);
+        snapshot.save(&path).unwrap();
+        let loaded = Snapshot::load(&path, &ChainSpec::default()).unwrap();
+        // only with the spec it was taken with, and its checkpoints
+        let other = ChainSpec {
+            block_reward: 1,
+            ..Default::default()
+        };
+        assert!(Snapshot::load(&path, &other).is_err());
+        let mut checkpoints = ChainSpec::default();
+        checkpoints.add_checkpoint(snapshot.height, [1u8; 32].into()).unwrap();
+        assert!(Snapshot::load(&path, &checkpoints).is_err());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.content_hash, snapshot.content_hash);
+
+        let mut restored = Blockchain::from_snapshot(&ChainSpec::default(), &loaded);
+        assert_eq!(restored.tip(), tip);
+        assert_eq!(restored.tip_state(), blockchain.tip_state());
+        assert_eq!(restored.chain_work(&tip), blockchain.chain_work(&tip));
+        assert_eq!(
+            restored.median_time_past(&tip),
+            blockchain.median_time_past(&tip)
+        );
+        assert_eq!(restored.all_blocks_in_longest_chain().len(), MEDIAN_TIME_SPAN);
+        // only the blocks after the snapshot can be served or extended
+        assert!(restored.block(&tip).is_some());
+        let parent = block.get_parent();
+        assert!(restored.block(&parent).is_none());
+        let next = generate_random_block(&tip);
+        restored.insert(&next).unwrap();
+        blockchain.insert(&next).unwrap();
+        assert_eq!(restored.tip_state(), blockchain.tip_state());
+        let fork = generate_random_block(&parent);
+        assert!(restored.insert(&fork).is_err());
+
+        let mut tampered = snapshot;
+        tampered.accounts[0].2 += 1;
+        assert!(tampered.verify().is_err());
+    }
+}
@@ -0,0 +1,320 @@
+use serde::Deserialize;
+use std::path::Path;
+
+use super::state::{ico_key, State, ICO_ACCOUNTS, ICO_BALANCE};
+use crate::network::message::MAX_MESSAGE_SIZE;
+use crate::types::address::Address;
+use crate::types::block::{Block, Header};
+use crate::types::hash::H256;
+use ring::digest;
+use ring::signature::KeyPair;
+
+/// Parameters of a chain. Nodes started with the same spec agree on the genesis block and the
+/// initial state.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChainSpec {
+    /// Unix time of the genesis block, in milliseconds.
+    pub genesis_timestamp: u64,
+    /// Difficulty of the genesis block, which every later block inherits.
+    pub difficulty: H256,
+    /// Intended time between blocks, in milliseconds. Informative for now, the difficulty is not
+    /// retargeted.
+    pub target_block_time: u64,
+    /// Amount credited to the coinbase address of every block but the genesis block.
+    pub block_reward: u64,
+    /// Initial balances.
+    pub allocations: Vec<(Address, u64)>,
+    /// Largest serialized size of a block, in bytes.
+    pub max_block_size: usize,
+    /// Largest number of transactions in a block.
+    pub max_block_transactions: usize,
+    /// How far ahead of our clock the timestamp of a block may be, in milliseconds.
+    pub max_future_drift: u64,
+    /// Blocks every node agrees on, as (height, hash), ordered by height.
+    pub checkpoints: Vec<(usize, H256)>,
+}
+
+/// Default intended time between blocks, in milliseconds.
+pub const DEFAULT_TARGET_BLOCK_TIME: u64 = 10_000;
+/// Default largest serialized size of a block, in bytes.
+pub const DEFAULT_MAX_BLOCK_SIZE: usize = 1 << 20;
+/// Default largest number of transactions in a block.
+pub const DEFAULT_MAX_BLOCK_TRANSACTIONS: usize = 4096;
+
+/// Default bound on how far in the future blocks may be, in milliseconds.
+pub const DEFAULT_MAX_FUTURE_DRIFT: u64 = 2 * 60 * 60 * 1000;
+
+fn default_target_block_time() -> u64 {
+    DEFAULT_TARGET_BLOCK_TIME
+}
+
+fn default_max_block_size() -> usize {
+    DEFAULT_MAX_BLOCK_SIZE
+}
+
+fn default_max_block_transactions() -> usize {
+    DEFAULT_MAX_BLOCK_TRANSACTIONS
+}
+
+fn default_max_future_drift() -> u64 {
+    DEFAULT_MAX_FUTURE_DRIFT
+}
+
+/// A chain spec as written in a JSON file, with hashes and addresses in hex.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SpecFile {
+    #[serde(default)]
+    genesis_timestamp: u64,
+    difficulty: String,
+    #[serde(default = "default_target_block_time")]
+    target_block_time: u64,
+    #[serde(default)]
+    block_reward: u64,
+    #[serde(default)]
+    allocations: Vec<AllocationFile>,
+    #[serde(default = "default_max_block_size")]
+    max_block_size: usize,
+    #[serde(default = "default_max_block_transactions")]
+    max_block_transactions: usize,
+    #[serde(default = "default_max_future_drift")]
+    max_future_drift: u64,
+    #[serde(default)]
+    checkpoints: Vec<CheckpointFile>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CheckpointFile {
+    height: usize,
+    hash: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AllocationFile {
+    address: String,
+    balance: u64,
+}
+
+impl Default for ChainSpec {
+    /// The spec of the default network: every difficulty is met, and the well-known ICO
+    /// accounts are funded.
+    fn default() -> Self {
+        let allocations = (0..ICO_ACCOUNTS)
+            .map(|i| {
+                let address = Address::from_public_key_bytes(ico_key(i).public_key().as_ref());
+                (address, ICO_BALANCE)
+            })
+            .collect();
+        Self {
+            genesis_timestamp: 0,
+            difficulty: [255u8; 32].into(),
+            target_block_time: DEFAULT_TARGET_BLOCK_TIME,
+            block_reward: 0,
+            allocations,
+            max_block_size: DEFAULT_MAX_BLOCK_SIZE,
+            max_block_transactions: DEFAULT_MAX_BLOCK_TRANSACTIONS,
+            max_future_drift: DEFAULT_MAX_FUTURE_DRIFT,
+            checkpoints: Vec::new(),
+        }
+    }
+}
+
+impl ChainSpec {
+    /// Parse a chain spec from JSON.
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        let file: SpecFile = serde_json::from_str(json).map_err(|e| e.to_string())?;
+        let difficulty = file
+            .difficulty
+            .parse::<H256>()
+            .map_err(|e| format!("bad difficulty {}: {}", file.difficulty, e))?;
+        let mut allocations = Vec::new();
+        let mut total: u64 = 0;
+        for allocation in file.allocations {
+            let address = allocation
+                .address
+                .parse::<Address>()
+                .map_err(|e| format!("bad address {}: {}", allocation.address, e))?;
+            if allocations.iter().any(|(a, _)| *a == address) {
+                return Err(format!("address {} is allocated twice", address));
+            }
+            total = total
+                .checked_add(allocation.balance)
+                .ok_or_else(|| "the allocations add up to more than a u64".to_string())?;
+            allocations.push((address, allocation.balance));
+        }
+        // blocks have to fit in a network message
+        if file.max_block_size >= MAX_MESSAGE_SIZE as usize {
+            return Err(format!(
+                "max_block_size {} does not fit in a message",
+                file.max_block_size
+            ));
+        }
+        let mut spec = Self {
+            genesis_timestamp: file.genesis_timestamp,
+            difficulty,
+            target_block_time: file.target_block_time,
+            block_reward: file.block_reward,
+            allocations,
+            max_block_size: file.max_block_size,
+            max_block_transactions: file.max_block_transactions,
+            max_future_drift: file.max_future_drift,
+            checkpoints: Vec::new(),
+        };
+        for checkpoint in file.checkpoints {
+            let hash = checkpoint
+                .hash
+                .parse::<H256>()
+                .map_err(|e| format!("bad checkpoint hash {}: {}", checkpoint.hash, e))?;
+            spec.add_checkpoint(checkpoint.height, hash)?;
+        }
+        Ok(spec)
+    }
+
+    /// Add a checkpoint, there can only be one per height.
+    pub fn add_checkpoint(&mut self, height: usize, hash: H256) -> Result<(), String> {
+        if self.checkpoints.iter().any(|(h, _)| *h == height) {
+            return Err(format!("two checkpoints at height {}", height));
+        }
+        self.checkpoints.push((height, hash));
+        self.checkpoints.sort_by_key(|(h, _)| *h);
+        Ok(())
+    }
+
+    /// Read a chain spec from a JSON file.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let json = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        Self::from_json(&json)
+    }
+
+    /// Hash of every parameter of the spec but the checkpoints, which name blocks of the chain
+    /// and can be added on the command line.
+    pub fn hash(&self) -> H256 {
+        let mut allocations = self.allocations.clone();
+        allocations.sort();
+        let parameters = (
+            self.genesis_timestamp,
+            self.difficulty,
+            self.target_block_time,
+            self.block_reward,
+            allocations,
+            self.max_block_size as u64,
+            self.max_block_transactions as u64,
+            self.max_future_drift,
+        );
+        let bytes = bincode::serialize(&parameters).unwrap();
+        digest::digest(&digest::SHA256, &bytes).into()
+    }
+
+    /// The genesis block. Its merkle root is the hash of the spec, so that nodes with specs that
+    /// differ in any rule start from different genesis blocks.
+    pub fn genesis_block(&self) -> Block {
+        let merkle_root = self.hash();
+        Block {
+            header: Header {
+                parent: [255u8; 32].into(),
+                nonce: 0,
+                difficulty: self.difficulty,
+                timestamp: self.genesis_timestamp,
+                merkle_root,
+                coinbase: Address::default(),
+            },
+            data: Vec::new(),
+        }
+    }
+
+    /// The state after the genesis block.
+    pub fn genesis_state(&self) -> State {
+        State::with_allocations(&self.allocations)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::Blockchain;
+    use crate::types::hash::Hashable;
+
+    const SPEC: &str = r#"{
+        "genesis_timestamp": 1667000000000,
+        "difficulty": "0000ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+        "target_block_time": 5000,
+        "block_reward": 50,
+        "allocations": [
+            {"address": "1851a0eae0060a132cf0f64a0ffaea248de6cba0", "balance": 1000}
+        ]
+    }"#;
+
+    #[test]
+    fn same_spec_same_genesis() {
+        let spec = ChainSpec::from_json(SPEC).unwrap();
+        assert_eq!(spec.block_reward, 50);
+        assert_eq!(spec.target_block_time, 5000);
+        let a = Blockchain::from_spec(&spec);
+        let b = Blockchain::from_spec(&ChainSpec::from_json(SPEC).unwrap());
+        assert_eq!(a.tip(), b.tip());
+        assert_eq!(a.tip_state(), b.tip_state());
+        let address = "1851a0eae0060a132cf0f64a0ffaea248de6cba0".parse().unwrap();
+        assert_eq!(a.tip_state().account(&address), (0, 1000));
+        assert_eq!(a.block_map[&a.tip()].get_difficulty(), spec.difficulty);
+
+        // every rule of the spec is part of the genesis block, but the checkpoints
+        let mut other = spec.clone();
+        other.allocations[0].1 += 1;
+        assert_ne!(other.genesis_block().hash(), spec.genesis_block().hash());
+        let mut other = spec.clone();
+        other.block_reward += 1;
+        assert_ne!(other.genesis_block().hash(), spec.genesis_block().hash());
+        let other = ChainSpec::from_json(&SPEC.replace("5000", "6000")).unwrap();
+        assert_eq!(other.target_block_time, 6000);
+        assert_ne!(other.genesis_block().hash(), spec.genesis_block().hash());
+        let mut other = spec.clone();
+        other.max_block_transactions -= 1;
+        assert_ne!(other.genesis_block().hash(), spec.genesis_block().hash());
+        let mut other = spec.clone();
+        other.add_checkpoint(1, [1u8; 32].into()).unwrap();
+        assert_eq!(other.genesis_block().hash(), spec.genesis_block().hash());
+    }
+
+    #[test]
+    fn credit_block_reward() {
+        use crate::types::block::generate_random_block;
+
+        let spec = ChainSpec {
+            block_reward: 50,
+            ..Default::default()
+        };
+        let mut blockchain = Blockchain::from_spec(&spec);
+        let mut block = generate_random_block(&blockchain.tip());
+        let miner: Address = [7u8; 20].into();
+        block.header.coinbase = miner;
+        blockchain.insert(&block).unwrap();
+        assert_eq!(blockchain.tip_state().account(&miner), (0, 50));
+    }
+
+    #[test]
+    fn reject_bad_specs() {
+        assert!(ChainSpec::from_json("{}").is_err());
+        let bad_difficulty = SPEC.replace("0000ffff", "zz00ffff");
+        assert!(ChainSpec::from_json(&bad_difficulty).is_err());
+        let twice = SPEC.replace(
+            "\"balance\": 1000}",
+            "\"balance\": 1000}, {\"address\": \"1851a0eae0060a132cf0f64a0ffaea248de6cba0\", \"balance\": 1}",
+        );
+        assert!(ChainSpec::from_json(&twice).is_err());
+        let checkpoints = SPEC.replace(
+            "\"allocations\"",
+            "\"checkpoints\": [{\"height\": 3, \"hash\": \"00\"}], \"allocations\"",
+        );
+        assert!(ChainSpec::from_json(&checkpoints).is_err());
+        let huge = SPEC.replace("\"block_reward\"", "\"max_block_size\": 4294967295, \"block_reward\"");
+        assert!(ChainSpec::from_json(&huge).is_err());
+        let overflow = SPEC.replace(
+            "\"balance\": 1000}",
+            "\"balance\": 18446744073709551615}, {\"address\": \"0000000000000000000000000000000000000001\", \"balance\": 1}",
+        );
+        assert!(ChainSpec::from_json(&overflow).is_err());
+        assert!(ChainSpec::from_json(&overflow.replace("\"balance\": 1}", "\"balance\": 0}")).is_ok());
+    }
+}
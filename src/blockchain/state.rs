@@ -0,0 +1,250 @@
+use serde::Serialize;
+use std::collections::HashMap;
+
+use crate::types::address::Address;
+use crate::types::key_pair;
+use crate::types::transaction::{verify, SignedTransaction};
+use ring::signature::Ed25519KeyPair;
+
+/// Number of accounts funded in the genesis state of the default chain spec.
+pub const ICO_ACCOUNTS: u32 = 10;
+/// Balance of each ICO account of the default chain spec.
+pub const ICO_BALANCE: u64 = 1_000_000_000;
+
+/// Key pair of ICO account `index`. These keys are well known, whoever runs a node can spend
+/// from them.
+pub fn ico_key(index: u32) -> Ed25519KeyPair {
+    key_pair::from_seed(format!("ico-account-{}", index).as_bytes())
+}
+
+/// Reasons a transaction cannot be executed on a state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxError {
+    /// The signature does not match the transaction and public key.
+    BadSignature,
+    /// The public key does not own the sender account.
+    WrongSender,
+    /// The sender account already used this nonce.
+    StaleNonce,
+    /// The sender account has transactions with lower nonces to execute first.
+    FutureNonce,
+    InsufficientBalance,
+    /// The balance of the receiver would overflow.
+    BalanceOverflow,
+}
+
+impl TxError {
+    /// Whether the transaction can never be valid, whatever the state. Peers relaying such a
+    /// transaction are misbehaving.
+    pub fn is_malformed(&self) -> bool {
+        matches!(self, TxError::BadSignature | TxError::WrongSender)
+    }
+}
+
+impl std::fmt::Display for TxError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let s = match self {
+            TxError::BadSignature => "bad signature",
+            TxError::WrongSender => "public key does not own the sender account",
+            TxError::StaleNonce => "nonce already used",
+            TxError::FutureNonce => "nonce too high",
+            TxError::InsufficientBalance => "insufficient balance",
+            TxError::BalanceOverflow => "receiver balance overflows",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl std::error::Error for TxError {}
+
+/// One account, as reported by the API.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Account {
+    pub address: String,
+    pub nonce: u32,
+    pub balance: u64,
+}
+
+/// Ledger state after executing a block: the nonce and balance of every account.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct State {
+    accounts: HashMap<Address, (u32, u64)>,
+}
+
+impl State {
+    /// A state where only the given accounts have a balance.
+    pub fn with_allocations(allocations: &[(Address, u64)]) -> Self {
+        let accounts = allocations
+            .iter()
+            .map(|(address, balance)| (*address, (0, *balance)))
+            .collect();
+        Self { accounts }
+    }
+
+    /// A state with the given (address, nonce, balance) accounts.
+    pub fn from_entries(entries: &[(Address, u32, u64)]) -> Self {
+        let accounts = entries
+            .iter()
+            .map(|(address, nonce, balance)| (*address, (*nonce, *balance)))
+            .collect();
+        Self { accounts }
+    }
+
+    /// All accounts as (address, nonce, balance), ordered by address.
+    pub fn entries(&self) -> Vec<(Address, u32, u64)> {
+        let mut entries: Vec<_> = self
+            .accounts
+            .iter()
+            .map(|(address, (nonce, balance))| (*address, *nonce, *balance))
+            .collect();
+        entries.sort();
+        entries
+    }
+
+    /// Nonce and balance of an account. Unknown accounts have neither.
+    pub fn account(&self, address: &Address) -> (u32, u64) {
+        self.accounts.get(address).copied().unwrap_or((0, 0))
+    }
+
+    /// Check that a transaction can be executed on this state.
+    pub fn check(&self, tx: &SignedTransaction) -> Result<(), TxError> {
+        if !verify(&tx.transaction, &tx.public_key, &tx.signature) {
+            return Err(TxError::BadSignature);
+        }
+        let transaction = &tx.transaction;
+        if Address::from_public_key_bytes(&tx.public_key) != transaction.sender {
+            return Err(TxError::WrongSender);
+        }
+        let (nonce, balance) = self.account(&transaction.sender);
+        if transaction.account_nonce <= nonce {
+            return Err(TxError::StaleNonce);
+        }
+        // before the nonce, so that a transaction the sender cannot pay for is never kept to
+        // wait for earlier ones
+        if transaction.value > balance {
+            return Err(TxError::InsufficientBalance);
+        }
+        if transaction.account_nonce != nonce + 1 {
+            return Err(TxError::FutureNonce);
+        }
+        let (_, receiver_balance) = self.account(&transaction.receiver);
+        if transaction.receiver != transaction.sender
+            && receiver_balance.checked_add(transaction.value).is_none()
+        {
+            return Err(TxError::BalanceOverflow);
+        }
+        Ok(())
+    }
+
+    /// Execute a transaction, leaving the state untouched if it is not valid.
+    pub fn apply(&mut self, tx: &SignedTransaction) -> Result<(), TxError> {
+        self.check(tx)?;
+        self.execute(tx);
+        Ok(())
+    }
+
+    /// Execute a transaction of a block that leads to a checkpoint, without checking its
+    /// signature, nonce or balances: the network did already.
+    pub fn execute_trusted(&mut self, tx: &SignedTransaction) {
+        self.execute(tx);
+    }
+
+    fn execute(&mut self, tx: &SignedTransaction) {
+        let transaction = &tx.transaction;
+        // saturating, so that a trusted transaction cannot bring the node down
+        let sender = self.accounts.entry(transaction.sender).or_insert((0, 0));
+        sender.0 = sender.0.saturating_add(1);
+        sender.1 = sender.1.saturating_sub(transaction.value);
+        let receiver = self.accounts.entry(transaction.receiver).or_insert((0, 0));
+        receiver.1 = receiver.1.saturating_add(transaction.value);
+    }
+
+    /// Add to the balance of an account, e.g. the block reward of a miner. Returns false,
+    /// leaving the state untouched, if the balance would overflow.
+    pub fn credit(&mut self, address: &Address, value: u64) -> bool {
+        let (_, balance) = self.account(address);
+        let balance = match balance.checked_add(value) {
+            Some(balance) => balance,
+            None => return false,
+        };
+        self.accounts.entry(*address).or_insert((0, 0)).1 = balance;
+        true
+    }
+
+    /// All accounts, ordered by address.
+    pub fn accounts(&self) -> Vec<Account> {
+        let mut accounts: Vec<_> = self.accounts.iter().collect();
+        accounts.sort_by_key(|(address, _)| **address);
+        accounts
+            .into_iter()
+            .map(|(address, (nonce, balance))| Account {
+                address: address.to_string(),
+                nonce: *nonce,
+                balance: *balance,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::spec::ChainSpec;
+    use crate::types::transaction::Transaction;
+    use ring::signature::KeyPair;
+
+    fn transfer(key: &Ed25519KeyPair, receiver: Address, value: u64, nonce: u32) -> SignedTransaction {
+        let transaction = Transaction {
+            sender: Address::from_public_key_bytes(key.public_key().as_ref()),
+            receiver,
+            value,
+            account_nonce: nonce,
+        };
+        SignedTransaction::new(transaction, key)
+    }
+
+    #[test]
+    fn apply_transfers() {
+        let mut state = ChainSpec::default().genesis_state();
+        let key = ico_key(0);
+        let receiver = Address::from_public_key_bytes(key_pair::random().public_key().as_ref());
+        state.apply(&transfer(&key, receiver, 10, 1)).unwrap();
+        assert_eq!(state.account(&receiver), (0, 10));
+        let sender = Address::from_public_key_bytes(key.public_key().as_ref());
+        assert_eq!(state.account(&sender), (1, ICO_BALANCE - 10));
+        assert_eq!(state.accounts().len(), ICO_ACCOUNTS as usize + 1);
+    }
+
+    #[test]
+    fn reject_invalid_transfers() {
+        let state = ChainSpec::default().genesis_state();
+        let key = ico_key(0);
+        let receiver = Address::default();
+        assert_eq!(state.check(&transfer(&key, receiver, 10, 0)), Err(TxError::StaleNonce));
+        assert_eq!(state.check(&transfer(&key, receiver, 10, 2)), Err(TxError::FutureNonce));
+        assert_eq!(
+            state.check(&transfer(&key, receiver, ICO_BALANCE + 1, 1)),
+            Err(TxError::InsufficientBalance)
+        );
+        assert_eq!(
+            state.check(&transfer(&key, receiver, ICO_BALANCE + 1, 2)),
+            Err(TxError::InsufficientBalance)
+        );
+        // a key that does not own the account
+        let mut tx = transfer(&ico_key(1), receiver, 10, 1);
+        tx.transaction.sender = Address::from_public_key_bytes(key.public_key().as_ref());
+        tx = SignedTransaction::new(tx.transaction, &ico_key(1));
+        assert_eq!(state.check(&tx), Err(TxError::WrongSender));
+        tx.signature[0] ^= 1;
+        assert_eq!(state.check(&tx), Err(TxError::BadSignature));
+
+        // balances never overflow, from a transfer or a reward
+        let sender = Address::from_public_key_bytes(key.public_key().as_ref());
+        let rich: Address = [7u8; 20].into();
+        let mut state = State::from_entries(&[(sender, 0, 10), (rich, 0, u64::MAX)]);
+        assert_eq!(state.check(&transfer(&key, rich, 10, 1)), Err(TxError::BalanceOverflow));
+        assert!(!state.credit(&rich, 1));
+        assert!(state.credit(&sender, 1));
+        assert_eq!(state.account(&sender), (0, 11));
+    }
+}
@@ -0,0 +1,377 @@
+pub mod wallet;
+
+use log::{debug, info};
+
+use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
+use rand::seq::SliceRandom;
+use rand::Rng;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time;
+
+use crate::blockchain::Blockchain;
+use crate::network::message::Message;
+use crate::network::server::Handle as ServerHandle;
+use crate::types::address::Address;
+use crate::types::hash::{Hashable, H256};
+use crate::types::key_pair;
+use crate::types::transaction::{Mempool, SignedTransaction, Transaction};
+use wallet::Wallet;
+
+/// Transactions of an account that may wait in the mempool at the same time.
+const MAX_PENDING: usize = 64;
+/// The generator stops creating new accounts once the wallet has this many.
+const MAX_ACCOUNTS: usize = 100;
+/// Chance that a transaction pays a new account rather than an existing one.
+const NEW_ACCOUNT_PROBABILITY: f64 = 0.1;
+/// Upper bound of the value of a transaction, unless configured otherwise.
+const DEFAULT_MAX_VALUE: u64 = 100;
+/// How long to wait when no account can send a transaction.
+const IDLE_INTERVAL: time::Duration = time::Duration::from_millis(10);
+
+enum ControlSignal {
+    Start(u64), // the number controls the theta of interval between transaction generation
+    Stop,
+    Configure(Option<u64>, Option<u64>), // new theta and max value, if set
+}
+
+enum OperatingState {
+    Paused,
+    Run(u64),
+}
+
+/// A transaction of ours that has not been executed on the longest chain yet.
+struct PendingTx {
+    hash: H256,
+    nonce: u32,
+    value: u64,
+}
+
+/// What the generator believes an account looks like once its pending transactions execute.
+#[derive(Default)]
+struct AccountView {
+    nonce: u32,
+    balance: u64,
+    pending: VecDeque<PendingTx>,
+}
+
+pub struct Context {
+    /// Channel for receiving control signal
+    control_chan: Receiver<ControlSignal>,
+    operating_state: OperatingState,
+    max_value: u64,
+    blockchain: Arc<Mutex<Blockchain>>,
+    mempool: Arc<Mutex<Mempool>>,
+    server: ServerHandle,
+    wallet: Wallet,
+    accounts: HashMap<Address, AccountView>,
+    /// Tip the account views were last synced with
+    synced_tip: Option<H256>,
+}
+
+#[derive(Clone)]
+pub struct Handle {
+    /// Channel for sending signal to the generator thread
+    control_chan: Sender<ControlSignal>,
+    /// Whether the wallet has an account to spend from, it never gets one later
+    has_accounts: bool,
+}
+
+pub fn new(
+    blockchain: &Arc<Mutex<Blockchain>>,
+    mempool: &Arc<Mutex<Mempool>>,
+    server: &ServerHandle,
+    wallet: Wallet,
+) -> (Context, Handle) {
+    let (signal_chan_sender, signal_chan_receiver) = unbounded();
+    let has_accounts = !wallet.is_empty();
+
+    let ctx = Context {
+        control_chan: signal_chan_receiver,
+        operating_state: OperatingState::Paused,
+        max_value: DEFAULT_MAX_VALUE,
+        blockchain: Arc::clone(blockchain),
+        mempool: Arc::clone(mempool),
+        server: server.clone(),
+        wallet,
+        accounts: HashMap::new(),
+        synced_tip: None,
+    };
+
+    let handle = Handle {
+        control_chan: signal_chan_sender,
+        has_accounts,
+    };
+
+    (ctx, handle)
+}
+
+impl Handle {
+    /// Start generating transactions, every `theta` microseconds. Fails if the wallet has no
+    /// account to spend from, as the generator would sit idle.
+    pub fn start(&self, theta: u64) -> Result<(), String> {
+        if !self.has_accounts {
+            return Err(
+                "the wallet has no account to spend from, give it one with --wallet-account"
+                    .to_string(),
+            );
+        }
+        self.control_chan.send(ControlSignal::Start(theta)).unwrap();
+        Ok(())
+    }
+
+    /// Stop generating transactions until the next `start`.
+    pub fn stop(&self) {
+        self.control_chan.send(ControlSignal::Stop).unwrap();
+    }
+
+    /// Change the theta and the largest transaction value, leaving `None` ones as they are.
+    pub fn configure(&self, theta: Option<u64>, max_value: Option<u64>) {
+        self.control_chan
+            .send(ControlSignal::Configure(theta, max_value))
+            .unwrap();
+    }
+}
+
+impl Context {
+    pub fn start(mut self) {
+        thread::Builder::new()
+            .name("tx-generator".to_string())
+            .spawn(move || {
+                self.generator_loop();
+            })
+            .unwrap();
+        info!("Transaction generator initialized into paused mode");
+    }
+
+    fn handle_signal(&mut self, signal: ControlSignal) {
+        match signal {
+            ControlSignal::Start(theta) => {
+                info!("Transaction generator starting with theta {}", theta);
+                self.operating_state = OperatingState::Run(theta);
+            }
+            ControlSignal::Stop => {
+                info!("Transaction generator stopped");
+                self.operating_state = OperatingState::Paused;
+            }
+            ControlSignal::Configure(theta, max_value) => {
+                if let Some(max_value) = max_value {
+                    self.max_value = max_value.max(1);
+                }
+                if let (Some(theta), OperatingState::Run(_)) = (theta, &self.operating_state) {
+                    self.operating_state = OperatingState::Run(theta);
+                }
+                info!(
+                    "Transaction generator configured with theta {:?} and max value {}",
+                    theta, self.max_value
+                );
+            }
+        }
+    }
+
+    fn generator_loop(&mut self) {
+        loop {
+            // check and react to control signals
+            match self.operating_state {
+                OperatingState::Paused => {
+                    match self.control_chan.recv() {
+                        Ok(signal) => self.handle_signal(signal),
+                        Err(_) => return,
+                    }
+                    continue;
+                }
+                OperatingState::Run(_) => match self.control_chan.try_recv() {
+                    Ok(signal) => {
+                        self.handle_signal(signal);
+                        continue;
+                    }
+                    Err(TryRecvError::Empty) => {}
+                    Err(TryRecvError::Disconnected) => return,
+                },
+            }
+            let theta = match self.operating_state {
+                OperatingState::Run(theta) => theta,
+                OperatingState::Paused => continue,
+            };
+
+            self.sync();
+            let interval = if self.generate() {
+                time::Duration::from_micros(theta)
+            } else {
+                IDLE_INTERVAL
+            };
+            if !interval.is_zero() {
+                thread::sleep(interval);
+            }
+        }
+    }
+
+    /// Rebuild the account views from the state at the tip whenever the tip moves. Pending
+    /// transactions that were executed are dropped, and so are the ones that left the mempool
+    /// without being executed, together with every later one of the same account.
+    fn sync(&mut self) {
+        let chain = self.blockchain.lock().unwrap();
+        let tip = chain.tip();
+        if self.synced_tip == Some(tip) {
+            return;
+        }
+        let state = chain.tip_state();
+        let mempool = self.mempool.lock().unwrap();
+        for address in self.wallet.addresses() {
+            let (nonce, balance) = state.account(address);
+            let view = self.accounts.entry(*address).or_default();
+            view.pending.retain(|tx| tx.nonce > nonce);
+            let valid = view
+                .pending
+                .iter()
+                .zip(nonce + 1..)
+                .take_while(|(tx, expected)| {
+                    tx.nonce == *expected && mempool.tx_map.contains_key(&tx.hash)
+                })
+                .count();
+            view.pending.truncate(valid);
+            view.nonce = nonce + valid as u32;
+            let spent: u64 = view.pending.iter().map(|tx| tx.value).sum();
+            view.balance = balance.saturating_sub(spent);
+        }
+        self.synced_tip = Some(tip);
+    }
+
+    /// Create, store and announce one transaction. Returns false if no account can afford one.
+    fn generate(&mut self) -> bool {
+        let mut rng = rand::thread_rng();
+        let senders: Vec<Address> = self
+            .wallet
+            .addresses()
+            .iter()
+            .filter(|address| match self.accounts.get(address) {
+                Some(view) => view.balance > 0 && view.pending.len() < MAX_PENDING,
+                None => false,
+            })
+            .copied()
+            .collect();
+        let sender = match senders.choose(&mut rng) {
+            Some(sender) => *sender,
+            None => return false,
+        };
+
+        // pay a new account now and then, so that the ledger keeps growing
+        let others: Vec<Address> = self
+            .wallet
+            .addresses()
+            .iter()
+            .filter(|address| **address != sender)
+            .copied()
+            .collect();
+        let receiver = if others.is_empty()
+            || (self.wallet.len() < MAX_ACCOUNTS && rng.gen_bool(NEW_ACCOUNT_PROBABILITY))
+        {
+            let receiver = self.wallet.add(key_pair::random());
+            self.accounts.insert(receiver, AccountView::default());
+            receiver
+        } else {
+            *others.choose(&mut rng).unwrap()
+        };
+
+        let view = self.accounts.get_mut(&sender).unwrap();
+        let transaction = Transaction {
+            sender,
+            receiver,
+            value: rng.gen_range(1..=self.max_value.min(view.balance)),
+            account_nonce: view.nonce + 1,
+        };
+        let signed_tx = SignedTransaction::new(transaction, self.wallet.key(&sender).unwrap());
+        let hash = signed_tx.hash();
+        view.nonce += 1;
+        view.balance -= signed_tx.transaction.value;
+        view.pending.push_back(PendingTx {
+            hash,
+            nonce: view.nonce,
+            value: signed_tx.transaction.value,
+        });
+        debug!(
+            hash:% = hash;
+            "Generated transaction {} from {} to {}",
+            hash, sender, receiver
+        );
+
+        // add tx to mempool, and broadcast the tx hash to network
+        self.mempool.lock().unwrap().insert(&signed_tx);
+        self.server
+            .broadcast(Message::NewTransactionHashes(vec![hash]));
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ntest::timeout;
+    use std::sync::{Arc, Mutex};
+
+    use super::wallet::Wallet;
+    use crate::blockchain::state::ico_key;
+    use crate::blockchain::Blockchain;
+    use crate::miner::policy::Policy;
+    use crate::network::server::Handle as ServerHandle;
+    use crate::types::block::generate_random_block;
+    use crate::types::merkle::MerkleTree;
+    use crate::types::transaction::Mempool;
+
+    /// Wait until the mempool holds at least `n` transactions.
+    fn wait_for_mempool(mempool: &Mutex<Mempool>, n: usize) {
+        while mempool.lock().unwrap().tx_map.len() < n {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn start_without_accounts() {
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        let (server, _server_receiver) = ServerHandle::new_for_test();
+        let (_ctx, handle) = super::new(&blockchain, &mempool, &server, Wallet::new());
+        assert!(handle.start(0).is_err());
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn generate_valid_transactions() {
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        let (server, _server_receiver) = ServerHandle::new_for_test();
+        let mut wallet = Wallet::new();
+        wallet.add(ico_key(0));
+        let (ctx, handle) = super::new(&blockchain, &mempool, &server, wallet);
+        ctx.start();
+        handle.start(0).unwrap();
+        handle.configure(Some(100), Some(1000));
+        wait_for_mempool(&mempool, 50);
+        handle.stop();
+
+        // every transaction in the mempool fits in the next block
+        let mut chain = blockchain.lock().unwrap();
+        let mut block = generate_random_block(&chain.tip());
+        {
+            let mut mempool = mempool.lock().unwrap();
+            block.data = Policy::default().select(&chain, &mempool);
+            block.header.merkle_root = MerkleTree::new(&block.data).root();
+            assert_eq!(block.data.len(), mempool.tx_map.len());
+            chain.insert(&block).unwrap();
+            mempool.prune(chain.tip_state());
+            assert!(mempool.tx_map.is_empty());
+        }
+        drop(chain);
+
+        // and generation carries on from the new state
+        handle.start(0).unwrap();
+        wait_for_mempool(&mempool, 50);
+        handle.stop();
+        let chain = blockchain.lock().unwrap();
+        let mempool = mempool.lock().unwrap();
+        assert_eq!(
+            Policy::default().select(&chain, &mempool).len(),
+            mempool.tx_map.len()
+        );
+    }
+}
@@ -0,0 +1,43 @@
+use std::collections::HashMap;
+
+use crate::types::address::Address;
+use ring::signature::{Ed25519KeyPair, KeyPair};
+
+/// Key pairs of the accounts this node controls.
+#[derive(Default)]
+pub struct Wallet {
+    keys: HashMap<Address, Ed25519KeyPair>,
+    /// Addresses in the order they were added, to pick accounts at random
+    addresses: Vec<Address>,
+}
+
+impl Wallet {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Add a key pair to the wallet, returning the address of its account.
+    pub fn add(&mut self, key: Ed25519KeyPair) -> Address {
+        let address = Address::from_public_key_bytes(key.public_key().as_ref());
+        if self.keys.insert(address, key).is_none() {
+            self.addresses.push(address);
+        }
+        address
+    }
+
+    pub fn key(&self, address: &Address) -> Option<&Ed25519KeyPair> {
+        self.keys.get(address)
+    }
+
+    pub fn addresses(&self) -> &[Address] {
+        &self.addresses
+    }
+
+    pub fn len(&self) -> usize {
+        self.addresses.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.addresses.is_empty()
+    }
+}
@@ -0,0 +1,240 @@
+//! The logger of the node: plain text like before, or one JSON object per line so that the logs
+//! of many nodes can be merged and searched, with a level of its own for each part of the node.
+//!
+//! The peer address and the block or transaction hash of a JSON line are the `peer` and `hash`
+//! key-values of the record, such as `warn!(peer:% = addr, hash:% = hash; "...")`.
+
+use log::kv::Key;
+use log::{LevelFilter, Log, Metadata, Record, SetLoggerError};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Parts of the node whose level can be set on its own, the modules under the crate root.
+pub const MODULES: [&str; 4] = ["network", "miner", "blockchain", "api"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+impl std::str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("unknown log format {}", s)),
+        }
+    }
+}
+
+/// One line of the JSON logs.
+#[derive(Serialize)]
+struct Line<'a> {
+    time: String,
+    level: &'a str,
+    module: &'a str,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    peer: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hash: Option<String>,
+}
+
+pub struct Logger {
+    format: Format,
+    /// Level of the modules without one of their own, and of other crates
+    default: LevelFilter,
+    levels: HashMap<&'static str, LevelFilter>,
+}
+
+impl Logger {
+    pub fn new(format: Format, default: LevelFilter) -> Self {
+        Self {
+            format,
+            default,
+            levels: HashMap::new(),
+        }
+    }
+
+    /// The level of `-v` given `verbosity` times: errors only without it, then warnings, info,
+    /// debug and trace.
+    pub fn verbosity(verbosity: usize) -> LevelFilter {
+        match verbosity {
+            0 => LevelFilter::Error,
+            1 => LevelFilter::Warn,
+            2 => LevelFilter::Info,
+            3 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        }
+    }
+
+    /// Set the level of one module from a MODULE=LEVEL setting, such as `network=debug`.
+    pub fn set_level(&mut self, setting: &str) -> Result<(), String> {
+        let (module, level) = match setting.split_once('=') {
+            Some(parts) => parts,
+            None => return Err(format!("expected MODULE=LEVEL, got {}", setting)),
+        };
+        let module = match MODULES.iter().find(|m| **m == module) {
+            Some(module) => *module,
+            None => {
+                return Err(format!(
+                    "unknown module {}, expected one of {}",
+                    module,
+                    MODULES.join(", ")
+                ))
+            }
+        };
+        let level = level
+            .parse::<LevelFilter>()
+            .map_err(|_| format!("unknown log level {}", level))?;
+        self.levels.insert(module, level);
+        Ok(())
+    }
+
+    pub fn init(self) -> Result<(), SetLoggerError> {
+        let max_level = self.levels.values().copied().fold(self.default, |a, b| a.max(b));
+        log::set_boxed_logger(Box::new(self))?;
+        log::set_max_level(max_level);
+        Ok(())
+    }
+
+    fn level(&self, target: &str) -> LevelFilter {
+        // targets are module paths, such as bitcoin::network::server
+        target
+            .split("::")
+            .nth(1)
+            .and_then(|module| self.levels.get(module))
+            .copied()
+            .unwrap_or(self.default)
+    }
+
+    fn format(&self, record: &Record) -> String {
+        match self.format {
+            Format::Text => format!("{} - {}", record.level(), record.args()),
+            Format::Json => {
+                let line = Line {
+                    time: timestamp(SystemTime::now()),
+                    level: record.level().as_str(),
+                    module: record.target(),
+                    message: record.args().to_string(),
+                    peer: field(record, "peer"),
+                    hash: field(record, "hash"),
+                };
+                serde_json::to_string(&line).unwrap()
+            }
+        }
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = self.format(record);
+        let _ = writeln!(std::io::stderr().lock(), "{}", line);
+    }
+
+    fn flush(&self) {
+        let _ = std::io::stderr().flush();
+    }
+}
+
+/// The value of a key-value of a record.
+fn field(record: &Record, key: &'static str) -> Option<String> {
+    record
+        .key_values()
+        .get(Key::from_str(key))
+        .map(|value| value.to_string())
+}
+
+/// RFC 3339 time in UTC, with milliseconds.
+fn timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs_of_day) = ((secs / 86400) as i64, secs % 86400);
+    // days since the epoch to a civil date, counting from the 1st of March of year 0 so that
+    // leap days end the year
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn levels_per_module() {
+        let mut logger = Logger::new(Format::Json, Logger::verbosity(1));
+        logger.set_level("network=debug").unwrap();
+        logger.set_level("api=off").unwrap();
+        assert!(logger.set_level("network").is_err());
+        assert!(logger.set_level("wallet=info").is_err());
+        assert!(logger.set_level("miner=loud").is_err());
+        assert_eq!(logger.level("bitcoin::network::server"), LevelFilter::Debug);
+        assert_eq!(logger.level("bitcoin::api"), LevelFilter::Off);
+        assert_eq!(logger.level("bitcoin::miner::worker"), LevelFilter::Warn);
+        assert_eq!(logger.level("tiny_http"), LevelFilter::Warn);
+    }
+
+    #[test]
+    fn json_fields() {
+        let hash = "ab".repeat(32);
+        let logger = Logger::new(Format::Json, LevelFilter::Info);
+        let format = |kvs: &[(&str, &str)]| {
+            let line = logger.format(
+                &Record::builder()
+                    .args(format_args!("Transaction {} from 10.0.0.2:6000 is invalid", hash))
+                    .level(log::Level::Warn)
+                    .target("bitcoin::network::worker")
+                    .key_values(&kvs)
+                    .build(),
+            );
+            serde_json::from_str::<serde_json::Value>(&line).unwrap()
+        };
+        let line = format(&[("peer", "10.0.0.2:6000"), ("hash", &hash)]);
+        assert_eq!(line["level"], "WARN");
+        assert_eq!(line["module"], "bitcoin::network::worker");
+        assert_eq!(line["peer"], "10.0.0.2:6000");
+        assert_eq!(line["hash"], hash.as_str());
+
+        // the message is not searched for fields
+        let line = format(&[]);
+        assert!(line.get("peer").is_none());
+        assert!(line.get("hash").is_none());
+    }
+
+    #[test]
+    fn rfc3339_timestamps() {
+        assert_eq!(timestamp(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        let time = UNIX_EPOCH + Duration::from_millis(1_709_210_096_789);
+        assert_eq!(timestamp(time), "2024-02-29T12:34:56.789Z");
+    }
+}
@@ -4,21 +4,41 @@ extern crate hex_literal;
 
 pub mod api;
 pub mod blockchain;
+pub mod generator;
+pub mod logging;
 pub mod miner;
 pub mod network;
+#[cfg(any(test, test_utilities))]
+pub mod simulation;
 pub mod types;
 
 use api::Server as ApiServer;
+use blockchain::archive;
+use blockchain::pruner::Pruner;
+use blockchain::snapshot::Snapshot;
+use blockchain::spec::ChainSpec;
+use blockchain::state::{ico_key, ICO_ACCOUNTS};
 use blockchain::Blockchain;
 use clap::clap_app;
+use generator::wallet::Wallet;
 use log::{error, info};
+use logging::Logger;
+use miner::policy::Policy;
+use network::ban::BanList;
+use network::envelope;
+use network::secure;
+use network::transport::Tcp;
 use smol::channel;
 use std::collections::HashMap;
 use std::net;
+use std::path::Path;
 use std::process;
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time;
+use types::address::Address;
+use types::hash::H256;
+use types::key_pair;
 use types::transaction::Mempool;
 
 fn main() {
@@ -27,17 +47,140 @@ fn main() {
      (version: "0.1")
      (about: "Bitcoin client")
      (@arg verbose: -v ... "Increases the verbosity of logging")
+     (@arg log_format: --("log-format") [FORMAT] default_value("text") possible_values(&["text", "json"]) "Sets the format of the logs, json writes one object per line with the time, module, peer and hash of each message")
+     (@arg log_level: --("log-level") ... [LEVEL] "Sets the level of one part of the node as MODULE=LEVEL, MODULE being network, miner, blockchain or api, may be repeated")
      (@arg peer_addr: --p2p [ADDR] default_value("127.0.0.1:6000") "Sets the IP address and the port of the P2P server")
      (@arg api_addr: --api [ADDR] default_value("127.0.0.1:7000") "Sets the IP address and the port of the API server")
      (@arg known_peer: -c --connect ... [PEER] "Sets the peers to connect to at start")
      (@arg p2p_workers: --("p2p-workers") [INT] default_value("4") "Sets the number of worker threads for P2P server")
+     (@arg miner_threads: --("miner-threads") [INT] default_value("1") "Sets the number of mining threads")
+     (@arg ban_threshold: --("ban-threshold") [INT] default_value("100") "Sets the misbehavior score at which a peer gets banned")
+     (@arg network_magic: --("network-magic") [HEX] default_value("0470fa22") "Sets the magic value identifying the network, peers with a different one are refused")
+     (@arg encrypt: --encrypt "Encrypts and authenticates P2P traffic, peers must use this option too")
+     (@arg identity: --identity [FILE] "Sets the file holding the Ed25519 identity key of this node, created if missing")
+     (@arg trusted_peer: --("trusted-peer") ... [KEY] requires[encrypt] "Only accepts encrypted peers with this hex identity key")
+     (@arg regtest: --regtest "Runs a local test network, where any nonce solves a block and blocks are generated on demand")
+     (@arg chain_spec: --("chain-spec") [FILE] "Sets the JSON file with the genesis block and initial allocations of the chain")
+     (@arg checkpoint: --checkpoint ... [CHECKPOINT] "Adds a HEIGHT:HASH checkpoint to the ones of the chain spec, may be repeated")
+     (@arg load_snapshot: --("load-snapshot") [FILE] "Starts from the state snapshot in this file instead of the genesis block, and only syncs the blocks after it")
+     (@arg reward_address: --("reward-address") [ADDR] "Sets the address credited with the rewards of mined blocks, defaults to the first wallet account")
+     (@arg wallet_account: --("wallet-account") ... [INDEX] "Gives the transaction generator the key of this ICO account, may be repeated, the generator has no account to spend from without it")
+     (@arg min_block_txs: --("min-block-txs") [INT] default_value("0") "Sets the number of transactions the miner waits for before mining a block")
+     (@arg max_block_size: --("max-block-size") [BYTES] "Sets the largest block the miner builds, capped by the chain spec")
+     (@arg max_block_txs: --("max-block-txs") [INT] "Sets the largest number of transactions in the blocks the miner builds, capped by the chain spec")
+     (@arg tx_order: --("tx-order") [ORDER] default_value("arrival") possible_values(&["arrival", "value"]) "Sets the order in which the miner picks transactions")
+     (@arg prune_depth: --("prune-depth") [INT] default_value("100") "Prunes side forks that branch off more than this many blocks below the tip, and the states of the blocks below it, in the background")
+     (@arg finality_depth: --("finality-depth") [INT] default_value("6") "Sets how many confirmations, the block itself included, a block needs to be final")
+     (@arg ban_duration: --("ban-duration") [SECS] default_value("3600") "Sets how long a misbehaving peer stays banned, in seconds")
+     (@arg ban_allow: --("ban-allow") ... [IP] "Never bans the peers from this IP, such as 127.0.0.1 on a local test network, may be repeated")
+     (@arg data_dir: --("data-dir") [DIR] default_value("data") "Sets the directory the API writes snapshots and chain files to, the paths it is given are relative to it")
+     (@subcommand export_chain =>
+      (name: "export-chain")
+      (about: "Downloads the chain of the node whose API server is at --api to a file")
+      (@arg out: --out <FILE> "Sets the file to write")
+      (@arg all: --all "Writes every block of the node, side forks included, instead of the longest chain"))
+     (@subcommand import_chain =>
+      (name: "import-chain")
+      (about: "Starts a node with the blocks of a chain file, each one validated as if it came from a peer")
+      (@arg file: <FILE> "Sets the chain file written by export-chain"))
     )
     .get_matches();
 
-    // init logger
+    // init logger, the levels of the modules are checked once errors can be logged
     let verbosity = matches.occurrences_of("verbose") as usize;
-    stderrlog::new().verbosity(verbosity).init().unwrap();
-    let blockchain = Blockchain::new();
+    let log_format = matches.value_of("log_format").unwrap().parse().unwrap();
+    let mut logger = Logger::new(log_format, Logger::verbosity(verbosity));
+    let log_level_errors: Vec<String> = matches
+        .values_of("log_level")
+        .into_iter()
+        .flatten()
+        .filter_map(|setting| logger.set_level(setting).err())
+        .collect();
+    logger.init().unwrap();
+    if let Some(e) = log_level_errors.first() {
+        error!("Error parsing log level: {}", e);
+        process::exit(1);
+    }
+
+    // export the chain of a running node, this process does not become a node
+    if let Some(export) = matches.subcommand_matches("export-chain") {
+        let api_addr = matches.value_of("api_addr").unwrap();
+        let out = Path::new(export.value_of("out").unwrap());
+        match export_chain(api_addr, out, export.is_present("all")) {
+            Ok(bytes) => println!("Wrote {} bytes to {}", bytes, out.display()),
+            Err(e) => {
+                error!("Error exporting chain: {}", e);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+
+    // load the chain spec
+    let mut chain_spec = match matches.value_of("chain_spec") {
+        Some(path) => ChainSpec::load(Path::new(path)).unwrap_or_else(|e| {
+            error!("Error loading chain spec {}: {}", path, e);
+            process::exit(1);
+        }),
+        None => ChainSpec::default(),
+    };
+    if let Some(checkpoints) = matches.values_of("checkpoint") {
+        for checkpoint in checkpoints {
+            let (height, hash) = checkpoint.split_once(':').unwrap_or_else(|| {
+                error!("Error parsing checkpoint {}: expected HEIGHT:HASH", checkpoint);
+                process::exit(1);
+            });
+            let height = height.parse::<usize>().unwrap_or_else(|e| {
+                error!("Error parsing checkpoint height {}: {}", height, e);
+                process::exit(1);
+            });
+            let hash = hash.parse::<H256>().unwrap_or_else(|e| {
+                error!("Error parsing checkpoint hash {}: {}", hash, e);
+                process::exit(1);
+            });
+            chain_spec.add_checkpoint(height, hash).unwrap_or_else(|e| {
+                error!("Error adding checkpoint {}: {}", checkpoint, e);
+                process::exit(1);
+            });
+        }
+    }
+    let regtest = matches.is_present("regtest");
+    if regtest {
+        info!("Running in regtest mode");
+        chain_spec.difficulty = [255u8; 32].into();
+    }
+    let blockchain = match matches.value_of("load_snapshot") {
+        Some(path) => {
+            let snapshot = Snapshot::load(Path::new(path), &chain_spec).unwrap_or_else(|e| {
+                error!("Error loading snapshot {}: {}", path, e);
+                process::exit(1);
+            });
+            info!(
+                "Starting from the snapshot of block {} at height {}",
+                snapshot.block, snapshot.height
+            );
+            Blockchain::from_snapshot(&chain_spec, &snapshot)
+        }
+        None => {
+            let blockchain = Blockchain::from_spec(&chain_spec);
+            info!("Genesis block is {}", blockchain.tip());
+            blockchain
+        }
+    };
+    let mut blockchain = blockchain;
+    if let Some(import) = matches.subcommand_matches("import-chain") {
+        let path = import.value_of("file").unwrap();
+        let imported = archive::import(&mut blockchain, Path::new(path)).unwrap_or_else(|e| {
+            error!("Error importing chain {}: {}", path, e);
+            process::exit(1);
+        });
+        info!(
+            "Imported {} blocks from {}, tip is {}",
+            imported,
+            path,
+            blockchain.tip()
+        );
+    }
     let blockchain = Arc::new(Mutex::new(blockchain));
     let mempool = Mempool::new();
     let mempool = Arc::new(Mutex::new(mempool));
@@ -60,12 +203,92 @@ fn main() {
             error!("Error parsing API server address: {}", e);
             process::exit(1);
         });
+    let finality_depth = matches
+        .value_of("finality_depth")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing finality depth: {}", e);
+            process::exit(1);
+        });
 
     // create channels between server and worker
     let (msg_tx, msg_rx) = channel::bounded(10000);
 
+    // parse peer ban policy
+    let ban_threshold = matches
+        .value_of("ban_threshold")
+        .unwrap()
+        .parse::<u32>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing ban threshold: {}", e);
+            process::exit(1);
+        });
+    let ban_duration = matches
+        .value_of("ban_duration")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing ban duration: {}", e);
+            process::exit(1);
+        });
+    let mut ban_list = BanList::new(ban_threshold, time::Duration::from_secs(ban_duration));
+    for ip in matches.values_of("ban_allow").into_iter().flatten() {
+        let ip = ip.parse::<net::IpAddr>().unwrap_or_else(|e| {
+            error!("Error parsing allowed IP {}: {}", ip, e);
+            process::exit(1);
+        });
+        ban_list.allow(ip);
+    }
+
+    // parse network magic, regtest nodes keep to themselves unless told otherwise
+    let network_magic = if regtest && matches.occurrences_of("network_magic") == 0 {
+        envelope::REGTEST_MAGIC
+    } else {
+        let network_magic = matches.value_of("network_magic").unwrap();
+        u32::from_str_radix(network_magic.trim_start_matches("0x"), 16).unwrap_or_else(|e| {
+            error!("Error parsing network magic: {}", e);
+            process::exit(1);
+        })
+    };
+
+    // load the identity of this node if the transport is encrypted
+    let secure = if matches.is_present("encrypt") {
+        let identity = match matches.value_of("identity") {
+            Some(path) => secure::load_or_create_identity(Path::new(path)).unwrap_or_else(|e| {
+                error!("Error loading identity key: {}", e);
+                process::exit(1);
+            }),
+            None => key_pair::random(),
+        };
+        let trusted_peers = matches.values_of("trusted_peer").map(|keys| {
+            keys.map(|key| {
+                let mut bytes = [0u8; 32];
+                hex::decode_to_slice(key, &mut bytes).unwrap_or_else(|e| {
+                    error!("Error parsing trusted peer key {}: {}", key, e);
+                    process::exit(1);
+                });
+                bytes
+            })
+            .collect()
+        });
+        let config = secure::Config::new(identity, trusted_peers);
+        info!("P2P identity key is {}", hex::encode(config.public_key()));
+        Some(Arc::new(config))
+    } else {
+        None
+    };
+
     // start the p2p server
-    let (server_ctx, server) = network::server::new(p2p_addr, msg_tx).unwrap();
+    let (server_ctx, server) = network::server::new(
+        p2p_addr,
+        msg_tx,
+        ban_list,
+        network_magic,
+        secure,
+        Arc::new(Tcp),
+    )
+    .unwrap();
     server_ctx.start().unwrap();
 
     // start the worker
@@ -88,12 +311,88 @@ fn main() {
     );
     worker_ctx.start();
 
+    // start pruning stale forks and old states
+    let prune_depth = matches
+        .value_of("prune_depth")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing prune depth: {}", e);
+            process::exit(1);
+        });
+    Pruner::new(&blockchain, &orphan_buffer, prune_depth).start();
+
+    // the wallet of the transaction generator, holding the ICO accounts it is given
+    let mut wallet = Wallet::new();
+    for index in matches.values_of("wallet_account").into_iter().flatten() {
+        let index = index.parse::<u32>().unwrap_or_else(|e| {
+            error!("Error parsing wallet account: {}", e);
+            process::exit(1);
+        });
+        if index >= ICO_ACCOUNTS {
+            error!("There are only {} ICO accounts", ICO_ACCOUNTS);
+            process::exit(1);
+        }
+        let address = wallet.add(ico_key(index));
+        info!("Transaction generator spends from ICO account {}", address);
+    }
+
     // start the miner
-    let (miner_ctx, miner, finished_block_chan) = miner::new(&blockchain, &mempool);
-    let miner_worker_ctx = miner::worker::Worker::new(&server, finished_block_chan, &blockchain);
+    let miner_threads = matches
+        .value_of("miner_threads")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing miner threads: {}", e);
+            process::exit(1);
+        });
+    let reward_address = match matches.value_of("reward_address") {
+        Some(address) => address.parse::<Address>().unwrap_or_else(|e| {
+            error!("Error parsing reward address: {}", e);
+            process::exit(1);
+        }),
+        None => wallet.addresses().first().copied().unwrap_or_default(),
+    };
+    let parse_limit = |name: &str, what: &str| match matches.value_of(name) {
+        Some(limit) => limit.parse::<usize>().unwrap_or_else(|e| {
+            error!("Error parsing {}: {}", what, e);
+            process::exit(1);
+        }),
+        None => usize::MAX,
+    };
+    let policy = Policy {
+        min_transactions: parse_limit("min_block_txs", "minimum block transactions"),
+        max_size: parse_limit("max_block_size", "maximum block size"),
+        max_transactions: parse_limit("max_block_txs", "maximum block transactions"),
+        order: matches.value_of("tx_order").unwrap().parse().unwrap_or_else(|e| {
+            error!("Error parsing transaction order: {}", e);
+            process::exit(1);
+        }),
+    };
+    let (miner_ctx, miner, finished_block_chan) = miner::new(
+        &blockchain,
+        &mempool,
+        miner_threads,
+        reward_address,
+        policy.clone(),
+    );
+    let miner_worker_ctx =
+        miner::worker::Worker::new(&server, finished_block_chan, &blockchain, &mempool);
+    let external_miner = miner::external::Handle::new(
+        &blockchain,
+        &mempool,
+        &miner_worker_ctx,
+        reward_address,
+        policy,
+        regtest,
+    );
     miner_ctx.start();
     miner_worker_ctx.start();
 
+    // start the transaction generator
+    let (generator_ctx, generator) = generator::new(&blockchain, &mempool, &server, wallet);
+    generator_ctx.start();
+
     // connect to known peers
     if let Some(known_peers) = matches.values_of("known_peer") {
         let known_peers: Vec<String> = known_peers.map(|x| x.to_owned()).collect();
@@ -110,11 +409,12 @@ fn main() {
                     };
                     match server.connect(addr) {
                         Ok(_) => {
-                            info!("Connected to outgoing peer {}", &addr);
+                            info!(peer:% = addr; "Connected to outgoing peer {}", &addr);
                             break;
                         }
                         Err(e) => {
                             error!(
+                                peer:% = addr;
                                 "Error connecting to peer {}, retrying in one second: {}",
                                 addr, e
                             );
@@ -128,9 +428,37 @@ fn main() {
     }
 
     // start the API server
-    ApiServer::start(api_addr, &miner, &server, &blockchain, &mempool);
+    ApiServer::start(
+        api_addr,
+        &miner,
+        &external_miner,
+        &generator,
+        &server,
+        &blockchain,
+        &mempool,
+        &orphan_buffer,
+        finality_depth,
+        Path::new(matches.value_of("data_dir").unwrap()),
+    );
 
     loop {
         std::thread::park();
     }
 }
+
+/// Download the chain of the node whose API server is at `api_addr` into `out`, and return
+/// the number of bytes written.
+fn export_chain(api_addr: &str, out: &Path, all: bool) -> Result<u64, String> {
+    let response = ureq::get(&format!("http://{}/blockchain/export", api_addr))
+        .query("all", &all.to_string())
+        .call()
+        .map_err(|e| e.to_string())?;
+    // errors come as a JSON message instead of the chain
+    if response.content_type() == "application/json" {
+        let body: serde_json::Value =
+            serde_json::from_reader(response.into_reader()).map_err(|e| e.to_string())?;
+        return Err(body["message"].as_str().unwrap_or_default().to_string());
+    }
+    let mut file = std::fs::File::create(out).map_err(|e| e.to_string())?;
+    std::io::copy(&mut response.into_reader(), &mut file).map_err(|e| e.to_string())
+}
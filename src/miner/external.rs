@@ -0,0 +1,281 @@
+//! Block templates for miners running outside of this process.
+//!
+//! A miner asks for a template, searches for a nonce such that the SHA256 of the header is at
+//! most the difficulty, and submits the header with that nonce. The header is the bincode
+//! encoding of `Header`: parent (32 bytes), nonce (4 bytes, little endian), difficulty (32
+//! bytes), timestamp (Unix milliseconds, 8 bytes, little endian), merkle root (32 bytes) and
+//! coinbase (20 bytes).
+
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use super::build_block;
+use super::policy::Policy;
+use super::worker::Worker;
+use crate::blockchain::{BlockError, Blockchain};
+use crate::types::address::Address;
+use crate::types::block::{Block, Header};
+use crate::types::hash::{Hashable, H256};
+use crate::types::transaction::Mempool;
+
+/// Number of templates handed out that can still be submitted.
+const MAX_TEMPLATES: usize = 64;
+/// Most blocks generated by one call of `generate`.
+const MAX_GENERATE: usize = 1000;
+
+/// A template as handed out to external miners.
+#[derive(Serialize, Debug, Clone)]
+pub struct BlockTemplate {
+    pub parent: String,
+    pub difficulty: String,
+    pub merkle_root: String,
+    pub coinbase: String,
+    pub timestamp: u64,
+    /// Hashes of the transactions, in block order.
+    pub transactions: Vec<String>,
+    /// Hex encoded header with a zero nonce, ready to be hashed once the nonce is filled in.
+    pub header: String,
+    /// Offset of the nonce in the header, in bytes.
+    pub nonce_offset: usize,
+}
+
+#[derive(Debug)]
+pub enum SubmitError {
+    /// The header could not be decoded.
+    Malformed(String),
+    /// The header was not built from a template we handed out, or the template expired.
+    UnknownTemplate,
+    Invalid(BlockError),
+    /// Blocks are only generated on demand in regtest mode.
+    NotRegtest,
+    /// No nonce solves the block.
+    NoSolution,
+    /// More blocks were asked for than `MAX_GENERATE`.
+    TooMany(usize),
+}
+
+impl std::fmt::Display for SubmitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SubmitError::Malformed(e) => write!(f, "malformed header: {}", e),
+            SubmitError::UnknownTemplate => write!(f, "unknown or expired template"),
+            SubmitError::Invalid(e) => write!(f, "invalid block: {}", e),
+            SubmitError::NotRegtest => write!(f, "blocks are only generated in regtest mode"),
+            SubmitError::NoSolution => write!(f, "no nonce solves the block"),
+            SubmitError::TooMany(n) => write!(
+                f,
+                "cannot generate {} blocks, at most {} at once",
+                n, MAX_GENERATE
+            ),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Handle {
+    blockchain: Arc<Mutex<Blockchain>>,
+    mempool: Arc<Mutex<Mempool>>,
+    worker: Worker,
+    /// Account credited with the block reward, unless the miner asks for another one
+    coinbase: Address,
+    /// How blocks are filled. The minimum number of transactions does not apply, since external
+    /// miners and `generate` decide themselves when to mine.
+    policy: Policy,
+    /// Whether blocks can be generated on demand
+    regtest: bool,
+    /// Recent templates, so that submitted headers can be matched with their transactions
+    templates: Arc<Mutex<VecDeque<Block>>>,
+}
+
+impl Handle {
+    /// Create a handle that submits solved blocks through `worker`, like the blocks mined in
+    /// this process. `regtest` enables `generate`.
+    pub fn new(
+        blockchain: &Arc<Mutex<Blockchain>>,
+        mempool: &Arc<Mutex<Mempool>>,
+        worker: &Worker,
+        coinbase: Address,
+        policy: Policy,
+        regtest: bool,
+    ) -> Self {
+        Self {
+            blockchain: Arc::clone(blockchain),
+            mempool: Arc::clone(mempool),
+            worker: worker.clone(),
+            coinbase,
+            policy,
+            regtest,
+            templates: Arc::new(Mutex::new(VecDeque::new())),
+        }
+    }
+
+    /// Build a template on top of the current tip, paying the block reward to `coinbase` or to
+    /// the default coinbase of this node.
+    pub fn template(&self, coinbase: Option<Address>) -> BlockTemplate {
+        let block = {
+            let chain = self.blockchain.lock().unwrap();
+            let mempool = self.mempool.lock().unwrap();
+            build_block(&chain, &mempool, &self.policy, coinbase.unwrap_or(self.coinbase))
+        };
+        let template = BlockTemplate {
+            parent: block.header.parent.to_string(),
+            difficulty: block.header.difficulty.to_string(),
+            merkle_root: block.header.merkle_root.to_string(),
+            coinbase: block.header.coinbase.to_string(),
+            timestamp: block.header.timestamp,
+            transactions: block.data.iter().map(|tx| tx.hash().to_string()).collect(),
+            header: hex::encode(bincode::serialize(&block.header).unwrap()),
+            nonce_offset: 32,
+        };
+        let mut templates = self.templates.lock().unwrap();
+        if templates.len() == MAX_TEMPLATES {
+            templates.pop_front();
+        }
+        templates.push_back(block);
+        template
+    }
+
+    /// Complete the template a hex encoded header was built from with `nonce`, then validate,
+    /// insert and broadcast the block. Returns the hash of the block.
+    pub fn submit(&self, header: &str, nonce: u32) -> Result<H256, SubmitError> {
+        let bytes = hex::decode(header).map_err(|e| SubmitError::Malformed(e.to_string()))?;
+        let mut header: Header =
+            bincode::deserialize(&bytes).map_err(|e| SubmitError::Malformed(e.to_string()))?;
+        header.nonce = nonce;
+        let data = {
+            let templates = self.templates.lock().unwrap();
+            let template = templates
+                .iter()
+                .rev()
+                .find(|t| {
+                    t.header.parent == header.parent
+                        && t.header.merkle_root == header.merkle_root
+                        && t.header.coinbase == header.coinbase
+                })
+                .ok_or(SubmitError::UnknownTemplate)?;
+            template.data.clone()
+        };
+        let block = Block { header, data };
+        self.worker.submit(&block).map_err(SubmitError::Invalid)?;
+        Ok(block.hash())
+    }
+
+    /// Mine `n` blocks one after the other on top of the tip, with the transactions of the
+    /// mempool, and submit them. Only meant for regtest, where any nonce solves a block.
+    /// Returns the hashes of the blocks.
+    pub fn generate(&self, n: usize) -> Result<Vec<H256>, SubmitError> {
+        if !self.regtest {
+            return Err(SubmitError::NotRegtest);
+        }
+        if n > MAX_GENERATE {
+            return Err(SubmitError::TooMany(n));
+        }
+        let mut hashes = Vec::with_capacity(n);
+        for _ in 0..n {
+            let mut block = {
+                let chain = self.blockchain.lock().unwrap();
+                let mempool = self.mempool.lock().unwrap();
+                build_block(&chain, &mempool, &self.policy, self.coinbase)
+            };
+            let difficulty = block.get_difficulty();
+            let nonce = (0..=u32::MAX).find(|nonce| {
+                block.header.nonce = *nonce;
+                block.hash() <= difficulty
+            });
+            if nonce.is_none() {
+                return Err(SubmitError::NoSolution);
+            }
+            self.worker.submit(&block).map_err(SubmitError::Invalid)?;
+            hashes.push(block.hash());
+        }
+        Ok(hashes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::message::Message;
+    use crate::network::server::Handle as ServerHandle;
+    use crossbeam::channel::unbounded;
+
+    #[test]
+    fn mine_external_template() {
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        let (server, server_receiver) = ServerHandle::new_for_test();
+        let (_sender, receiver) = unbounded();
+        let worker = Worker::new(&server, receiver, &blockchain, &mempool);
+        let handle = Handle::new(&blockchain, &mempool, &worker, Address::default(), Policy::default(), false);
+
+        let template = handle.template(None);
+        let genesis = blockchain.lock().unwrap().tip();
+        assert_eq!(template.parent, genesis.to_string());
+        assert!(template.transactions.is_empty());
+
+        // the genesis difficulty accepts any nonce
+        let hash = handle.submit(&template.header, 42).unwrap();
+        assert_eq!(blockchain.lock().unwrap().tip(), hash);
+        match server_receiver.recv() {
+            Some(Message::NewBlockHashes(v)) => assert_eq!(v, vec![hash]),
+            _ => panic!(),
+        }
+        // the same nonce again is the same block, which is neither inserted nor broadcast twice
+        assert!(matches!(
+            handle.submit(&template.header, 42),
+            Err(SubmitError::Invalid(BlockError::Duplicate))
+        ));
+        // another nonce gives a sibling block, which is just as valid
+        let sibling = handle.submit(&template.header, 43).unwrap();
+        match server_receiver.recv() {
+            Some(Message::NewBlockHashes(v)) => assert_eq!(v, vec![sibling]),
+            _ => panic!(),
+        }
+        assert!(matches!(
+            handle.submit(&"00".repeat(128), 0),
+            Err(SubmitError::UnknownTemplate)
+        ));
+        assert!(matches!(
+            handle.submit("zz", 0),
+            Err(SubmitError::Malformed(_))
+        ));
+        assert!(matches!(handle.generate(1), Err(SubmitError::NotRegtest)));
+    }
+
+    #[test]
+    fn generate_blocks() {
+        use crate::blockchain::state::ico_key;
+        use crate::types::transaction::{SignedTransaction, Transaction};
+        use ring::signature::KeyPair;
+
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        let (server, _server_receiver) = ServerHandle::new_for_test();
+        let (_sender, receiver) = unbounded();
+        let worker = Worker::new(&server, receiver, &blockchain, &mempool);
+        let handle = Handle::new(&blockchain, &mempool, &worker, Address::default(), Policy::default(), true);
+
+        let key = ico_key(0);
+        let transaction = Transaction {
+            sender: Address::from_public_key_bytes(key.public_key().as_ref()),
+            receiver: Address::default(),
+            value: 1,
+            account_nonce: 1,
+        };
+        let tx = SignedTransaction::new(transaction, &key);
+        mempool.lock().unwrap().insert(&tx);
+
+        let hashes = handle.generate(3).unwrap();
+        assert_eq!(hashes.len(), 3);
+        let chain = blockchain.lock().unwrap();
+        assert_eq!(chain.all_blocks_in_longest_chain()[1..], hashes[..]);
+        assert_eq!(chain.block_map[&hashes[0]].data.len(), 1);
+        assert!(mempool.lock().unwrap().tx_map.is_empty());
+        drop(chain);
+        assert!(matches!(
+            handle.generate(usize::MAX),
+            Err(SubmitError::TooMany(usize::MAX))
+        ));
+    }
+}
@@ -1,24 +1,41 @@
+pub mod external;
+pub mod policy;
+pub mod status;
 pub mod worker;
 
-use log::info;
+use log::{debug, error, info};
 
-use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
-use std::sync::{Arc, Mutex};
+use crossbeam::channel::{unbounded, Receiver, RecvTimeoutError, Sender};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
 use std::time;
 
 use std::thread;
 
 use crate::blockchain::Blockchain;
+use crate::types::address::Address;
 use crate::types::block::Block;
-use crate::types::block::Header;
-use crate::types::hash::Hashable;
+use crate::types::block::{unix_millis, Header};
+use crate::types::hash::{Hashable, H256};
 use crate::types::merkle::MerkleTree;
-use crate::types::transaction::{Mempool, SignedTransaction};
+use crate::types::transaction::Mempool;
+use policy::Policy;
+use status::{Stats, Status};
+
+/// How often the mining threads check whether their template is still current when they hash
+/// without pause. With a lambda they check after every hash.
+const STALE_CHECK_INTERVAL: u32 = 1024;
+/// How often the control thread checks the blockchain tip for changes.
+const TIP_POLL_INTERVAL: time::Duration = time::Duration::from_millis(10);
+/// How often the template is rebuilt to pick up new transactions.
+const TEMPLATE_REFRESH_INTERVAL: time::Duration = time::Duration::from_secs(1);
 
 enum ControlSignal {
-    Start(u64), // the number controls the lambda of interval between block generation
-    Update,     // update the block in mining, it may due to new blockchain tip or new transaction
-    Exit,
+    Start(u64),     // the number controls the lambda of interval between block generation
+    Update,         // update the block in mining, it may due to new blockchain tip or new transaction
+    Pause,          // stop mining, but keep the mining threads around
+    SetLambda(u64), // change the lambda while running
+    Exit,           // stop the mining threads, a later start spawns them again
 }
 
 enum OperatingState {
@@ -27,6 +44,106 @@ enum OperatingState {
     ShutDown,
 }
 
+impl std::fmt::Display for OperatingState {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            OperatingState::Paused => write!(f, "Paused"),
+            OperatingState::Run(lambda) => write!(f, "Run({})", lambda),
+            OperatingState::ShutDown => write!(f, "ShutDown"),
+        }
+    }
+}
+
+/// A block to mine. Every mining thread works on a different part of the nonce space.
+struct Template {
+    id: u64,
+    block: Block,
+}
+
+/// State shared by the control thread and the mining threads.
+struct Shared {
+    /// The block being mined, `None` while there is nothing to mine.
+    template: Mutex<Option<Arc<Template>>>,
+    template_changed: Condvar,
+    /// Id of the current template. A thread that solves the template bumps it, so that the
+    /// other threads stop working on it.
+    generation: AtomicU64,
+    /// Interval between two hashes of a mining thread, in microseconds.
+    lambda: AtomicU64,
+    stats: Stats,
+}
+
+impl Shared {
+    fn new() -> Self {
+        Self {
+            template: Mutex::new(None),
+            template_changed: Condvar::new(),
+            generation: AtomicU64::new(0),
+            lambda: AtomicU64::new(0),
+            stats: Stats::new(OperatingState::Paused.to_string()),
+        }
+    }
+
+    /// Replace the template, or clear it with `None`. Returns the id of the new template.
+    fn publish(&self, block: Option<Block>) -> u64 {
+        let mut template = self.template.lock().unwrap();
+        let id = self.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        *template = block.map(|block| Arc::new(Template { id, block }));
+        self.template_changed.notify_all();
+        id
+    }
+
+    fn is_current(&self, id: u64) -> bool {
+        self.generation.load(Ordering::SeqCst) == id
+    }
+
+    /// Claim the solution of a template. Only the first thread to solve it succeeds, the
+    /// others are woken up if they are resting.
+    fn solve(&self, id: u64) -> bool {
+        let _template = self.template.lock().unwrap();
+        let solved = self
+            .generation
+            .compare_exchange(id, id + 1, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok();
+        self.template_changed.notify_all();
+        solved
+    }
+
+    /// Sleep for `interval` between two hashes of template `id`, waking up early if the
+    /// template is replaced or `stop` is set.
+    fn rest(&self, id: u64, interval: time::Duration, stop: &AtomicBool) {
+        let template = self.template.lock().unwrap();
+        let _ = self
+            .template_changed
+            .wait_timeout_while(template, interval, |_| {
+                self.is_current(id) && !stop.load(Ordering::SeqCst)
+            })
+            .unwrap();
+    }
+
+    /// Block until there is a template other than `done`, or `stop` is set.
+    fn next_template(&self, done: u64, stop: &AtomicBool) -> Option<Arc<Template>> {
+        let mut template = self.template.lock().unwrap();
+        loop {
+            if stop.load(Ordering::SeqCst) {
+                return None;
+            }
+            match &*template {
+                Some(t) if t.id != done && self.is_current(t.id) => return Some(Arc::clone(t)),
+                _ => {}
+            }
+            template = self.template_changed.wait(template).unwrap();
+        }
+    }
+
+    /// Tell the mining threads watching `stop` to exit.
+    fn shut_down(&self, stop: &AtomicBool) {
+        let _template = self.template.lock().unwrap();
+        stop.store(true, Ordering::SeqCst);
+        self.template_changed.notify_all();
+    }
+}
+
 pub struct Context {
     /// Channel for receiving control signal
     control_chan: Receiver<ControlSignal>,
@@ -34,20 +151,40 @@ pub struct Context {
     finished_block_chan: Sender<Block>,
     blockchain: Arc<Mutex<Blockchain>>,
     mempool: Arc<Mutex<Mempool>>,
+    num_threads: usize,
+    /// Account credited with the reward of the blocks we mine
+    coinbase: Address,
+    policy: Policy,
+    shared: Arc<Shared>,
+    /// Set to stop the mining threads we spawned last, `None` when there are none. Every start
+    /// gets a flag of its own, so that the threads of an earlier start keep exiting.
+    stop: Option<Arc<AtomicBool>>,
+    /// Tip the current template builds on, id of the template and number of transactions
+    template: Option<(H256, u64, usize)>,
+    /// When the templates were last rebuilt
+    built_at: time::Instant,
 }
 
 #[derive(Clone)]
 pub struct Handle {
     /// Channel for sending signal to the miner thread
     control_chan: Sender<ControlSignal>,
+    shared: Arc<Shared>,
+    blockchain: Arc<Mutex<Blockchain>>,
+    num_threads: usize,
 }
 
 pub fn new(
     blockchain: &Arc<Mutex<Blockchain>>,
     mempool: &Arc<Mutex<Mempool>>,
+    num_threads: usize,
+    coinbase: Address,
+    policy: Policy,
 ) -> (Context, Handle, Receiver<Block>) {
     let (signal_chan_sender, signal_chan_receiver) = unbounded();
     let (finished_block_sender, finished_block_receiver) = unbounded();
+    let shared = Arc::new(Shared::new());
+    let num_threads = num_threads.max(1);
 
     let ctx = Context {
         control_chan: signal_chan_receiver,
@@ -55,10 +192,20 @@ pub fn new(
         finished_block_chan: finished_block_sender,
         blockchain: Arc::clone(blockchain),
         mempool: Arc::clone(mempool),
+        num_threads,
+        coinbase,
+        policy,
+        shared: Arc::clone(&shared),
+        stop: None,
+        template: None,
+        built_at: time::Instant::now(),
     };
 
     let handle = Handle {
         control_chan: signal_chan_sender,
+        shared,
+        blockchain: Arc::clone(blockchain),
+        num_threads,
     };
 
     (ctx, handle, finished_block_receiver)
@@ -70,14 +217,27 @@ fn test_new() -> (Context, Handle, Receiver<Block>) {
     let blockchain = Arc::new(Mutex::new(blockchain));
     let mempool = Mempool::new();
     let mempool = Arc::new(Mutex::new(mempool));
-    new(&blockchain, &mempool)
+    new(&blockchain, &mempool, 1, Address::default(), Policy::default())
 }
 
 impl Handle {
+    /// Stop the mining threads. Mining can be restarted with `start`.
     pub fn exit(&self) {
         self.control_chan.send(ControlSignal::Exit).unwrap();
     }
 
+    /// Stop mining until the next `start`, keeping the mining threads around.
+    pub fn pause(&self) {
+        self.control_chan.send(ControlSignal::Pause).unwrap();
+    }
+
+    /// Change the lambda of a running miner.
+    pub fn set_lambda(&self, lambda: u64) {
+        self.control_chan
+            .send(ControlSignal::SetLambda(lambda))
+            .unwrap();
+    }
+
     pub fn start(&self, lambda: u64) {
         self.control_chan
             .send(ControlSignal::Start(lambda))
@@ -87,6 +247,12 @@ impl Handle {
     pub fn update(&self) {
         self.control_chan.send(ControlSignal::Update).unwrap();
     }
+
+    /// Report what the miner is doing.
+    pub fn status(&self) -> Status {
+        let blockchain = self.blockchain.lock().unwrap();
+        self.shared.stats.report(&blockchain, self.num_threads)
+    }
 }
 
 impl Context {
@@ -100,114 +266,318 @@ impl Context {
         info!("Miner initialized into paused mode");
     }
 
+    /// Start the mining threads, they stay idle until a template is published.
+    fn spawn_threads(&mut self) {
+        let stop = Arc::new(AtomicBool::new(false));
+        for i in 0..self.num_threads {
+            let stop = Arc::clone(&stop);
+            let shared = Arc::clone(&self.shared);
+            let finished_block_chan = self.finished_block_chan.clone();
+            let blockchain = Arc::clone(&self.blockchain);
+            let mempool = Arc::clone(&self.mempool);
+            let num_threads = self.num_threads as u64;
+            thread::Builder::new()
+                .name(format!("miner-{}", i))
+                .spawn(move || {
+                    hash_loop(
+                        i as u64,
+                        num_threads,
+                        &shared,
+                        &stop,
+                        &finished_block_chan,
+                        &blockchain,
+                        &mempool,
+                    );
+                })
+                .unwrap();
+        }
+        self.stop = Some(stop);
+    }
+
+    /// Tell the mining threads to stop. They exit after their current hash without being
+    /// waited for, so that the control thread keeps answering signals.
+    fn stop_threads(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            self.shared.shut_down(&stop);
+        }
+    }
+
+    /// Stop mining the current template, if any.
+    fn clear_template(&mut self) {
+        self.template = None;
+        self.shared.stats.set_template(None);
+        self.shared.publish(None);
+    }
+
+    fn handle_signal(&mut self, signal: ControlSignal) -> bool {
+        match signal {
+            ControlSignal::Exit => {
+                if let OperatingState::ShutDown = self.operating_state {
+                    return false;
+                }
+                info!("Miner shutting down");
+                self.operating_state = OperatingState::ShutDown;
+                self.clear_template();
+                self.stop_threads();
+            }
+            ControlSignal::Pause => {
+                if let OperatingState::Run(_) = self.operating_state {
+                    info!("Miner paused");
+                    self.operating_state = OperatingState::Paused;
+                    self.clear_template();
+                }
+            }
+            ControlSignal::Start(i) => {
+                info!("Miner starting in continuous mode with lambda {}", i);
+                if self.stop.is_none() {
+                    self.spawn_threads();
+                }
+                self.operating_state = OperatingState::Run(i);
+            }
+            ControlSignal::SetLambda(i) => match self.operating_state {
+                OperatingState::Run(_) => {
+                    info!("Miner lambda set to {}", i);
+                    self.operating_state = OperatingState::Run(i);
+                }
+                _ => {
+                    info!("Miner is not running, ignoring lambda {}", i);
+                }
+            },
+            ControlSignal::Update => {
+                // rebuild the template in the running state, nothing to do when paused
+                return true;
+            }
+        };
+        self.shared.stats.set_state(self.operating_state.to_string());
+        false
+    }
+
     fn miner_loop(&mut self) {
         // main mining loop
         loop {
             // check and react to control signals
+            let mut update = false;
             match self.operating_state {
-                OperatingState::Paused => {
-                    let signal = self.control_chan.recv().unwrap();
-                    match signal {
-                        ControlSignal::Exit => {
-                            info!("Miner shutting down");
-                            self.operating_state = OperatingState::ShutDown;
-                        }
-                        ControlSignal::Start(i) => {
-                            info!("Miner starting in continuous mode with lambda {}", i);
-                            self.operating_state = OperatingState::Run(i);
-                        }
-                        ControlSignal::Update => {
-                            // in paused state, don't need to update
+                OperatingState::Paused | OperatingState::ShutDown => {
+                    let signal = match self.control_chan.recv() {
+                        Ok(signal) => signal,
+                        Err(_) => {
+                            // every handle is gone, nothing can restart us
+                            self.stop_threads();
+                            return;
                         }
                     };
+                    self.handle_signal(signal);
                     continue;
                 }
-                OperatingState::ShutDown => {
-                    return;
-                }
-                _ => match self.control_chan.try_recv() {
+                _ => match self.control_chan.recv_timeout(TIP_POLL_INTERVAL) {
                     Ok(signal) => {
-                        match signal {
-                            ControlSignal::Exit => {
-                                info!("Miner shutting down");
-                                self.operating_state = OperatingState::ShutDown;
-                            }
-                            ControlSignal::Start(i) => {
-                                info!("Miner starting in continuous mode with lambda {}", i);
-                                self.operating_state = OperatingState::Run(i);
-                            }
-                            ControlSignal::Update => {
-                                unimplemented!()
-                            }
-                        };
+                        update = self.handle_signal(signal);
+                    }
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => {
+                        self.stop_threads();
+                        return;
                     }
-                    Err(TryRecvError::Empty) => {}
-                    Err(TryRecvError::Disconnected) => panic!("Miner control channel detached"),
                 },
             }
-            if let OperatingState::ShutDown = self.operating_state {
-                return;
+            match self.operating_state {
+                OperatingState::Run(lambda) => self.shared.lambda.store(lambda, Ordering::Relaxed),
+                _ => continue,
             }
 
-            // actual mining, create a block
-            use std::time::SystemTime;
-            let mut chain_unwrapped = self.blockchain.lock().unwrap(); // acquire the lock and access the struct
-            let latest_block_hash = chain_unwrapped.tip();
-            // eprintln!("LATEST BLOCK HASH, {:?}", latest_block_hash);
-            let latest_block = &chain_unwrapped.block_map[&latest_block_hash];
-            let mut signed_tx_: Vec<SignedTransaction> = Vec::new();
-            // TODO: Add Tx from mempool to block
-            let mut unwrapped_mempool = self.mempool.lock().unwrap();
-            if unwrapped_mempool.tx_map.len() >= 10 {
-                for tx_hs in unwrapped_mempool.tx_map.keys() {
-                    signed_tx_.push(unwrapped_mempool.tx_map[&tx_hs].clone());
+            // build a new template if the tip moved or we are asked to. A solved template was
+            // inserted by the thread that solved it, or rejected, either way it is done
+            let tip = self.blockchain.lock().unwrap().tip();
+            let stale = match self.template {
+                Some((parent, id, _)) => parent != tip || !self.shared.is_current(id),
+                None => true,
+            };
+            // now and then, switch to a template with more transactions if there is one
+            let refresh = match self.template {
+                Some((_, id, _)) => {
+                    self.shared.is_current(id) && self.built_at.elapsed() > TEMPLATE_REFRESH_INTERVAL
                 }
-                for tx in signed_tx_.clone() {
-                    unwrapped_mempool.remove(&tx);
-                }
-                if !signed_tx_.is_empty() {
-                    let merkle_tree = MerkleTree::new(&signed_tx_.clone());
-                    let header = Header {
-                        parent: latest_block_hash,
-                        nonce: latest_block.header.nonce + 1, // does not matter, because we hash and it produces random chances of solving puzzle
-                        difficulty: latest_block.get_difficulty(),
-                        timestamp: SystemTime::now().elapsed().unwrap().subsec_millis(),
-                        merkle_root: merkle_tree.root(),
-                    };
-                    let new_block = Block {
-                        header,
-                        data: signed_tx_.clone(),
-                    };
-                    chain_unwrapped.insert(&new_block);
-
-                    if new_block.hash() <= new_block.get_difficulty() {
-                        self.finished_block_chan
-                            .send(new_block)
-                            .expect("Send finished block error");
+                None => false,
+            };
+            if stale || update || refresh {
+                self.built_at = time::Instant::now();
+                match self.build_template() {
+                    Some(block) => {
+                        let grown = match self.template {
+                            Some((_, _, n)) => block.data.len() > n,
+                            None => true,
+                        };
+                        if stale || update || grown {
+                            let parent = block.get_parent();
+                            let n = block.data.len();
+                            self.shared.stats.set_template(Some((parent, n)));
+                            self.template = Some((parent, self.shared.publish(Some(block)), n));
+                        }
+                    }
+                    None => {
+                        if stale || update {
+                            self.clear_template();
+                        }
                     }
                 }
             }
+            self.shared.stats.sample();
+        }
+    }
+
+    /// Build the next block to mine on top of the current tip, or `None` if there are fewer
+    /// transactions than the policy asks for.
+    fn build_template(&self) -> Option<Block> {
+        let chain_unwrapped = self.blockchain.lock().unwrap(); // acquire the lock and access the struct
+        let unwrapped_mempool = self.mempool.lock().unwrap();
+        let block = build_block(&chain_unwrapped, &unwrapped_mempool, &self.policy, self.coinbase);
+        if block.data.len() < self.policy.min_transactions {
+            return None;
+        }
+        debug!(hash:% = block.get_parent(); "Mining on top of {} with {} transactions", block.get_parent(), block.data.len());
+        Some(block)
+    }
+}
 
-            // drop lock
-            drop(unwrapped_mempool);
+/// Build a block on top of the current tip, with a zero nonce, holding the mempool
+/// transactions picked by `policy`. The block reward goes to `coinbase`.
+pub fn build_block(
+    chain: &Blockchain,
+    mempool: &Mempool,
+    policy: &Policy,
+    coinbase: Address,
+) -> Block {
+    let latest_block_hash = chain.tip();
+    let latest_block = &chain.block_map[&latest_block_hash];
+    // only transactions that execute on top of the tip, or the block would be rejected
+    let signed_tx_ = policy.select(chain, mempool);
+    let merkle_tree = MerkleTree::new(&signed_tx_);
+    let header = Header {
+        parent: latest_block_hash,
+        nonce: 0, // set by the miner
+        difficulty: latest_block.get_difficulty(),
+        // our clock may be behind the chain, the block would be rejected then
+        timestamp: unix_millis().max(chain.median_time_past(&latest_block_hash) + 1),
+        merkle_root: merkle_tree.root(),
+        coinbase,
+    };
+    Block {
+        header,
+        data: signed_tx_,
+    }
+}
 
-            if let OperatingState::Run(i) = self.operating_state {
-                if i != 0 {
-                    let interval = time::Duration::from_micros(i as u64);
-                    thread::sleep(interval);
+/// Body of mining thread `index` out of `num_threads`: search its share of the nonce space of
+/// each template until one of the threads solves it or the template is replaced. A thread that
+/// runs out of nonces starts over with a later timestamp.
+fn hash_loop(
+    index: u64,
+    num_threads: u64,
+    shared: &Shared,
+    stop: &AtomicBool,
+    finished_block_chan: &Sender<Block>,
+    blockchain: &Mutex<Blockchain>,
+    mempool: &Mutex<Mempool>,
+) {
+    let range = (u32::MAX as u64 + 1) / num_threads;
+    let first = (index * range) as u32;
+    let last = if index + 1 == num_threads {
+        u32::MAX
+    } else {
+        ((index + 1) * range - 1) as u32
+    };
+    let mut done = 0;
+    while let Some(template) = shared.next_template(done, stop) {
+        done = template.id;
+        let mut header = template.block.header.clone();
+        let mut hashes = 0;
+        'template: loop {
+            for nonce in first..=last {
+                let lambda = shared.lambda.load(Ordering::Relaxed);
+                if lambda != 0 || nonce % STALE_CHECK_INTERVAL == 0 {
+                    shared.stats.add_hashes(hashes);
+                    hashes = 0;
+                    if !shared.is_current(template.id) || stop.load(Ordering::SeqCst) {
+                        break 'template;
+                    }
+                }
+                header.nonce = nonce;
+                let hash = header.hash();
+                hashes += 1;
+                if hash <= header.difficulty {
+                    if shared.solve(template.id) {
+                        let new_block = Block {
+                            header,
+                            data: template.block.data.clone(),
+                        };
+                        insert_block(new_block, shared, finished_block_chan, blockchain, mempool);
+                    }
+                    break 'template;
+                }
+                if lambda != 0 {
+                    shared.rest(template.id, time::Duration::from_micros(lambda), stop);
                 }
             }
+            header.timestamp = unix_millis().max(header.timestamp + 1);
         }
+        shared.stats.add_hashes(hashes);
     }
 }
 
+/// Insert a block we solved into the blockchain, so that the next template builds on it, and
+/// hand it to the miner worker to broadcast.
+fn insert_block(
+    block: Block,
+    shared: &Shared,
+    finished_block_chan: &Sender<Block>,
+    blockchain: &Mutex<Blockchain>,
+    mempool: &Mutex<Mempool>,
+) {
+    let hash = block.hash();
+    let mut chain_unwrapped = blockchain.lock().unwrap();
+    if let Err(e) = chain_unwrapped.insert(&block) {
+        error!(hash:% = hash; "Mined block {} is invalid: {}", hash, e);
+        return;
+    }
+    shared.stats.block_found(hash, &chain_unwrapped);
+    mempool.lock().unwrap().prune(chain_unwrapped.tip_state());
+    drop(chain_unwrapped);
+    finished_block_chan
+        .send(block)
+        .expect("Send finished block error");
+}
+
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
 
 #[cfg(test)]
 mod test {
     use crate::types::hash::Hashable;
+    use crate::types::transaction::SignedTransaction;
     use ntest::timeout;
 
+    /// Transfers from ICO account 0 with nonces `first..first + n`, valid on top of a chain
+    /// that executed the ones before `first`.
+    fn valid_transactions(first: u32, n: u32) -> Vec<SignedTransaction> {
+        use crate::blockchain::state::ico_key;
+        use crate::types::address::Address;
+        use crate::types::transaction::Transaction;
+        use ring::signature::KeyPair;
+
+        let key = ico_key(0);
+        (first..first + n)
+            .map(|nonce| {
+                let transaction = Transaction {
+                    sender: Address::from_public_key_bytes(key.public_key().as_ref()),
+                    receiver: Address::default(),
+                    value: 1,
+                    account_nonce: nonce,
+                };
+                SignedTransaction::new(transaction, &key)
+            })
+            .collect()
+    }
+
     #[test]
     #[timeout(60000)]
     fn miner_three_block() {
@@ -221,6 +591,78 @@ mod test {
             block_prev = block_next;
         }
     }
+
+    #[test]
+    #[timeout(60000)]
+    fn miner_multi_thread() {
+        use crate::blockchain::Blockchain;
+        use crate::types::transaction::Mempool;
+        use std::sync::{Arc, Mutex};
+
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        let mut mempool = Mempool::new();
+        for tx in valid_transactions(1, 10) {
+            mempool.insert(&tx);
+        }
+        let mempool = Arc::new(Mutex::new(mempool));
+        let (miner_ctx, miner_handle, finished_block_chan) = super::new(&blockchain, &mempool, 4, Default::default(), Default::default());
+        miner_ctx.start();
+        miner_handle.start(0);
+        let block = finished_block_chan.recv().unwrap();
+        assert_eq!(block.hash(), blockchain.lock().unwrap().tip());
+        assert!(block.hash() <= block.get_difficulty());
+        assert_eq!(block.data.len(), 10);
+        let status = miner_handle.status();
+        assert_eq!(status.state, "Run(0)");
+        assert_eq!(status.threads, 4);
+        assert_eq!(status.blocks_found, 1);
+        // the miner inserted the block, it is the tip
+        assert_eq!(status.stale_blocks, 0);
+        miner_handle.exit();
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn miner_pause_and_restart() {
+        use crate::blockchain::Blockchain;
+        use crate::types::transaction::Mempool;
+        use std::sync::{Arc, Mutex};
+
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        let fill_mempool = |first| {
+            let mut mempool = mempool.lock().unwrap();
+            for tx in valid_transactions(first, 10) {
+                mempool.insert(&tx);
+            }
+        };
+        let wait_for_state = |handle: &super::Handle, state: &str| {
+            while handle.status().state != state {
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            }
+        };
+        let (miner_ctx, miner_handle, finished_block_chan) = super::new(&blockchain, &mempool, 2, Default::default(), Default::default());
+        miner_ctx.start();
+        fill_mempool(1);
+        miner_handle.start(0);
+        let block = finished_block_chan.recv().unwrap();
+        miner_handle.pause();
+        wait_for_state(&miner_handle, "Paused");
+        assert_eq!(blockchain.lock().unwrap().tip(), block.hash());
+        assert!(miner_handle.status().template_parent.is_none());
+        miner_handle.exit();
+        wait_for_state(&miner_handle, "ShutDown");
+
+        // mining picks up again after a stop, on top of the new tip
+        fill_mempool(11);
+        miner_handle.start(0);
+        miner_handle.set_lambda(1);
+        let block2 = finished_block_chan.recv().unwrap();
+        assert_eq!(block2.get_parent(), block.hash());
+        wait_for_state(&miner_handle, "Run(1)");
+        assert_eq!(miner_handle.status().blocks_found, 2);
+        miner_handle.exit();
+    }
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
@@ -0,0 +1,278 @@
+//! Which mempool transactions go into the blocks we build.
+//!
+//! Transactions are picked greedily: among the next transaction of every sender (by nonce), the
+//! first one in the configured order goes in, until the block is full or nothing fits anymore.
+
+use std::collections::VecDeque;
+use std::str::FromStr;
+
+use crate::blockchain::state::TxError;
+use crate::blockchain::Blockchain;
+use crate::types::hash::Hashable;
+use crate::types::transaction::{Mempool, SignedTransaction};
+
+/// Order in which the miner picks transactions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxOrder {
+    /// Oldest first.
+    Arrival,
+    /// Largest value first, then oldest first.
+    Value,
+}
+
+impl FromStr for TxOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "arrival" => Ok(TxOrder::Arrival),
+            "value" => Ok(TxOrder::Value),
+            _ => Err(format!("unknown transaction order {}, expected arrival or value", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for TxOrder {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            TxOrder::Arrival => write!(f, "arrival"),
+            TxOrder::Value => write!(f, "value"),
+        }
+    }
+}
+
+/// How the miner fills blocks. The limits can only make blocks smaller than the consensus
+/// limits of the chain spec.
+#[derive(Debug, Clone)]
+pub struct Policy {
+    /// The miner waits for this many transactions before mining a block.
+    pub min_transactions: usize,
+    /// Largest serialized size of a block, in bytes.
+    pub max_size: usize,
+    pub max_transactions: usize,
+    pub order: TxOrder,
+}
+
+impl Default for Policy {
+    /// Mine right away, with blocks as large as consensus allows, oldest transactions first.
+    fn default() -> Self {
+        Self {
+            min_transactions: 0,
+            max_size: usize::MAX,
+            max_transactions: usize::MAX,
+            order: TxOrder::Arrival,
+        }
+    }
+}
+
+impl Policy {
+    /// Pick the mempool transactions of a block on top of the tip. Every picked transaction
+    /// executes on top of the ones before it, and the block respects both the policy and the
+    /// consensus limits.
+    pub fn select(&self, chain: &Blockchain, mempool: &Mempool) -> Vec<SignedTransaction> {
+        let spec = chain.spec();
+        let max_size = self.max_size.min(spec.max_block_size) as u64;
+        let max_transactions = self.max_transactions.min(spec.max_block_transactions);
+        let tip = &chain.block_map[&chain.tip()];
+        // header and length of the transaction vector
+        let mut size = bincode::serialized_size(&tip.header).unwrap() + 8;
+
+        // the transactions of a sender by nonce, the ones sharing a nonce in the configured order
+        let mut queues: Vec<Queue> = mempool
+            .by_sender()
+            .into_values()
+            .map(|txs| {
+                let mut queue: Queue = txs
+                    .into_iter()
+                    .map(|tx| (self.key(mempool, tx), tx))
+                    .collect();
+                queue
+                    .make_contiguous()
+                    .sort_by_key(|(key, tx)| (tx.transaction.account_nonce, *key));
+                queue
+            })
+            .collect();
+        let mut state = chain.tip_state().clone();
+        let mut selected = Vec::new();
+        while selected.len() < max_transactions {
+            let next = queues
+                .iter()
+                .enumerate()
+                .filter_map(|(i, queue)| queue.front().map(|(key, tx)| (i, *key, *tx)))
+                .min_by_key(|(_, key, _)| *key);
+            let (i, _, tx) = match next {
+                Some(next) => next,
+                None => break,
+            };
+            let tx_size = bincode::serialized_size(tx).unwrap();
+            if size + tx_size > max_size {
+                skip(&mut queues[i]);
+                continue;
+            }
+            match state.apply(tx) {
+                Ok(()) => {}
+                Err(TxError::FutureNonce) => {
+                    // none of the later transactions of this sender can execute either
+                    queues[i].clear();
+                    continue;
+                }
+                Err(TxError::InsufficientBalance) => {
+                    skip(&mut queues[i]);
+                    continue;
+                }
+                Err(_) => {
+                    // another transaction may still use this nonce, e.g. when this one reuses
+                    // the nonce of a transaction picked before
+                    queues[i].pop_front();
+                    continue;
+                }
+            }
+            size += tx_size;
+            selected.push(tx.clone());
+            queues[i].pop_front();
+        }
+        selected
+    }
+
+    /// Sort key of a transaction, lower goes first.
+    fn key(&self, mempool: &Mempool, tx: &SignedTransaction) -> (u64, u64) {
+        let arrival = mempool.arrival(&tx.hash());
+        match self.order {
+            TxOrder::Arrival => (arrival, 0),
+            TxOrder::Value => (u64::MAX - tx.transaction.value, arrival),
+        }
+    }
+}
+
+/// Transactions of one sender, with their sort keys.
+type Queue<'a> = VecDeque<((u64, u64), &'a SignedTransaction)>;
+
+/// Drop the first transaction of a sender, which does not fit. Another transaction with the same
+/// nonce may, but without one the later transactions of the sender cannot execute either.
+fn skip(queue: &mut Queue) {
+    if let Some((_, skipped)) = queue.pop_front() {
+        let nonce = skipped.transaction.account_nonce;
+        if !matches!(queue.front(), Some((_, tx)) if tx.transaction.account_nonce == nonce) {
+            queue.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::spec::ChainSpec;
+    use crate::blockchain::state::ico_key;
+    use crate::types::address::Address;
+    use crate::types::transaction::Transaction;
+    use ring::signature::KeyPair;
+
+    fn transfer(account: u32, nonce: u32, value: u64) -> SignedTransaction {
+        let key = ico_key(account);
+        let transaction = Transaction {
+            sender: Address::from_public_key_bytes(key.public_key().as_ref()),
+            receiver: Address::default(),
+            value,
+            account_nonce: nonce,
+        };
+        SignedTransaction::new(transaction, &key)
+    }
+
+    fn values(txs: &[SignedTransaction]) -> Vec<u64> {
+        txs.iter().map(|tx| tx.transaction.value).collect()
+    }
+
+    #[test]
+    fn select_in_order() {
+        let chain = Blockchain::new();
+        let mut mempool = Mempool::new();
+        // inserted out of nonce order, and with a gap for account 1
+        for tx in &[
+            transfer(0, 2, 20),
+            transfer(1, 1, 5),
+            transfer(0, 1, 10),
+            transfer(2, 1, 30),
+            transfer(1, 3, 50),
+        ] {
+            mempool.insert(tx);
+        }
+        let arrival = Policy::default().select(&chain, &mempool);
+        assert_eq!(values(&arrival), vec![5, 10, 20, 30]);
+        let by_value = Policy {
+            order: TxOrder::Value,
+            ..Default::default()
+        };
+        // account 0 sends 10 before 20, so 20 waits for it
+        assert_eq!(values(&by_value.select(&chain, &mempool)), vec![30, 10, 20, 5]);
+    }
+
+    #[test]
+    fn select_past_duplicate_nonce() {
+        let chain = Blockchain::new();
+        let mut mempool = Mempool::new();
+        for tx in &[transfer(0, 1, 10), transfer(0, 1, 20), transfer(0, 2, 30)] {
+            mempool.insert(tx);
+        }
+        // one of the two transactions with nonce 1 goes in, the other one is skipped
+        let selected = Policy::default().select(&chain, &mempool);
+        let nonces: Vec<u32> = selected.iter().map(|tx| tx.transaction.account_nonce).collect();
+        assert_eq!(nonces, vec![1, 2]);
+        assert_eq!(selected[1].transaction.value, 30);
+    }
+
+    #[test]
+    fn select_affordable_with_same_nonce() {
+        use crate::blockchain::state::ICO_BALANCE;
+
+        let chain = Blockchain::new();
+        for order in &[TxOrder::Arrival, TxOrder::Value] {
+            let mut mempool = Mempool::new();
+            // the first one in either order cannot be paid, the one with the same nonce can
+            for tx in &[
+                transfer(0, 1, ICO_BALANCE + 1),
+                transfer(0, 1, 10),
+                transfer(0, 2, 30),
+                transfer(1, 1, ICO_BALANCE + 1),
+                transfer(1, 2, 40),
+            ] {
+                mempool.insert(tx);
+            }
+            let policy = Policy {
+                order: *order,
+                ..Default::default()
+            };
+            let mut selected = values(&policy.select(&chain, &mempool));
+            selected.sort_unstable();
+            // account 1 has nothing for nonce 1, so its nonce 2 waits
+            assert_eq!(selected, vec![10, 30]);
+        }
+    }
+
+    #[test]
+    fn select_within_limits() {
+        let mut mempool = Mempool::new();
+        for nonce in 1..=5 {
+            mempool.insert(&transfer(0, nonce, 1));
+        }
+        let chain = Blockchain::new();
+        let policy = Policy {
+            max_transactions: 2,
+            ..Default::default()
+        };
+        assert_eq!(policy.select(&chain, &mempool).len(), 2);
+
+        // the consensus limits win over a larger policy
+        let tx_size = bincode::serialized_size(&transfer(0, 1, 1)).unwrap() as usize;
+        let spec = ChainSpec {
+            max_block_size: 128 + 8 + 3 * tx_size,
+            ..Default::default()
+        };
+        let chain = Blockchain::from_spec(&spec);
+        assert_eq!(Policy::default().select(&chain, &mempool).len(), 3);
+        let chain = Blockchain::from_spec(&ChainSpec {
+            max_block_transactions: 4,
+            ..Default::default()
+        });
+        assert_eq!(Policy::default().select(&chain, &mempool).len(), 4);
+    }
+}
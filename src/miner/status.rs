@@ -0,0 +1,161 @@
+use serde::Serialize;
+use std::collections::{HashSet, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::blockchain::Blockchain;
+use crate::types::hash::H256;
+
+/// How often the hash counter is sampled for the hash rate.
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+/// The hash rate is averaged over this window.
+const RATE_WINDOW: Duration = Duration::from_secs(10);
+/// Number of the last blocks we found that are checked against the longest chain. Older ones
+/// are buried too deep to change, whether they are stale is settled.
+const MINED_WINDOW: usize = 1000;
+
+/// What the miner is doing, as reported by `/miner/status`.
+#[derive(Serialize, Debug, Clone)]
+pub struct Status {
+    pub state: String,
+    pub threads: usize,
+    /// Parent of the block being mined, if any.
+    pub template_parent: Option<String>,
+    pub template_transactions: usize,
+    pub hashes: u64,
+    /// Hashes per second over the last few seconds.
+    pub hash_rate: f64,
+    pub blocks_found: u64,
+    /// Blocks we found and inserted that are no longer on the longest chain.
+    pub stale_blocks: u64,
+}
+
+/// Counters published by the miner threads.
+#[derive(Default)]
+pub struct Stats {
+    hashes: AtomicU64,
+    blocks_found: AtomicU64,
+    /// The last `MINED_WINDOW` blocks we found
+    mined: Mutex<VecDeque<H256>>,
+    /// Blocks found before those that were off the longest chain
+    settled_stale: AtomicU64,
+    template: Mutex<Option<(H256, usize)>>,
+    state: Mutex<String>,
+    samples: Mutex<VecDeque<(Instant, u64)>>,
+}
+
+impl Stats {
+    pub fn new(state: String) -> Self {
+        Self {
+            state: Mutex::new(state),
+            ..Default::default()
+        }
+    }
+
+    pub fn add_hashes(&self, n: u64) {
+        self.hashes.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Count a block we found, once it is inserted into `blockchain`.
+    pub fn block_found(&self, hash: H256, blockchain: &Blockchain) {
+        self.blocks_found.fetch_add(1, Ordering::Relaxed);
+        let mut mined = self.mined.lock().unwrap();
+        mined.push_back(hash);
+        if mined.len() > MINED_WINDOW {
+            let oldest = mined.pop_front().unwrap();
+            if !blockchain.is_in_longest_chain(&oldest) {
+                self.settled_stale.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn set_state(&self, state: String) {
+        *self.state.lock().unwrap() = state;
+    }
+
+    /// Record the parent and number of transactions of the block being mined.
+    pub fn set_template(&self, template: Option<(H256, usize)>) {
+        *self.template.lock().unwrap() = template;
+    }
+
+    /// Sample the hash counter, at most once per `SAMPLE_INTERVAL`.
+    pub fn sample(&self) {
+        let now = Instant::now();
+        let mut samples = self.samples.lock().unwrap();
+        if let Some((last, _)) = samples.back() {
+            if now.duration_since(*last) < SAMPLE_INTERVAL {
+                return;
+            }
+        }
+        samples.push_back((now, self.hashes.load(Ordering::Relaxed)));
+        while samples.len() > 1 && now.duration_since(samples[0].0) > RATE_WINDOW {
+            samples.pop_front();
+        }
+    }
+
+    /// Hashes per second since the oldest sample in the window.
+    fn hash_rate(&self) -> f64 {
+        let now = Instant::now();
+        let samples = self.samples.lock().unwrap();
+        let (since, hashes) = match samples.iter().find(|(t, _)| now.duration_since(*t) <= RATE_WINDOW) {
+            Some(sample) => *sample,
+            None => return 0.0,
+        };
+        let elapsed = now.duration_since(since).as_secs_f64();
+        if elapsed == 0.0 {
+            return 0.0;
+        }
+        (self.hashes.load(Ordering::Relaxed) - hashes) as f64 / elapsed
+    }
+
+    pub fn report(&self, blockchain: &Blockchain, threads: usize) -> Status {
+        let longest_chain: HashSet<H256> =
+            blockchain.all_blocks_in_longest_chain().into_iter().collect();
+        let stale_blocks = self.settled_stale.load(Ordering::Relaxed)
+            + self
+                .mined
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|h| !longest_chain.contains(h))
+                .count() as u64;
+        let template = *self.template.lock().unwrap();
+        Status {
+            state: self.state.lock().unwrap().clone(),
+            threads,
+            template_parent: template.map(|(parent, _)| parent.to_string()),
+            template_transactions: template.map_or(0, |(_, n)| n),
+            hashes: self.hashes.load(Ordering::Relaxed),
+            hash_rate: self.hash_rate(),
+            blocks_found: self.blocks_found.load(Ordering::Relaxed),
+            stale_blocks,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::block::generate_random_block;
+    use crate::types::hash::Hashable;
+
+    #[test]
+    fn stale_after_reorg() {
+        let mut blockchain = Blockchain::new();
+        let genesis = blockchain.tip();
+        let stats = Stats::new("Run(0)".to_string());
+        let mined = generate_random_block(&genesis);
+        blockchain.insert(&mined).unwrap();
+        stats.block_found(mined.hash(), &blockchain);
+        assert_eq!(stats.report(&blockchain, 1).stale_blocks, 0);
+
+        // a longer fork leaves our block behind
+        let fork = generate_random_block(&genesis);
+        blockchain.insert(&fork).unwrap();
+        blockchain.insert(&generate_random_block(&fork.hash())).unwrap();
+        let status = stats.report(&blockchain, 1);
+        assert_eq!(status.blocks_found, 1);
+        assert_eq!(status.stale_blocks, 1);
+    }
+}
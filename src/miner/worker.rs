@@ -1,8 +1,9 @@
-use crate::blockchain::Blockchain;
+use crate::blockchain::{BlockError, Blockchain};
 use crate::network::message;
 use crate::network::server::Handle as ServerHandle;
 use crate::types::block::Block;
 use crate::types::hash::Hashable;
+use crate::types::transaction::Mempool;
 use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
 use log::{debug, info};
 use std::sync::{Arc, Mutex};
@@ -13,6 +14,7 @@ pub struct Worker {
     server: ServerHandle,
     finished_block_chan: Receiver<Block>,
     blockchain: Arc<Mutex<Blockchain>>,
+    mempool: Arc<Mutex<Mempool>>,
 }
 
 impl Worker {
@@ -20,11 +22,13 @@ impl Worker {
         server: &ServerHandle,
         finished_block_chan: Receiver<Block>,
         blockchain: &Arc<Mutex<Blockchain>>,
+        mempool: &Arc<Mutex<Mempool>>,
     ) -> Self {
         Self {
             server: server.clone(),
             finished_block_chan,
             blockchain: Arc::clone(blockchain),
+            mempool: Arc::clone(mempool),
         }
     }
 
@@ -44,13 +48,29 @@ impl Worker {
                 .finished_block_chan
                 .recv()
                 .expect("Receive finished block error");
-            // TODO for student: insert this finished block to blockchain, and broadcast this block hash
-            let mut chain_unwrapped = self.blockchain.lock().unwrap();
-            chain_unwrapped.insert(&_block);
-            let mut block_hashes = Vec::new();
-            block_hashes.push(_block.hash());
-            self.server
-                .broadcast(message::Message::NewBlockHashes(block_hashes));
+            // the miner inserted the block already
+            self.broadcast(&_block);
         }
     }
+
+    /// Validate and insert a solved block, whether mined here or by an external miner, and
+    /// broadcast its hash.
+    pub fn submit(&self, block: &Block) -> Result<(), BlockError> {
+        let mut chain_unwrapped = self.blockchain.lock().unwrap();
+        chain_unwrapped.insert(block)?;
+        self.mempool
+            .lock()
+            .unwrap()
+            .prune(chain_unwrapped.tip_state());
+        drop(chain_unwrapped);
+        self.broadcast(block);
+        Ok(())
+    }
+
+    fn broadcast(&self, block: &Block) {
+        let mut block_hashes = Vec::new();
+        block_hashes.push(block.hash());
+        self.server
+            .broadcast(message::Message::NewBlockHashes(block_hashes));
+    }
 }
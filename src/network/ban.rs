@@ -0,0 +1,195 @@
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+/// Kinds of peer misbehavior the node keeps score of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Misbehavior {
+    /// A frame that could not be decoded into a message.
+    MalformedMessage,
+    /// A message that breaks the gossip protocol, e.g. an oversized request.
+    ProtocolViolation,
+    /// A transaction with a bad signature.
+    InvalidTransaction,
+    /// A block that is invalid whatever our clock or chain, e.g. one failing PoW or difficulty
+    /// checks.
+    InvalidBlock,
+}
+
+impl Misbehavior {
+    /// How much this misbehavior adds to the peer's score.
+    pub fn score(&self) -> u32 {
+        match self {
+            Misbehavior::MalformedMessage => 20,
+            Misbehavior::ProtocolViolation => 20,
+            Misbehavior::InvalidTransaction => 50,
+            Misbehavior::InvalidBlock => 50,
+        }
+    }
+}
+
+impl std::fmt::Display for Misbehavior {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let s = match self {
+            Misbehavior::MalformedMessage => "malformed message",
+            Misbehavior::ProtocolViolation => "protocol violation",
+            Misbehavior::InvalidTransaction => "invalid transaction",
+            Misbehavior::InvalidBlock => "invalid block",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// A ban on an IP address, as reported by the API.
+#[derive(Serialize, Debug, Clone)]
+pub struct Ban {
+    pub ip: IpAddr,
+    pub reason: String,
+    pub remaining_secs: u64,
+}
+
+/// Misbehavior scores of connected peers and the addresses currently banned.
+pub struct BanList {
+    threshold: u32,
+    duration: Duration,
+    scores: HashMap<SocketAddr, u32>,
+    /// Bans cover a whole IP, since a peer dials from a new port every time it reconnects
+    bans: HashMap<IpAddr, (Instant, Misbehavior)>,
+    /// IPs that are never banned, such as the loopback address shared by the nodes of a local
+    /// test network
+    allowed: HashSet<IpAddr>,
+}
+
+impl BanList {
+    /// Create a ban list that bans a peer for `duration` once its score reaches `threshold`.
+    pub fn new(threshold: u32, duration: Duration) -> Self {
+        Self {
+            threshold,
+            duration,
+            scores: HashMap::new(),
+            bans: HashMap::new(),
+            allowed: HashSet::new(),
+        }
+    }
+
+    /// Never ban the peers from an IP. Their scores are still kept.
+    pub fn allow(&mut self, ip: IpAddr) {
+        self.allowed.insert(ip);
+    }
+
+    /// Raise the score of a peer. Returns true if the peer crossed the threshold and is now
+    /// banned, which never happens to the peers of allowed IPs.
+    pub fn misbehave(&mut self, addr: SocketAddr, misbehavior: Misbehavior) -> bool {
+        let score = self.scores.entry(addr).or_insert(0);
+        *score = score.saturating_add(misbehavior.score());
+        if *score < self.threshold || self.allowed.contains(&addr.ip()) {
+            return false;
+        }
+        self.scores.remove(&addr);
+        self.bans
+            .insert(addr.ip(), (Instant::now() + self.duration, misbehavior));
+        true
+    }
+
+    /// Current score of a peer.
+    pub fn score(&self, addr: &SocketAddr) -> u32 {
+        self.scores.get(addr).copied().unwrap_or(0)
+    }
+
+    /// Forget the score of a disconnected peer.
+    pub fn forget(&mut self, addr: &SocketAddr) {
+        self.scores.remove(addr);
+    }
+
+    /// Check whether a peer is banned, dropping the ban if it has expired.
+    pub fn is_banned(&mut self, addr: &SocketAddr) -> bool {
+        let ip = addr.ip();
+        match self.bans.get(&ip) {
+            Some((until, _)) if *until > Instant::now() => true,
+            Some(_) => {
+                self.bans.remove(&ip);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// List all bans that have not expired yet.
+    pub fn bans(&mut self) -> Vec<Ban> {
+        let now = Instant::now();
+        self.bans.retain(|_, (until, _)| *until > now);
+        self.bans
+            .iter()
+            .map(|(ip, (until, reason))| Ban {
+                ip: *ip,
+                reason: reason.to_string(),
+                remaining_secs: (*until - now).as_secs(),
+            })
+            .collect()
+    }
+
+    /// Lift the bans on one IP, or all bans. Returns the number of bans lifted.
+    pub fn clear(&mut self, ip: Option<IpAddr>) -> usize {
+        let n = self.bans.len();
+        match ip {
+            Some(ip) => self.bans.retain(|banned, _| *banned != ip),
+            None => self.bans.clear(),
+        }
+        n - self.bans.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::new("10.0.0.1".parse().unwrap(), port)
+    }
+
+    #[test]
+    fn ban_after_threshold() {
+        let mut list = BanList::new(100, Duration::from_secs(60));
+        for _ in 0..4 {
+            assert!(!list.misbehave(addr(1), Misbehavior::MalformedMessage));
+        }
+        assert_eq!(list.score(&addr(1)), 80);
+        assert!(list.misbehave(addr(1), Misbehavior::MalformedMessage));
+        // the ban is on the IP, so other ports of the same host are banned too
+        assert!(list.is_banned(&addr(2)));
+        assert_eq!(list.bans().len(), 1);
+        assert_eq!(list.clear(None), 1);
+        assert!(!list.is_banned(&addr(1)));
+    }
+
+    #[test]
+    fn ban_loopback_address() {
+        let mut list = BanList::new(100, Duration::from_secs(60));
+        let local = |port| SocketAddr::new("127.0.0.1".parse().unwrap(), port);
+        assert!(!list.misbehave(local(50001), Misbehavior::InvalidBlock));
+        assert!(list.misbehave(local(50001), Misbehavior::InvalidBlock));
+        // the peer reconnects from another ephemeral port, and is still banned
+        assert!(list.is_banned(&local(50002)));
+        assert_eq!(list.bans()[0].ip, local(50001).ip());
+        assert_eq!(list.clear(Some(local(50001).ip())), 1);
+        assert!(!list.is_banned(&local(50002)));
+
+        // unless the IP is allowed, as on a local test network
+        list.allow(local(0).ip());
+        for _ in 0..4 {
+            assert!(!list.misbehave(local(50003), Misbehavior::InvalidBlock));
+        }
+        assert_eq!(list.score(&local(50003)), 200);
+        assert!(!list.is_banned(&local(50003)));
+        assert!(list.bans().is_empty());
+    }
+
+    #[test]
+    fn ban_expires() {
+        let mut list = BanList::new(50, Duration::from_millis(0));
+        assert!(list.misbehave(addr(1), Misbehavior::InvalidBlock));
+        assert!(!list.is_banned(&addr(1)));
+        assert!(list.bans().is_empty());
+    }
+}
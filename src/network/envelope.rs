@@ -0,0 +1,258 @@
+//! The envelope every message travels in on the wire.
+//!
+//! ```text
+//! | magic (4) | version (1) | kind (1) | flags (1) | length (4) | checksum (4) | payload |
+//! ```
+//!
+//! Integers are big endian. The payload is the bincode encoding of a `Message`, deflated if the
+//! `FLAG_COMPRESSED` bit is set, and the checksum is the first 4 bytes of the SHA256 of the
+//! payload as sent.
+
+use super::message::{Message, MAX_MESSAGE_SIZE};
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use ring::digest;
+use std::convert::TryInto;
+use std::io::{Read, Write};
+
+/// Magic value of the default network.
+pub const DEFAULT_MAGIC: u32 = 0x0470_fa22;
+/// Magic value of regtest networks, unless set otherwise.
+pub const REGTEST_MAGIC: u32 = 0x0470_7e57;
+/// Version of the envelope format.
+pub const VERSION: u8 = 1;
+/// Size of the envelope header in bytes.
+pub const HEADER_SIZE: usize = 15;
+/// Set in `flags` when the payload is deflated.
+pub const FLAG_COMPRESSED: u8 = 0x01;
+/// `Blocks` payloads larger than this are compressed.
+const COMPRESSION_THRESHOLD: usize = 16 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub magic: u32,
+    pub version: u8,
+    pub kind: u8,
+    pub flags: u8,
+    pub length: u32,
+    pub checksum: [u8; 4],
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// The peer is on another network.
+    WrongMagic(u32),
+    UnsupportedVersion(u8),
+    /// The payload is larger than `MAX_MESSAGE_SIZE`.
+    Oversized(u32),
+    /// The frame is shorter than its header says.
+    Truncated,
+    /// The payload does not match the checksum in the header.
+    BadChecksum,
+    /// The compressed payload could not be inflated.
+    Decompress(std::io::Error),
+    /// The message is not of the type in the header.
+    KindMismatch(u8),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::WrongMagic(m) => write!(f, "wrong network magic {:08x}", m),
+            Error::UnsupportedVersion(v) => write!(f, "unsupported envelope version {}", v),
+            Error::Oversized(n) => write!(f, "payload of {} bytes is too large", n),
+            Error::Truncated => write!(f, "truncated frame"),
+            Error::BadChecksum => write!(f, "payload checksum mismatch"),
+            Error::Decompress(e) => write!(f, "error inflating payload: {}", e),
+            Error::KindMismatch(k) => write!(f, "message is not of type {}", k),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl Header {
+    pub fn to_bytes(&self) -> [u8; HEADER_SIZE] {
+        let mut bytes = [0u8; HEADER_SIZE];
+        bytes[0..4].copy_from_slice(&self.magic.to_be_bytes());
+        bytes[4] = self.version;
+        bytes[5] = self.kind;
+        bytes[6] = self.flags;
+        bytes[7..11].copy_from_slice(&self.length.to_be_bytes());
+        bytes[11..15].copy_from_slice(&self.checksum);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8; HEADER_SIZE]) -> Self {
+        Header {
+            magic: u32::from_be_bytes(bytes[0..4].try_into().unwrap()),
+            version: bytes[4],
+            kind: bytes[5],
+            flags: bytes[6],
+            length: u32::from_be_bytes(bytes[7..11].try_into().unwrap()),
+            checksum: bytes[11..15].try_into().unwrap(),
+        }
+    }
+
+    /// Check the header of a frame before reading its payload.
+    pub fn validate(&self, magic: u32) -> Result<(), Error> {
+        if self.magic != magic {
+            return Err(Error::WrongMagic(self.magic));
+        }
+        if self.version != VERSION {
+            return Err(Error::UnsupportedVersion(self.version));
+        }
+        if self.length > MAX_MESSAGE_SIZE {
+            return Err(Error::Oversized(self.length));
+        }
+        Ok(())
+    }
+}
+
+fn checksum(payload: &[u8]) -> [u8; 4] {
+    digest::digest(&digest::SHA256, payload).as_ref()[0..4]
+        .try_into()
+        .unwrap()
+}
+
+/// The type of a bincode encoded message, which starts with the index of its variant as a
+/// little-endian `u32`.
+fn payload_kind(payload: &[u8]) -> Option<u32> {
+    payload.get(0..4).map(|tag| u32::from_le_bytes(tag.try_into().unwrap()))
+}
+
+/// Wrap a message into a frame ready to be written to a peer. Messages larger than
+/// `MAX_MESSAGE_SIZE` are refused, since peers would drop them.
+pub fn seal(magic: u32, msg: &Message) -> Result<Vec<u8>, Error> {
+    let mut payload = bincode::serialize(msg).unwrap();
+    if payload.len() > MAX_MESSAGE_SIZE as usize {
+        return Err(Error::Oversized(payload.len() as u32));
+    }
+    let mut flags = 0;
+    if let Message::Blocks(_) = msg {
+        if payload.len() > COMPRESSION_THRESHOLD {
+            let mut encoder = DeflateEncoder::new(Vec::new(), Compression::fast());
+            encoder.write_all(&payload).unwrap();
+            payload = encoder.finish().unwrap();
+            flags |= FLAG_COMPRESSED;
+        }
+    }
+    let header = Header {
+        magic,
+        version: VERSION,
+        kind: msg.kind(),
+        flags,
+        length: payload.len() as u32,
+        checksum: checksum(&payload),
+    };
+    let mut frame = Vec::with_capacity(HEADER_SIZE + payload.len());
+    frame.extend_from_slice(&header.to_bytes());
+    frame.extend_from_slice(&payload);
+    Ok(frame)
+}
+
+/// Verify the payload of a frame against its header, and return the bincode encoded message.
+pub fn open(header: &Header, payload: Vec<u8>) -> Result<Vec<u8>, Error> {
+    if checksum(&payload) != header.checksum {
+        return Err(Error::BadChecksum);
+    }
+    let payload = inflate(header, payload)?;
+    if payload_kind(&payload) != Some(header.kind as u32) {
+        return Err(Error::KindMismatch(header.kind));
+    }
+    Ok(payload)
+}
+
+fn inflate(header: &Header, payload: Vec<u8>) -> Result<Vec<u8>, Error> {
+    if header.flags & FLAG_COMPRESSED == 0 {
+        return Ok(payload);
+    }
+    // inflate at most one byte past the limit, so that a small frame cannot expand without bound
+    let mut inflated = Vec::new();
+    DeflateDecoder::new(&payload[..])
+        .take(MAX_MESSAGE_SIZE as u64 + 1)
+        .read_to_end(&mut inflated)
+        .map_err(Error::Decompress)?;
+    if inflated.len() > MAX_MESSAGE_SIZE as usize {
+        return Err(Error::Oversized(inflated.len() as u32));
+    }
+    Ok(inflated)
+}
+
+/// Split a complete frame into its header and the bincode encoded message.
+pub fn open_frame(magic: u32, frame: &[u8]) -> Result<(Header, Vec<u8>), Error> {
+    if frame.len() < HEADER_SIZE {
+        return Err(Error::Truncated);
+    }
+    let header = Header::from_bytes(frame[0..HEADER_SIZE].try_into().unwrap());
+    header.validate(magic)?;
+    if frame.len() - HEADER_SIZE != header.length as usize {
+        return Err(Error::Truncated);
+    }
+    let payload = open(&header, frame[HEADER_SIZE..].to_vec())?;
+    Ok((header, payload))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::block::generate_random_block;
+    use crate::types::transaction::SignedTransaction;
+
+    #[test]
+    fn seal_and_open() {
+        let msg = Message::Ping(String::from("hello"));
+        let frame = seal(DEFAULT_MAGIC, &msg).unwrap();
+        let (header, payload) = open_frame(DEFAULT_MAGIC, &frame).unwrap();
+        assert_eq!(header.kind, msg.kind());
+        assert_eq!(header.flags, 0);
+        match Message::decode(&payload).unwrap() {
+            Message::Ping(s) => assert_eq!(s, "hello"),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn compress_large_blocks() {
+        let mut block = generate_random_block(&[0u8; 32].into());
+        block.data = vec![SignedTransaction::default(); 2000];
+        let frame = seal(DEFAULT_MAGIC, &Message::Blocks(vec![block])).unwrap();
+        let (header, payload) = open_frame(DEFAULT_MAGIC, &frame).unwrap();
+        assert_eq!(header.flags & FLAG_COMPRESSED, FLAG_COMPRESSED);
+        assert!(frame.len() < payload.len());
+        match Message::decode(&payload).unwrap() {
+            Message::Blocks(v) => assert_eq!(v[0].data.len(), 2000),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn reject_bad_frames() {
+        let frame = seal(DEFAULT_MAGIC, &Message::Ping(String::from("hello"))).unwrap();
+        match open_frame(DEFAULT_MAGIC + 1, &frame) {
+            Err(Error::WrongMagic(m)) => assert_eq!(m, DEFAULT_MAGIC),
+            _ => panic!(),
+        }
+        let mut corrupt = frame.clone();
+        *corrupt.last_mut().unwrap() ^= 1;
+        match open_frame(DEFAULT_MAGIC, &corrupt) {
+            Err(Error::BadChecksum) => {}
+            _ => panic!(),
+        }
+
+        // a Ping claiming to be a Blocks message
+        let mut mislabeled = frame.clone();
+        mislabeled[5] = Message::Blocks(Vec::new()).kind();
+        match open_frame(DEFAULT_MAGIC, &mislabeled) {
+            Err(Error::KindMismatch(k)) => assert_eq!(k, mislabeled[5]),
+            _ => panic!(),
+        }
+
+        let huge = Message::Ping("a".repeat(MAX_MESSAGE_SIZE as usize));
+        match seal(DEFAULT_MAGIC, &huge) {
+            Err(Error::Oversized(_)) => {}
+            _ => panic!(),
+        }
+    }
+}
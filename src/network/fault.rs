@@ -0,0 +1,143 @@
+//! Faults injected into the traffic with chosen peers, so that forking under network delay and
+//! partitions can be studied without changing code.
+
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Longest latency that can be injected, an hour, far beyond any timeout of the node.
+pub const MAX_LATENCY_MS: u64 = 3_600_000;
+
+/// What happens to the messages to and from a peer.
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct Fault {
+    /// Delay of every message, in milliseconds.
+    pub latency_ms: u64,
+    /// Share of the messages that are lost, in percent.
+    pub drop_percent: f64,
+    /// Every message is lost, the connection stays up so that the partition can be healed.
+    pub partitioned: bool,
+}
+
+impl Fault {
+    fn drops(&self) -> bool {
+        self.partitioned || rand::random::<f64>() * 100.0 < self.drop_percent
+    }
+}
+
+/// A peer and its faults, as listed by `/network/peers`.
+#[derive(Serialize, Debug, Clone)]
+pub struct PeerFault {
+    pub addr: String,
+    #[serde(flatten)]
+    pub fault: Fault,
+}
+
+/// The faults of every peer. Peers without faults of their own get the default ones.
+#[derive(Clone, Default)]
+pub struct Faults {
+    rules: Arc<Mutex<Rules>>,
+}
+
+#[derive(Default)]
+struct Rules {
+    default: Fault,
+    peers: HashMap<SocketAddr, Fault>,
+}
+
+impl Faults {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, addr: &SocketAddr) -> Fault {
+        let rules = self.rules.lock().unwrap();
+        rules.peers.get(addr).copied().unwrap_or(rules.default)
+    }
+
+    /// When a message sent to or received from a peer now gets through, or `None` if it is lost,
+    /// or would arrive later than the clock can tell.
+    pub fn deliver_at(&self, addr: &SocketAddr) -> Option<Instant> {
+        let fault = self.get(addr);
+        if fault.drops() {
+            return None;
+        }
+        Instant::now().checked_add(Duration::from_millis(fault.latency_ms))
+    }
+
+    /// Change the latency and the drop rate of one peer, starting from the default faults if it
+    /// has none of its own, or the default faults if `peer` is `None`. Values left out are kept,
+    /// and the latency is capped at `MAX_LATENCY_MS`.
+    pub fn set(&self, peer: Option<SocketAddr>, latency_ms: Option<u64>, drop_percent: Option<f64>) {
+        let mut rules = self.rules.lock().unwrap();
+        let default = rules.default;
+        let fault = match peer {
+            Some(addr) => rules.peers.entry(addr).or_insert(default),
+            None => &mut rules.default,
+        };
+        if let Some(latency_ms) = latency_ms {
+            fault.latency_ms = latency_ms.min(MAX_LATENCY_MS);
+        }
+        if let Some(drop_percent) = drop_percent {
+            fault.drop_percent = drop_percent;
+        }
+    }
+
+    /// Cut the traffic with some peers, on top of their other faults.
+    pub fn partition(&self, peers: &[SocketAddr]) {
+        let mut rules = self.rules.lock().unwrap();
+        let default = rules.default;
+        for addr in peers {
+            rules.peers.entry(*addr).or_insert(default).partitioned = true;
+        }
+    }
+
+    /// Remove every fault.
+    pub fn heal(&self) {
+        *self.rules.lock().unwrap() = Rules::default();
+    }
+
+    /// The faults of some peers.
+    pub fn list(&self, peers: &[SocketAddr]) -> Vec<PeerFault> {
+        peers
+            .iter()
+            .map(|addr| PeerFault {
+                addr: addr.to_string(),
+                fault: self.get(addr),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_partition_and_heal() {
+        let faults = Faults::new();
+        let a: SocketAddr = "127.0.0.1:6000".parse().unwrap();
+        let b: SocketAddr = "127.0.0.1:6001".parse().unwrap();
+        assert!(faults.deliver_at(&a).unwrap() <= Instant::now());
+
+        faults.set(None, Some(100), None);
+        faults.set(Some(a), None, Some(100.0));
+        assert_eq!(faults.get(&a).latency_ms, 100);
+        assert!(faults.deliver_at(&a).is_none());
+        assert!(faults.deliver_at(&b).unwrap() > Instant::now() + Duration::from_millis(50));
+
+        faults.partition(&[b]);
+        assert!(faults.get(&b).partitioned);
+        assert!(faults.deliver_at(&b).is_none());
+        assert_eq!(faults.list(&[a, b]).len(), 2);
+
+        faults.set(Some(b), Some(u64::MAX), None);
+        assert_eq!(faults.get(&b).latency_ms, MAX_LATENCY_MS);
+
+        faults.heal();
+        assert_eq!(faults.get(&a), Fault::default());
+        assert_eq!(faults.get(&b), Fault::default());
+    }
+}
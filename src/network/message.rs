@@ -2,6 +2,22 @@ use serde::{Serialize, Deserialize};
 
 use crate::types::{hash::H256, block::Block, transaction::SignedTransaction};
 
+/// Largest frame a peer may send us, in bytes. Frames above this are rejected before their
+/// payload is read.
+pub const MAX_MESSAGE_SIZE: u32 = 32 * 1024 * 1024;
+
+/// Names of the message types, indexed by the type carried in the wire envelope.
+const KIND_NAMES: [&str; 8] = [
+    "Ping",
+    "Pong",
+    "NewBlockHashes",
+    "GetBlocks",
+    "Blocks",
+    "NewTransactionHashes",
+    "GetTransactions",
+    "Transactions",
+];
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Message {
     Ping(String),
@@ -13,3 +29,95 @@ pub enum Message {
     GetTransactions(Vec<H256>),
     Transactions(Vec<SignedTransaction>),
 }
+
+impl Message {
+    /// Decode a message received from a peer. Never allocates more than `MAX_MESSAGE_SIZE`
+    /// bytes, whatever length prefixes the payload claims.
+    pub fn decode(bytes: &[u8]) -> Result<Message, bincode::Error> {
+        bincode::config()
+            .limit(MAX_MESSAGE_SIZE as u64)
+            .deserialize(bytes)
+    }
+
+    /// Type of the message, as carried in the wire envelope.
+    pub fn kind(&self) -> u8 {
+        match self {
+            Message::Ping(_) => 0,
+            Message::Pong(_) => 1,
+            Message::NewBlockHashes(_) => 2,
+            Message::GetBlocks(_) => 3,
+            Message::Blocks(_) => 4,
+            Message::NewTransactionHashes(_) => 5,
+            Message::GetTransactions(_) => 6,
+            Message::Transactions(_) => 7,
+        }
+    }
+
+    /// Number of hashes or items carried by an inventory message, zero for other messages.
+    pub fn inventory_size(&self) -> usize {
+        match self {
+            Message::NewBlockHashes(v) | Message::GetBlocks(v) => v.len(),
+            Message::NewTransactionHashes(v) | Message::GetTransactions(v) => v.len(),
+            Message::Blocks(v) => v.len(),
+            Message::Transactions(v) => v.len(),
+            Message::Ping(_) | Message::Pong(_) => 0,
+        }
+    }
+}
+
+/// Name of a message type carried in a wire envelope.
+pub fn kind_name(kind: u8) -> &'static str {
+    KIND_NAMES.get(kind as usize).copied().unwrap_or("Unknown")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_roundtrip() {
+        let msg = Message::GetBlocks(vec![[1u8; 32].into()]);
+        let bytes = bincode::serialize(&msg).unwrap();
+        match Message::decode(&bytes).unwrap() {
+            Message::GetBlocks(v) => assert_eq!(v, vec![[1u8; 32].into()]),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn kind_names() {
+        let hashes = vec![H256::default()];
+        let messages = vec![
+            Message::Ping(String::new()),
+            Message::Pong(String::new()),
+            Message::NewBlockHashes(hashes.clone()),
+            Message::GetBlocks(hashes.clone()),
+            Message::Blocks(Vec::new()),
+            Message::NewTransactionHashes(hashes.clone()),
+            Message::GetTransactions(hashes),
+            Message::Transactions(Vec::new()),
+        ];
+        assert_eq!(messages.len(), KIND_NAMES.len());
+        for msg in messages {
+            // the name is the one of the variant, and bincode numbers the variants like the kinds
+            let debug = format!("{:?}", msg);
+            assert_eq!(debug.split('(').next().unwrap(), kind_name(msg.kind()));
+            let bytes = bincode::serialize(&msg).unwrap();
+            assert_eq!(bytes[0], msg.kind());
+            assert_eq!(Message::decode(&bytes).unwrap().kind(), msg.kind());
+        }
+        assert_eq!(kind_name(KIND_NAMES.len() as u8), "Unknown");
+    }
+
+    #[test]
+    fn decode_garbage() {
+        // unknown variant
+        assert!(Message::decode(&[200, 0, 0, 0]).is_err());
+        // truncated payload
+        assert!(Message::decode(&[2, 0, 0, 0, 5, 0, 0, 0, 0, 0, 0, 0]).is_err());
+        // a vector claiming more elements than could ever fit in a frame
+        let mut bytes = vec![4, 0, 0, 0];
+        bytes.extend_from_slice(&u64::MAX.to_le_bytes());
+        assert!(Message::decode(&bytes).is_err());
+    }
+}
@@ -1,4 +1,10 @@
+pub mod ban;
+pub mod envelope;
+pub mod fault;
 pub mod message;
 pub mod peer;
+pub mod secure;
 pub mod server;
+pub mod stats;
+pub mod transport;
 pub mod worker;
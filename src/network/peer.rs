@@ -1,18 +1,55 @@
-use super::message::Message;
+use super::envelope;
+use super::message::{self, Message};
+use crate::types::hash::H256;
 use futures::{channel::mpsc, sink::SinkExt};
-use log::trace;
-use smol::Async;
+use log::{trace, warn};
+use std::collections::{HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
 
+/// How many block and transaction hashes we remember per peer.
+const KNOWN_INVENTORY_CAPACITY: usize = 50_000;
+
+/// Create the handle of the peer at `addr`, and the queue of the frames written to it.
 pub fn new(
-    stream: &Async<std::net::TcpStream>,
-) -> std::io::Result<(mpsc::UnboundedReceiver<Vec<u8>>, Handle)> {
+    addr: std::net::SocketAddr,
+    magic: u32,
+) -> (mpsc::UnboundedReceiver<Vec<u8>>, Handle) {
     let (write_sender, write_receiver) = mpsc::unbounded();
-    let addr = stream.get_ref().peer_addr()?;
     let handle = Handle {
         write_queue: write_sender,
         addr,
+        magic,
+        known_inventory: Arc::new(Mutex::new(KnownInventory::new())),
     };
-    Ok((write_receiver, handle))
+    (write_receiver, handle)
+}
+
+/// Hashes of blocks and transactions a peer is known to have, because it sent or announced them
+/// to us, or because we sent or announced them to it. The oldest entries are forgotten first.
+#[derive(Debug, Default)]
+struct KnownInventory {
+    set: HashSet<H256>,
+    order: VecDeque<H256>,
+}
+
+impl KnownInventory {
+    fn new() -> Self {
+        Self::default()
+    }
+
+    /// Remember a hash, returning false if it was already known.
+    fn insert(&mut self, hash: H256) -> bool {
+        if !self.set.insert(hash) {
+            return false;
+        }
+        self.order.push_back(hash);
+        if self.order.len() > KNOWN_INVENTORY_CAPACITY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.set.remove(&oldest);
+            }
+        }
+        true
+    }
 }
 
 #[derive(Copy, Clone)]
@@ -24,7 +61,9 @@ pub enum Direction {
 #[derive(Clone, Debug)]
 pub struct Handle {
     addr: std::net::SocketAddr,
+    magic: u32,
     write_queue: mpsc::UnboundedSender<Vec<u8>>,
+    known_inventory: Arc<Mutex<KnownInventory>>,
 }
 
 #[cfg(any(test,test_utilities))]
@@ -34,7 +73,13 @@ pub struct TestReceiver {
 
 impl Handle {
     pub fn write(&mut self, msg: Message) {
-        let buffer = bincode::serialize(&msg).unwrap();
+        let buffer = match envelope::seal(self.magic, &msg) {
+            Ok(buffer) => buffer,
+            Err(e) => {
+                warn!(peer:% = self.addr; "Cannot send {} message to peer {}: {}", message::kind_name(msg.kind()), self.addr, e);
+                return;
+            }
+        };
         smol::block_on(async move {
             if self.write_queue.send(buffer).await.is_err() {
                 trace!("Trying to send to disconnected peer");
@@ -42,6 +87,48 @@ impl Handle {
         });
     }
 
+    /// Announce new blocks or transactions to this peer, leaving out the hashes it already
+    /// knows. Nothing is sent if the peer knows all of them. Other messages are written as is.
+    pub fn announce(&mut self, msg: Message) {
+        let msg = match msg {
+            Message::NewBlockHashes(hashes) => {
+                let hashes = self.unknown(hashes);
+                if hashes.is_empty() {
+                    return;
+                }
+                Message::NewBlockHashes(hashes)
+            }
+            Message::NewTransactionHashes(hashes) => {
+                let hashes = self.unknown(hashes);
+                if hashes.is_empty() {
+                    return;
+                }
+                Message::NewTransactionHashes(hashes)
+            }
+            msg => msg,
+        };
+        self.write(msg);
+    }
+
+    /// Record that this peer has the given blocks or transactions.
+    pub fn mark_known<I: IntoIterator<Item = H256>>(&self, hashes: I) {
+        let mut known = self.known_inventory.lock().unwrap();
+        for hash in hashes {
+            known.insert(hash);
+        }
+    }
+
+    /// Keep the hashes this peer does not know yet, and mark them as known.
+    fn unknown(&self, hashes: Vec<H256>) -> Vec<H256> {
+        let mut known = self.known_inventory.lock().unwrap();
+        hashes.into_iter().filter(|h| known.insert(*h)).collect()
+    }
+
+    /// Close the write queue of this peer, which disconnects it.
+    pub fn disconnect(&mut self) {
+        self.write_queue.close_channel();
+    }
+
     pub fn addr(&self) -> &std::net::SocketAddr {
         &self.addr
     }
@@ -51,7 +138,9 @@ impl Handle {
         let (s,r) = mpsc::unbounded();
         (Handle {
             addr: std::net::SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::new(127, 0, 0, 1)), 12321),
+            magic: envelope::DEFAULT_MAGIC,
             write_queue: s,
+            known_inventory: Arc::new(Mutex::new(KnownInventory::new())),
         },
         TestReceiver {
             r
@@ -62,8 +151,32 @@ impl Handle {
 #[cfg(any(test,test_utilities))]
 impl TestReceiver {
     pub fn recv(&mut self) -> Message {
-        let bytes = smol::block_on(futures::stream::StreamExt::next(&mut self.r)).unwrap();
+        let frame = smol::block_on(futures::stream::StreamExt::next(&mut self.r)).unwrap();
+        let (_, bytes) = envelope::open_frame(envelope::DEFAULT_MAGIC, &frame).unwrap();
         let msg: Message = bincode::deserialize(&bytes).unwrap();
         msg
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn announce_skips_known_hashes() {
+        let (mut handle, mut receiver) = Handle::test_handle();
+        let known: H256 = [1u8; 32].into();
+        let new: H256 = [2u8; 32].into();
+        handle.mark_known(vec![known]);
+        handle.announce(Message::NewBlockHashes(vec![known, new]));
+        handle.announce(Message::NewBlockHashes(vec![known, new]));
+        handle.announce(Message::Ping(String::from("done")));
+        match receiver.recv() {
+            Message::NewBlockHashes(v) => assert_eq!(v, vec![new]),
+            _ => panic!(),
+        }
+        match receiver.recv() {
+            Message::Ping(_) => {}
+            _ => panic!(),
+        }
+    }
+}
@@ -0,0 +1,337 @@
+//! Optional encrypted and authenticated transport between peers.
+//!
+//! Right after the TCP connection is set up, both sides send a hello containing their Ed25519
+//! identity key and a fresh X25519 ephemeral key. Then each side signs the whole transcript with
+//! its identity key: the network magic, the identity and ephemeral keys of both sides, and whether
+//! it dialed or accepted the connection, so that nobody in the middle can pass the keys of one
+//! node as their own. The X25519 shared secret is expanded with HKDF-SHA256 into one
+//! ChaCha20-Poly1305 key per direction. From then on, every envelope frame is sent as
+//!
+//! ```text
+//! | length (4) | sealed frame and tag |
+//! ```
+//!
+//! with a per direction counter as nonce.
+
+use super::envelope;
+use super::message::MAX_MESSAGE_SIZE;
+use super::peer::Direction;
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use ring::{aead, agreement, hkdf, rand, signature};
+use std::collections::HashSet;
+use std::convert::TryInto;
+use std::io::{Error, ErrorKind, Write};
+use std::path::Path;
+use std::time::Duration;
+
+const HELLO_CONTEXT: &[u8] = b"bitcoin-p2p-hello";
+const KDF_SALT: &[u8] = b"bitcoin-p2p-keys";
+const KEY_SIZE: usize = 32;
+const SIGNATURE_SIZE: usize = 64;
+const HELLO_SIZE: usize = KEY_SIZE * 2;
+/// Peers that do not complete the handshake within this time are dropped.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Identity of this node, and which peers it is willing to talk to.
+pub struct Config {
+    identity: Ed25519KeyPair,
+    /// If set, only peers with one of these identity keys are accepted.
+    trusted_peers: Option<HashSet<[u8; KEY_SIZE]>>,
+}
+
+impl Config {
+    pub fn new(identity: Ed25519KeyPair, trusted_peers: Option<HashSet<[u8; KEY_SIZE]>>) -> Self {
+        Self {
+            identity,
+            trusted_peers,
+        }
+    }
+
+    /// The public identity key of this node.
+    pub fn public_key(&self) -> &[u8] {
+        self.identity.public_key().as_ref()
+    }
+}
+
+/// Load an Ed25519 identity key from a PKCS#8 file, generating and saving a new one if the file
+/// does not exist. New files are only readable by their owner.
+pub fn load_or_create_identity(path: &Path) -> std::io::Result<Ed25519KeyPair> {
+    let pkcs8 = if path.exists() {
+        std::fs::read(path)?
+    } else {
+        let rng = rand::SystemRandom::new();
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng)
+            .map_err(|_| Error::other("error generating identity key"))?;
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        options.open(path)?.write_all(pkcs8.as_ref())?;
+        pkcs8.as_ref().to_vec()
+    };
+    Ed25519KeyPair::from_pkcs8(&pkcs8)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, format!("bad identity key: {}", e)))
+}
+
+/// Seals outgoing frames.
+pub struct Sealer {
+    key: aead::LessSafeKey,
+    counter: u64,
+}
+
+/// Opens incoming frames.
+pub struct Opener {
+    key: aead::LessSafeKey,
+    counter: u64,
+}
+
+fn nonce(counter: u64) -> aead::Nonce {
+    let mut bytes = [0u8; aead::NONCE_LEN];
+    bytes[4..].copy_from_slice(&counter.to_be_bytes());
+    aead::Nonce::assume_unique_for_key(bytes)
+}
+
+impl Sealer {
+    /// Encrypt a frame and prefix it with its length.
+    pub fn seal(&mut self, frame: Vec<u8>) -> Vec<u8> {
+        let mut data = frame;
+        self.key
+            .seal_in_place_append_tag(nonce(self.counter), aead::Aad::empty(), &mut data)
+            .unwrap();
+        self.counter += 1;
+        let mut sealed = Vec::with_capacity(4 + data.len());
+        sealed.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        sealed.extend_from_slice(&data);
+        sealed
+    }
+}
+
+impl Opener {
+    /// Largest sealed frame a peer may send.
+    pub fn max_sealed_size() -> u32 {
+        MAX_MESSAGE_SIZE + (envelope::HEADER_SIZE + aead::MAX_TAG_LEN) as u32
+    }
+
+    /// Decrypt and authenticate a sealed frame, without its length prefix.
+    pub fn open(&mut self, mut sealed: Vec<u8>) -> std::io::Result<Vec<u8>> {
+        let len = self
+            .key
+            .open_in_place(nonce(self.counter), aead::Aad::empty(), &mut sealed)
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "frame fails authentication"))?
+            .len();
+        self.counter += 1;
+        sealed.truncate(len);
+        Ok(sealed)
+    }
+}
+
+/// What a side signs: the hellos of the initiator and the responder, and the role of the signer.
+fn transcript_message(magic: u32, initiator: &[u8], responder: &[u8], signer: Direction) -> Vec<u8> {
+    let role: &[u8] = match signer {
+        Direction::Outgoing => b"initiator",
+        Direction::Incoming => b"responder",
+    };
+    [HELLO_CONTEXT, &magic.to_be_bytes()[..], initiator, responder, role].concat()
+}
+
+fn derive_key(prk: &hkdf::Prk, label: &[u8], transcript: &[u8]) -> aead::LessSafeKey {
+    let info = [label, transcript];
+    let okm = prk.expand(&info, &aead::CHACHA20_POLY1305).unwrap();
+    aead::LessSafeKey::new(aead::UnboundKey::from(okm))
+}
+
+/// Run the handshake over a fresh connection. Returns the sealer and opener for the rest of the
+/// connection, and the identity key of the peer.
+pub async fn handshake<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    config: &Config,
+    magic: u32,
+    direction: Direction,
+) -> std::io::Result<(Sealer, Opener, [u8; KEY_SIZE])>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let rng = rand::SystemRandom::new();
+    let handshake_error = |msg: &str| Error::other(msg.to_string());
+    let ephemeral = agreement::EphemeralPrivateKey::generate(&agreement::X25519, &rng)
+        .map_err(|_| handshake_error("error generating ephemeral key"))?;
+    let ephemeral_public = ephemeral
+        .compute_public_key()
+        .map_err(|_| handshake_error("error computing ephemeral key"))?;
+
+    // send our hello and read theirs
+    let hello = [config.public_key(), ephemeral_public.as_ref()].concat();
+    writer.write_all(&hello).await?;
+    writer.flush().await?;
+    let mut peer_hello = [0u8; HELLO_SIZE];
+    reader.read_exact(&mut peer_hello).await?;
+    let peer_identity: [u8; KEY_SIZE] = peer_hello[0..KEY_SIZE].try_into().unwrap();
+    let peer_ephemeral = &peer_hello[KEY_SIZE..];
+
+    // sign the transcript, and check the signature of the peer over the same transcript with
+    // its own role
+    let (initiator_hello, responder_hello, peer_direction) = match direction {
+        Direction::Outgoing => (&hello[..], &peer_hello[..], Direction::Incoming),
+        Direction::Incoming => (&peer_hello[..], &hello[..], Direction::Outgoing),
+    };
+    let signature = config.identity.sign(&transcript_message(
+        magic,
+        initiator_hello,
+        responder_hello,
+        direction,
+    ));
+    writer.write_all(signature.as_ref()).await?;
+    writer.flush().await?;
+    let mut peer_signature = [0u8; SIGNATURE_SIZE];
+    reader.read_exact(&mut peer_signature).await?;
+    signature::UnparsedPublicKey::new(&signature::ED25519, &peer_identity)
+        .verify(
+            &transcript_message(magic, initiator_hello, responder_hello, peer_direction),
+            &peer_signature,
+        )
+        .map_err(|_| Error::new(ErrorKind::PermissionDenied, "bad hello signature"))?;
+    if let Some(trusted) = &config.trusted_peers {
+        if !trusted.contains(&peer_identity) {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                format!("untrusted identity {}", hex::encode(peer_identity)),
+            ));
+        }
+    }
+
+    // derive one key per direction, bound to both ephemeral keys
+    let (initiator, responder) = match direction {
+        Direction::Outgoing => (ephemeral_public.as_ref(), peer_ephemeral),
+        Direction::Incoming => (peer_ephemeral, ephemeral_public.as_ref()),
+    };
+    let transcript = [initiator, responder].concat();
+    let peer_public = agreement::UnparsedPublicKey::new(&agreement::X25519, peer_ephemeral);
+    let (outgoing, incoming) = agreement::agree_ephemeral(
+        ephemeral,
+        &peer_public,
+        handshake_error("key agreement failed"),
+        |secret| {
+            let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, KDF_SALT).extract(secret);
+            let to_responder = derive_key(&prk, b"initiator", &transcript);
+            let to_initiator = derive_key(&prk, b"responder", &transcript);
+            Ok(match direction {
+                Direction::Outgoing => (to_responder, to_initiator),
+                Direction::Incoming => (to_initiator, to_responder),
+            })
+        },
+    )?;
+    Ok((
+        Sealer {
+            key: outgoing,
+            counter: 0,
+        },
+        Opener {
+            key: incoming,
+            counter: 0,
+        },
+        peer_identity,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::key_pair;
+    use smol::Async;
+    use std::net::{TcpListener, TcpStream};
+
+    type Halves = (Sealer, Opener, [u8; KEY_SIZE]);
+
+    /// Run both sides of a handshake against each other over a loopback connection.
+    fn connect(
+        client: &Config,
+        server: &Config,
+    ) -> (std::io::Result<Halves>, std::io::Result<Halves>) {
+        smol::block_on(async {
+            let listener = Async::<TcpListener>::bind(([127, 0, 0, 1], 0)).unwrap();
+            let addr = listener.get_ref().local_addr().unwrap();
+            let (outgoing, incoming) = futures::future::join(
+                Async::<TcpStream>::connect(addr),
+                listener.accept(),
+            )
+            .await;
+            let outgoing = outgoing.unwrap();
+            let incoming = incoming.unwrap().0;
+            futures::future::join(
+                handshake(&mut &outgoing, &mut &outgoing, client, 1, Direction::Outgoing),
+                handshake(&mut &incoming, &mut &incoming, server, 1, Direction::Incoming),
+            )
+            .await
+        })
+    }
+
+    #[test]
+    fn handshake_and_exchange() {
+        let client = Config::new(key_pair::random(), None);
+        let server = Config::new(key_pair::random(), None);
+        let (c, s) = connect(&client, &server);
+        let (mut c_sealer, mut c_opener, server_identity) = c.unwrap();
+        let (mut s_sealer, mut s_opener, client_identity) = s.unwrap();
+        assert_eq!(&server_identity[..], server.public_key());
+        assert_eq!(&client_identity[..], client.public_key());
+        for _ in 0..2 {
+            let sealed = c_sealer.seal(b"hello".to_vec());
+            assert_eq!(s_opener.open(sealed[4..].to_vec()).unwrap(), b"hello");
+            let sealed = s_sealer.seal(b"world".to_vec());
+            assert_eq!(c_opener.open(sealed[4..].to_vec()).unwrap(), b"world");
+        }
+        // tampered frames fail authentication
+        let mut sealed = c_sealer.seal(b"hello".to_vec());
+        sealed[5] ^= 1;
+        assert!(s_opener.open(sealed[4..].to_vec()).is_err());
+    }
+
+    #[test]
+    fn reject_untrusted_peer() {
+        let client = Config::new(key_pair::random(), None);
+        let mut trusted = HashSet::new();
+        trusted.insert([0u8; KEY_SIZE]);
+        let server = Config::new(key_pair::random(), Some(trusted));
+        let (c, s) = connect(&client, &server);
+        assert!(c.is_ok());
+        assert_eq!(s.err().unwrap().kind(), ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn signature_covers_the_transcript() {
+        let identity = key_pair::random();
+        let ours = [[1u8; KEY_SIZE], [2u8; KEY_SIZE]].concat();
+        let theirs = [[3u8; KEY_SIZE], [4u8; KEY_SIZE]].concat();
+        let signed = transcript_message(1, &ours, &theirs, Direction::Outgoing);
+        let public_key =
+            signature::UnparsedPublicKey::new(&signature::ED25519, identity.public_key().as_ref());
+        let signature = identity.sign(&signed);
+        assert!(public_key.verify(&signed, signature.as_ref()).is_ok());
+        // the signature cannot be replayed with another peer, or reflected back
+        let other = [[5u8; KEY_SIZE], [4u8; KEY_SIZE]].concat();
+        let replayed = transcript_message(1, &ours, &other, Direction::Outgoing);
+        assert!(public_key.verify(&replayed, signature.as_ref()).is_err());
+        let reflected = transcript_message(1, &ours, &theirs, Direction::Incoming);
+        assert!(public_key.verify(&reflected, signature.as_ref()).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn identity_file_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+        let name = hex::encode(key_pair::random().public_key().as_ref());
+        let path = std::env::temp_dir().join(format!("identity-{}", name));
+        let created = load_or_create_identity(&path).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        let loaded = load_or_create_identity(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(created.public_key().as_ref(), loaded.public_key().as_ref());
+    }
+}
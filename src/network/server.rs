@@ -1,25 +1,38 @@
 use crate::types::address::Address;
+use super::ban::{Ban, BanList, Misbehavior};
+use super::envelope;
+use super::fault::{Faults, PeerFault};
+use super::secure;
 use super::peer;
 use super::message;
+use super::stats::Stats;
+use super::transport::{self, Connection, Listener, ReadError, Transport, Writer};
 
-use async_dup::Arc as AsyncArc;
-use futures::io::{AsyncReadExt, AsyncWriteExt};
 use futures::io::{BufReader, BufWriter};
-use futures::{channel::oneshot, stream::StreamExt};
-use smol::{Async, Executor};
-use log::{debug, info, trace};
-use std::net;
+use futures::{channel::mpsc, channel::oneshot, stream::StreamExt};
+use smol::Executor;
+use log::{debug, info, trace, warn};
+use std::convert::TryInto;
 use std::sync::Arc;
 use std::thread;
+use std::time::Instant;
 
 
 pub fn new(
     addr: std::net::SocketAddr,
     msg_sink: smol::channel::Sender<(Vec<u8>, peer::Handle)>,
+    ban_list: BanList,
+    magic: u32,
+    secure: Option<Arc<secure::Config>>,
+    transport: Arc<dyn Transport>,
 ) -> std::io::Result<(Context, Handle)> {
     let (control_signal_sender, control_signal_receiver) = smol::channel::bounded(10000);
+    let faults = Faults::new();
+    let stats = Arc::new(Stats::new());
     let handle = Handle {
         control_chan: control_signal_sender.clone(),
+        faults: faults.clone(),
+        stats: Arc::clone(&stats),
     };
     let ctx = Context {
         peers: std::collections::HashMap::new(),
@@ -27,6 +40,12 @@ pub fn new(
         control_chan: control_signal_receiver,
         control_sender: control_signal_sender,
         new_msg_chan: msg_sink,
+        ban_list,
+        magic,
+        secure,
+        transport,
+        faults,
+        stats,
     };
     Ok((ctx, handle))
 }
@@ -37,13 +56,19 @@ pub struct Context {
     control_chan: smol::channel::Receiver<ControlSignal>,
     control_sender: smol::channel::Sender<ControlSignal>,
     new_msg_chan: smol::channel::Sender<(Vec<u8>, peer::Handle)>,
+    ban_list: BanList,
+    magic: u32,
+    secure: Option<Arc<secure::Config>>,
+    transport: Arc<dyn Transport>,
+    faults: Faults,
+    stats: Arc<Stats>,
 }
 
 impl Context {
     /// Start a new server context.
     pub fn start(self) -> std::io::Result<()> {
         // initialize the server socket
-        let listener = Async::<net::TcpListener>::bind(self.addr)?;
+        let listener = self.transport.listen(self.addr)?;
         info!("P2P server listening at {}", self.addr);
         let control_chan = self.control_sender.clone();
         let ex = Executor::new();
@@ -63,16 +88,17 @@ impl Context {
 
     /// the loop that endlessly accept incoming peers
     async fn listener_loop(
-        listener: Async<net::TcpListener>,
+        mut listener: Box<dyn Listener>,
         control_chan: smol::channel::Sender<ControlSignal>,
     ) -> std::io::Result<()> {
         loop {
-            let (stream, addr) = listener.accept().await?;
+            let connection = listener.accept().await?;
+            let addr = connection.addr();
             control_chan
-                .send(ControlSignal::GetNewPeer(stream))
+                .send(ControlSignal::GetNewPeer(connection))
                 .await
                 .unwrap();
-            info!("Incoming peer from {}", addr);
+            info!(peer:% = addr; "Incoming peer from {}", addr);
         }
     }
 
@@ -88,17 +114,50 @@ impl Context {
                 ControlSignal::BroadcastMessage(msg) => {
                     trace!("Processing BroadcastMessage command");
                     for (_, hd) in self.peers.iter_mut() {
-                        hd.write(msg.clone());
+                        hd.announce(msg.clone());
                     }
                 }
-                ControlSignal::GetNewPeer(stream) => {
+                ControlSignal::GetNewPeer(connection) => {
                     trace!("Processing GetNewPeer command");
-                    self.accept(stream, ex.clone()).await?;
+                    self.accept(connection, ex.clone()).await;
                 }
                 ControlSignal::DroppedPeer(addr) => {
-                    trace!("Processing DroppedPeer({})", addr);
+                    trace!(peer:% = addr; "Processing DroppedPeer({})", addr);
                     self.peers.remove(&addr);
-                    info!("Peer {} disconnected", addr);
+                    self.ban_list.forget(&addr);
+                    info!(peer:% = addr; "Peer {} disconnected", addr);
+                }
+                ControlSignal::Misbehaving(addr, misbehavior) => {
+                    trace!(peer:% = addr; "Processing Misbehaving({}, {})", addr, misbehavior);
+                    if self.ban_list.misbehave(addr, misbehavior) {
+                        warn!(peer:% = addr; "Banning {} for {}", addr, misbehavior);
+                        // disconnect every peer the ban covers
+                        for (_, hd) in self.peers.iter_mut() {
+                            if self.ban_list.is_banned(hd.addr()) {
+                                hd.disconnect();
+                            }
+                        }
+                    } else {
+                        debug!(
+                            peer:% = addr;
+                            "Peer {} misbehaved ({}), score is now {}",
+                            addr,
+                            misbehavior,
+                            self.ban_list.score(&addr)
+                        );
+                    }
+                }
+                ControlSignal::GetBans(result_chan) => {
+                    trace!("Processing GetBans command");
+                    let _ = result_chan.send(self.ban_list.bans());
+                }
+                ControlSignal::GetPeers(result_chan) => {
+                    trace!("Processing GetPeers command");
+                    let _ = result_chan.send(self.peers.keys().copied().collect());
+                }
+                ControlSignal::ClearBans(ip, result_chan) => {
+                    trace!("Processing ClearBans command");
+                    let _ = result_chan.send(self.ban_list.clear(ip));
                 }
                 ControlSignal::SendToPeer((_receiver, _msg)) => {
                     unimplemented!()
@@ -114,107 +173,203 @@ impl Context {
         addr: &std::net::SocketAddr,
         ex: Arc<Executor<'_>>,
     ) -> std::io::Result<peer::Handle> {
-        debug!("Establishing connection to peer {}", addr);
-        let stream = Async::<std::net::TcpStream>::connect(addr.clone()).await?;
+        if self.ban_list.is_banned(addr) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                format!("peer {} is banned", addr),
+            ));
+        }
+        debug!(peer:% = addr; "Establishing connection to peer {}", addr);
+        let connection = self.transport.dial(*addr).await?;
 
         // register the new peer
-        self.register(stream, peer::Direction::Outgoing, ex).await
+        Ok(self.register(connection, peer::Direction::Outgoing, ex))
     }
 
-    async fn accept(
-        &mut self,
-        stream: Async<net::TcpStream>,
-        ex: Arc<Executor<'_>>,
-    ) -> std::io::Result<()> {
-        self.register(stream, peer::Direction::Incoming, ex).await?;
-        Ok(())
+    async fn accept(&mut self, connection: Connection, ex: Arc<Executor<'_>>) {
+        let addr = connection.addr();
+        if self.ban_list.is_banned(&addr) {
+            info!(peer:% = addr; "Refusing incoming peer {}, it is banned", addr);
+            connection.close();
+            return;
+        }
+        self.register(connection, peer::Direction::Incoming, ex);
     }
 
-    async fn register(
+    fn register(
         &mut self,
-        stream: Async<net::TcpStream>,
-        _direction: peer::Direction,
+        connection: Connection,
+        direction: peer::Direction,
         ex: Arc<Executor<'_>>,
-    ) -> std::io::Result<peer::Handle> {
-        let (mut write_queue, handle) = peer::new(&stream)?;
+    ) -> peer::Handle {
+        let addr = connection.addr();
+        let (mut write_queue, handle) = peer::new(addr, self.magic);
+        let magic = self.magic;
 
-        let stream = AsyncArc::new(stream);
+        let (reader, writer, closer) = connection.split();
         let new_msg_chan = self.new_msg_chan.clone();
-        let handle_copy = handle.clone();
+        let mut handle_copy = handle.clone();
         let control_chan = self.control_sender.clone();
-        let addr = stream.get_ref().peer_addr()?;
+        let reader_control_chan = self.control_sender.clone();
+        let reader_faults = self.faults.clone();
+        let writer_faults = self.faults.clone();
+        let reader_stats = Arc::clone(&self.stats);
+        let writer_stats = Arc::clone(&self.stats);
 
         // start the reactor for this peer
-        // first, start a task that keeps reading from this guy
-        let mut reader = BufReader::new(stream.clone());
+        // first, start a task that keeps reading from this guy. If the transport is encrypted, it
+        // runs the handshake first. Then it hands the writer, and the sealing key if any, over to
+        // the writer task
+        let secure = self.secure.clone();
+        let (writer_sender, writer_receiver) =
+            oneshot::channel::<(BufWriter<Writer>, Option<secure::Sealer>)>();
+        let mut writer = BufWriter::new(writer);
+        let mut reader = BufReader::new(reader);
+        let (inbound, mut delayed_inbound) = mpsc::unbounded::<(Instant, Vec<u8>)>();
         ex.spawn(async move {
-            // the buffer to store the frame header, which contains the length of the frame
-            let mut size_buffer: [u8; 4] = [0; 4];
-            // the buffer to store the message content
-            let mut msg_buffer: Vec<u8> = vec![];
+            let mut opener = None;
+            match secure {
+                Some(config) => {
+                    let handshake = secure::handshake(
+                        &mut reader,
+                        &mut writer,
+                        &config,
+                        magic,
+                        direction,
+                    );
+                    let timeout = async {
+                        smol::Timer::after(secure::HANDSHAKE_TIMEOUT).await;
+                        Err(std::io::Error::new(
+                            std::io::ErrorKind::TimedOut,
+                            "handshake timed out",
+                        ))
+                    };
+                    match smol::future::or(handshake, timeout).await {
+                        Ok((sealer, o, identity)) => {
+                            info!(
+                                peer:% = addr;
+                                "Secure channel to peer {} with identity {} established",
+                                addr,
+                                hex::encode(identity)
+                            );
+                            opener = Some(o);
+                            let _ = writer_sender.send((writer, Some(sealer)));
+                        }
+                        Err(e) => {
+                            warn!(peer:% = addr; "Handshake with peer {} failed: {}", addr, e);
+                            handle_copy.disconnect();
+                            return;
+                        }
+                    }
+                }
+                None => {
+                    let _ = writer_sender.send((writer, None));
+                }
+            }
             loop {
-                // first, read exactly 4 bytes to get the frame header
-                let msg_size = match reader.read_exact(&mut size_buffer).await {
-                    Ok(_) => u32::from_be_bytes(size_buffer),
-                    Err(_) => {
+                let new_payload = match transport::read_frame(&mut reader, opener.as_mut(), magic).await {
+                    Ok((header, p)) => {
+                        reader_stats.received(header.kind, envelope::HEADER_SIZE + header.length as usize);
+                        p
+                    }
+                    Err(ReadError::Io(e)) => {
+                        debug!(peer:% = addr; "Stopped reading from peer {}: {}", addr, e);
                         break;
                     }
+                    Err(ReadError::Envelope(e)) => {
+                        warn!(peer:% = addr; "Bad frame from peer {}: {}", addr, e);
+                        // drop peers from other networks, and peers whose frames we cannot skip
+                        let misbehavior = match e {
+                            envelope::Error::WrongMagic(_)
+                            | envelope::Error::UnsupportedVersion(_) => break,
+                            envelope::Error::Oversized(_) => Misbehavior::ProtocolViolation,
+                            _ => Misbehavior::MalformedMessage,
+                        };
+                        let _ = reader_control_chan
+                            .send(ControlSignal::Misbehaving(addr, misbehavior))
+                            .await;
+                        if let Misbehavior::ProtocolViolation = misbehavior {
+                            break;
+                        }
+                        continue;
+                    }
                 };
-                // then, read exactly msg_size bytes to get the whole message
-                if msg_buffer.len() < msg_size as usize {
-                    msg_buffer.resize(msg_size as usize, 0);
+                // the message goes on to the worker once the injected latency has passed
+                let deliver_at = match reader_faults.deliver_at(&addr) {
+                    Some(deliver_at) => deliver_at,
+                    None => {
+                        trace!(peer:% = addr; "Dropping a message from peer {}", addr);
+                        continue;
+                    }
+                };
+                if inbound.unbounded_send((deliver_at, new_payload)).is_err() {
+                    break;
                 }
-                match reader
-                    .read_exact(&mut msg_buffer[0..msg_size as usize])
+            }
+            // the peer is disconnected, stop the writer as well
+            handle_copy.disconnect();
+        })
+            .detach();
+        // the messages from this guy reach the worker in order, each one after its latency
+        let mut delivered_handle = handle.clone();
+        ex.spawn(async move {
+            while let Some((deliver_at, payload)) = delayed_inbound.next().await {
+                smol::Timer::at(deliver_at).await;
+                if new_msg_chan
+                    .send((payload, delivered_handle.clone()))
                     .await
+                    .is_err()
                 {
-                    Ok(_) => {
-                        let new_payload: Vec<u8> = msg_buffer[0..msg_size as usize].to_vec();
-                        new_msg_chan
-                            .send((new_payload, handle_copy.clone()))
-                            .await
-                            .unwrap();
-                    }
-                    Err(_) => {
-                        break;
-                    }
+                    break;
                 }
             }
-            // the peer is disconnected
+            delivered_handle.disconnect();
         })
             .detach();
 
-        // second, start a task that keeps writing to this guy
-        let mut writer = BufWriter::new(stream.clone());
+        // second, start a task that holds back the messages to this guy for the injected latency,
+        // the queue is closed if we disconnect from this peer
+        let (outbound, mut delayed_outbound) = mpsc::unbounded::<(Instant, Vec<u8>)>();
         ex.spawn(async move {
-            loop {
-                // first, get a message to write from the queue
-                let new_msg = write_queue.next().await.unwrap();
-
-                // second, encode the length of the message
-                let size_buffer = (new_msg.len() as u32).to_be_bytes();
-
-                // third, write the frame header and the payload
-                match writer.write_all(&size_buffer).await {
-                    Ok(_) => {}
-                    Err(_) => {
-                        break;
+            while let Some(new_msg) = write_queue.next().await {
+                match writer_faults.deliver_at(&addr) {
+                    Some(deliver_at) => {
+                        if outbound.unbounded_send((deliver_at, new_msg)).is_err() {
+                            break;
+                        }
                     }
+                    None => trace!(peer:% = addr; "Dropping a message to peer {}", addr),
                 }
-                match writer.write_all(&new_msg).await {
-                    Ok(_) => {}
-                    Err(_) => {
-                        break;
-                    }
-                }
-                match writer.flush().await {
-                    Ok(_) => {}
-                    Err(_) => {
+            }
+        })
+            .detach();
+
+        // third, start a task that keeps writing to this guy
+        ex.spawn(async move {
+            // wait for the handshake, the sender is dropped if it fails
+            if let Ok((mut writer, mut sealer)) = writer_receiver.await {
+                // first, get a message to write from the queue, once its latency has passed
+                while let Some((deliver_at, new_msg)) = delayed_outbound.next().await {
+                    smol::Timer::at(deliver_at).await;
+                    // second, seal the frame, which is already wrapped in its envelope
+                    let kind = envelope::Header::from_bytes(
+                        new_msg[..envelope::HEADER_SIZE].try_into().unwrap(),
+                    )
+                    .kind;
+                    let size = new_msg.len();
+                    let new_msg = match &mut sealer {
+                        Some(sealer) => sealer.seal(new_msg),
+                        None => new_msg,
+                    };
+                    // third, write the frame
+                    if transport::write_frame(&mut writer, &new_msg).await.is_err() {
                         break;
                     }
+                    writer_stats.sent(kind, size);
                 }
             }
-            // the peer is disconnected
+            // the peer is disconnected, make sure the reader stops too
+            closer();
             control_chan
                 .send(ControlSignal::DroppedPeer(addr))
                 .await
@@ -224,13 +379,15 @@ impl Context {
 
         // insert the peer handle so that we can broadcast to this guy later
         self.peers.insert(addr, handle.clone());
-        Ok(handle)
+        handle
     }
 }
 
 #[derive(Clone)]
 pub struct Handle {
     control_chan: smol::channel::Sender<ControlSignal>,
+    faults: Faults,
+    stats: Arc<Stats>,
 }
 #[cfg(any(test,test_utilities))]
 pub struct TestReceiver{
@@ -267,10 +424,48 @@ impl Handle {
         smol::block_on(self.control_chan.send(ControlSignal::SendToPeer((receiver, msg)))).unwrap();
     }
 
+    /// Raise the misbehavior score of a peer, banning it if it crosses the threshold.
+    pub fn report(&self, addr: std::net::SocketAddr, misbehavior: Misbehavior) {
+        smol::block_on(self.control_chan.send(ControlSignal::Misbehaving(addr, misbehavior))).unwrap();
+    }
+
+    /// List the IPs that are currently banned.
+    pub fn bans(&self) -> Vec<Ban> {
+        let (sender, receiver) = oneshot::channel();
+        smol::block_on(self.control_chan.send(ControlSignal::GetBans(sender))).unwrap();
+        smol::block_on(receiver).unwrap()
+    }
+
+    /// Lift the ban on one IP, or on all IPs if `ip` is `None`. Returns the number of bans lifted.
+    pub fn clear_bans(&self, ip: Option<std::net::IpAddr>) -> usize {
+        let (sender, receiver) = oneshot::channel();
+        smol::block_on(self.control_chan.send(ControlSignal::ClearBans(ip, sender))).unwrap();
+        smol::block_on(receiver).unwrap()
+    }
+
+    /// List the connected peers, with the faults injected into their traffic.
+    pub fn peers(&self) -> Vec<PeerFault> {
+        let (sender, receiver) = oneshot::channel();
+        smol::block_on(self.control_chan.send(ControlSignal::GetPeers(sender))).unwrap();
+        let mut peers = smol::block_on(receiver).unwrap();
+        peers.sort();
+        self.faults.list(&peers)
+    }
+
+    /// The faults injected into the traffic with peers.
+    pub fn faults(&self) -> &Faults {
+        &self.faults
+    }
+
+    /// Counters of the traffic with peers and of the blocks they sent.
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
     #[cfg(any(test,test_utilities))]
     pub fn new_for_test() -> (Handle, TestReceiver) {
         let (s,r) = smol::channel::unbounded();
-        let h = Handle {control_chan: s};
+        let h = Handle {control_chan: s, faults: Faults::new(), stats: Arc::new(Stats::new())};
         let t = TestReceiver {control_chan: r};
         (h,t)
     }
@@ -282,7 +477,160 @@ enum ControlSignal {
         oneshot::Sender<std::io::Result<peer::Handle>>,
     ),
     BroadcastMessage(message::Message),
-    GetNewPeer(Async<net::TcpStream>),
+    GetNewPeer(Connection),
     DroppedPeer(std::net::SocketAddr),
+    Misbehaving(std::net::SocketAddr, Misbehavior),
+    GetBans(oneshot::Sender<Vec<Ban>>),
+    GetPeers(oneshot::Sender<Vec<std::net::SocketAddr>>),
+    ClearBans(Option<std::net::IpAddr>, oneshot::Sender<usize>),
     SendToPeer((Address,message::Message)),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::Blockchain;
+    use crate::network::transport::MemoryNetwork;
+    use crate::network::worker::Worker;
+    use crate::types::block::generate_random_block;
+    use crate::types::hash::Hashable;
+    use crate::types::key_pair;
+    use crate::types::transaction::Mempool;
+    use ntest::timeout;
+    use std::collections::HashMap;
+    use std::net::SocketAddr;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    /// Start a P2P server and its worker on an in-memory network.
+    fn start_node(
+        network: &MemoryNetwork,
+        ip: [u8; 4],
+        encrypt: bool,
+    ) -> (Handle, Arc<Mutex<Blockchain>>) {
+        let (msg_tx, msg_rx) = smol::channel::bounded(10000);
+        let secure = if encrypt {
+            Some(Arc::new(secure::Config::new(key_pair::random(), None)))
+        } else {
+            None
+        };
+        let (ctx, server) = new(
+            SocketAddr::from((ip, 6000)),
+            msg_tx,
+            BanList::new(100, Duration::from_secs(60)),
+            envelope::DEFAULT_MAGIC,
+            secure,
+            Arc::new(network.transport(ip.into())),
+        )
+        .unwrap();
+        ctx.start().unwrap();
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        let orphan_buffer = Arc::new(Mutex::new(HashMap::new()));
+        Worker::new(1, msg_rx, &server, &blockchain, &mempool, &orphan_buffer).start();
+        (server, blockchain)
+    }
+
+    fn sync_block(encrypt: bool) {
+        let network = MemoryNetwork::new();
+        let (a, a_chain) = start_node(&network, [10, 0, 0, 1], encrypt);
+        let (b, b_chain) = start_node(&network, [10, 0, 0, 2], encrypt);
+        assert!(b.connect(SocketAddr::from(([10, 0, 0, 3], 6000))).is_err());
+        let peer = b.connect(SocketAddr::from(([10, 0, 0, 1], 6000))).unwrap();
+        assert_eq!(peer.addr().ip(), std::net::IpAddr::from([10, 0, 0, 1]));
+
+        let block = {
+            let mut chain = a_chain.lock().unwrap();
+            let block = generate_random_block(&chain.tip());
+            chain.insert(&block).unwrap();
+            block
+        };
+        // a registers b a bit after b is connected, announce until b asks for the block
+        while !b_chain.lock().unwrap().block_map.contains_key(&block.hash()) {
+            a.broadcast(message::Message::NewBlockHashes(vec![block.hash()]));
+            thread::sleep(Duration::from_millis(50));
+        }
+        assert_eq!(b_chain.lock().unwrap().tip(), block.hash());
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn sync_block_in_memory() {
+        sync_block(false);
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn sync_block_in_memory_encrypted() {
+        sync_block(true);
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn refuse_banned_peer_from_new_port() {
+        let network = MemoryNetwork::new();
+        let (a, _) = start_node(&network, [10, 0, 0, 1], false);
+        // b dials from ephemeral ports of the loopback address, as on a local test network
+        let (b, _) = start_node(&network, [127, 0, 0, 1], false);
+        let a_addr = SocketAddr::from(([10, 0, 0, 1], 6000));
+        b.connect(a_addr).unwrap();
+        let wait_for_peers = |n| {
+            while a.peers().len() != n {
+                thread::sleep(Duration::from_millis(10));
+            }
+        };
+        wait_for_peers(1);
+        let first: SocketAddr = a.peers()[0].addr.parse().unwrap();
+        a.report(first, Misbehavior::InvalidBlock);
+        a.report(first, Misbehavior::InvalidBlock);
+        wait_for_peers(0);
+        assert_eq!(a.bans().len(), 1);
+
+        // b dials again, from another port, and a hangs up at once
+        b.connect(a_addr).unwrap();
+        thread::sleep(Duration::from_millis(200));
+        assert!(a.peers().is_empty());
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn partition_and_heal() {
+        let network = MemoryNetwork::new();
+        let (a, a_chain) = start_node(&network, [10, 0, 0, 1], false);
+        let (b, b_chain) = start_node(&network, [10, 0, 0, 2], false);
+        b.connect(SocketAddr::from(([10, 0, 0, 1], 6000))).unwrap();
+        let peers = loop {
+            let peers = a.peers();
+            if !peers.is_empty() {
+                break peers;
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+        let peer: SocketAddr = peers[0].addr.parse().unwrap();
+        a.faults().partition(&[peer]);
+        assert!(a.peers()[0].fault.partitioned);
+
+        let mine = || {
+            let mut chain = a_chain.lock().unwrap();
+            let block = generate_random_block(&chain.tip());
+            chain.insert(&block).unwrap();
+            block.hash()
+        };
+        let lost = mine();
+        a.broadcast(message::Message::NewBlockHashes(vec![lost]));
+        thread::sleep(Duration::from_millis(200));
+        assert!(!b_chain.lock().unwrap().block_map.contains_key(&lost));
+
+        // once healed, the next block brings the lost one along, late
+        a.faults().heal();
+        a.faults().set(None, Some(100), None);
+        let start = std::time::Instant::now();
+        let next = mine();
+        a.broadcast(message::Message::NewBlockHashes(vec![next]));
+        while b_chain.lock().unwrap().tip() != next {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert!(b_chain.lock().unwrap().block_map.contains_key(&lost));
+    }
+}
@@ -0,0 +1,127 @@
+//! Counters of the traffic with peers and of the blocks they send us, as exported by `/metrics`.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use super::message;
+
+/// Messages of one type, and their size in the wire envelope.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Traffic {
+    pub messages: u64,
+    pub bytes: u64,
+}
+
+#[derive(Default)]
+pub struct Stats {
+    received: Mutex<BTreeMap<u8, Traffic>>,
+    sent: Mutex<BTreeMap<u8, Traffic>>,
+    blocks_received: AtomicU64,
+    blocks_accepted: AtomicU64,
+    blocks_rejected: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+fn add(traffic: &Mutex<BTreeMap<u8, Traffic>>, kind: u8, bytes: usize) {
+    let mut traffic = traffic.lock().unwrap();
+    let entry = traffic.entry(kind).or_default();
+    entry.messages += 1;
+    entry.bytes += bytes as u64;
+}
+
+fn by_name(traffic: &Mutex<BTreeMap<u8, Traffic>>) -> Vec<(&'static str, Traffic)> {
+    traffic
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(kind, traffic)| (message::kind_name(*kind), *traffic))
+        .collect()
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count a frame read from a peer, `kind` being the message type in its envelope.
+    pub fn received(&self, kind: u8, bytes: usize) {
+        add(&self.received, kind, bytes);
+    }
+
+    /// Count a frame written to a peer.
+    pub fn sent(&self, kind: u8, bytes: usize) {
+        add(&self.sent, kind, bytes);
+    }
+
+    pub fn block_received(&self) {
+        self.blocks_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn block_accepted(&self) {
+        self.blocks_accepted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn block_rejected(&self, reason: &'static str) {
+        *self.blocks_rejected.lock().unwrap().entry(reason).or_insert(0) += 1;
+    }
+
+    /// Traffic received per message type, by the name of the type.
+    pub fn received_by_type(&self) -> Vec<(&'static str, Traffic)> {
+        by_name(&self.received)
+    }
+
+    pub fn sent_by_type(&self) -> Vec<(&'static str, Traffic)> {
+        by_name(&self.sent)
+    }
+
+    pub fn blocks_received(&self) -> u64 {
+        self.blocks_received.load(Ordering::Relaxed)
+    }
+
+    pub fn blocks_accepted(&self) -> u64 {
+        self.blocks_accepted.load(Ordering::Relaxed)
+    }
+
+    /// Blocks rejected, per reason.
+    pub fn blocks_rejected(&self) -> Vec<(&'static str, u64)> {
+        self.blocks_rejected
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(reason, n)| (*reason, *n))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::message::Message;
+
+    #[test]
+    fn count_traffic_and_blocks() {
+        let stats = Stats::new();
+        let ping = Message::Ping(String::new()).kind();
+        let blocks = Message::Blocks(Vec::new()).kind();
+        stats.received(ping, 20);
+        stats.received(ping, 30);
+        stats.received(blocks, 1000);
+        stats.sent(ping, 20);
+        assert_eq!(
+            stats.received_by_type(),
+            vec![
+                ("Ping", Traffic { messages: 2, bytes: 50 }),
+                ("Blocks", Traffic { messages: 1, bytes: 1000 }),
+            ]
+        );
+        assert_eq!(stats.sent_by_type(), vec![("Ping", Traffic { messages: 1, bytes: 20 })]);
+
+        stats.block_received();
+        stats.block_received();
+        stats.block_accepted();
+        stats.block_rejected("unknown_parent");
+        assert_eq!(stats.blocks_received(), 2);
+        assert_eq!(stats.blocks_accepted(), 1);
+        assert_eq!(stats.blocks_rejected(), vec![("unknown_parent", 1)]);
+    }
+}
@@ -0,0 +1,367 @@
+//! How the P2P server reaches its peers: over TCP, or over in-memory pipes between the nodes of
+//! one process.
+//!
+//! A transport listens for and dials connections, and a connection splits into a byte reader
+//! and a byte writer that carry frames, sealed or not, as written by `write_frame` and read by
+//! `read_frame`.
+
+use async_dup::Arc as AsyncArc;
+use futures::channel::mpsc;
+use futures::future::BoxFuture;
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use futures::stream::StreamExt;
+use smol::Async;
+use std::collections::HashMap;
+use std::io;
+use std::net::{self, IpAddr, Shutdown, SocketAddr};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use super::envelope;
+use super::secure;
+
+pub type Reader = Box<dyn AsyncRead + Unpin + Send>;
+pub type Writer = Box<dyn AsyncWrite + Unpin + Send>;
+/// Closes both directions of a connection.
+pub type Closer = Box<dyn FnOnce() + Send>;
+
+pub trait Transport: Send + Sync {
+    /// Start accepting connections at `addr`.
+    fn listen(&self, addr: SocketAddr) -> io::Result<Box<dyn Listener>>;
+
+    /// Connect to the peer listening at `addr`.
+    fn dial(&self, addr: SocketAddr) -> BoxFuture<'static, io::Result<Connection>>;
+}
+
+pub trait Listener: Send {
+    /// Wait for the next incoming connection.
+    fn accept(&mut self) -> BoxFuture<'_, io::Result<Connection>>;
+}
+
+pub struct Connection {
+    addr: SocketAddr,
+    reader: Reader,
+    writer: Writer,
+    closer: Closer,
+}
+
+impl Connection {
+    pub fn new(addr: SocketAddr, reader: Reader, writer: Writer, closer: Closer) -> Self {
+        Self {
+            addr,
+            reader,
+            writer,
+            closer,
+        }
+    }
+
+    /// Address of the peer at the other end.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn split(self) -> (Reader, Writer, Closer) {
+        (self.reader, self.writer, self.closer)
+    }
+
+    pub fn close(self) {
+        (self.closer)();
+    }
+}
+
+pub enum ReadError {
+    /// The connection is broken or the peer cannot be trusted anymore.
+    Io(io::Error),
+    /// The frame is invalid.
+    Envelope(envelope::Error),
+}
+
+/// Read the next frame from a peer, and return its envelope header and the bincode encoded
+/// message it carries.
+pub async fn read_frame<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    opener: Option<&mut secure::Opener>,
+    magic: u32,
+) -> Result<(envelope::Header, Vec<u8>), ReadError> {
+    match opener {
+        Some(opener) => {
+            // read the length of the sealed frame, then the frame itself
+            let mut size_buffer = [0u8; 4];
+            reader.read_exact(&mut size_buffer).await.map_err(ReadError::Io)?;
+            let size = u32::from_be_bytes(size_buffer);
+            if size > secure::Opener::max_sealed_size() {
+                return Err(ReadError::Envelope(envelope::Error::Oversized(size)));
+            }
+            let mut sealed = vec![0u8; size as usize];
+            reader.read_exact(&mut sealed).await.map_err(ReadError::Io)?;
+            let frame = opener.open(sealed).map_err(ReadError::Io)?;
+            envelope::open_frame(magic, &frame).map_err(ReadError::Envelope)
+        }
+        None => {
+            // read exactly one envelope header, and check it before allocating for the payload
+            let mut header_buffer = [0u8; envelope::HEADER_SIZE];
+            reader.read_exact(&mut header_buffer).await.map_err(ReadError::Io)?;
+            let header = envelope::Header::from_bytes(&header_buffer);
+            header.validate(magic).map_err(ReadError::Envelope)?;
+            let mut payload = vec![0u8; header.length as usize];
+            reader.read_exact(&mut payload).await.map_err(ReadError::Io)?;
+            let payload = envelope::open(&header, payload).map_err(ReadError::Envelope)?;
+            Ok((header, payload))
+        }
+    }
+}
+
+/// Write a frame, already wrapped in its envelope and sealed if the connection is encrypted.
+pub async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, frame: &[u8]) -> io::Result<()> {
+    writer.write_all(frame).await?;
+    writer.flush().await
+}
+
+/// Plain TCP connections.
+pub struct Tcp;
+
+impl Transport for Tcp {
+    fn listen(&self, addr: SocketAddr) -> io::Result<Box<dyn Listener>> {
+        Ok(Box::new(TcpListener(Async::<net::TcpListener>::bind(addr)?)))
+    }
+
+    fn dial(&self, addr: SocketAddr) -> BoxFuture<'static, io::Result<Connection>> {
+        Box::pin(async move { tcp_connection(Async::<net::TcpStream>::connect(addr).await?) })
+    }
+}
+
+struct TcpListener(Async<net::TcpListener>);
+
+impl Listener for TcpListener {
+    fn accept(&mut self) -> BoxFuture<'_, io::Result<Connection>> {
+        Box::pin(async move { tcp_connection(self.0.accept().await?.0) })
+    }
+}
+
+fn tcp_connection(stream: Async<net::TcpStream>) -> io::Result<Connection> {
+    let addr = stream.get_ref().peer_addr()?;
+    let stream = AsyncArc::new(stream);
+    let closer = stream.clone();
+    Ok(Connection::new(
+        addr,
+        Box::new(stream.clone()),
+        Box::new(stream),
+        Box::new(move || {
+            let _ = closer.get_ref().shutdown(Shutdown::Both);
+        }),
+    ))
+}
+
+/// First port of the addresses nodes dial from, like the ephemeral ports of TCP.
+const FIRST_DIAL_PORT: u16 = 49152;
+
+/// In-memory connections between the nodes of one process, each one with its own transport.
+#[derive(Clone)]
+pub struct MemoryNetwork {
+    listeners: Arc<Mutex<HashMap<SocketAddr, smol::channel::Sender<Connection>>>>,
+    next_port: Arc<AtomicU16>,
+}
+
+impl Default for MemoryNetwork {
+    fn default() -> Self {
+        Self {
+            listeners: Arc::new(Mutex::new(HashMap::new())),
+            next_port: Arc::new(AtomicU16::new(FIRST_DIAL_PORT)),
+        }
+    }
+}
+
+impl MemoryNetwork {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The transport of a node whose outgoing connections come from `ip`.
+    pub fn transport(&self, ip: IpAddr) -> Memory {
+        Memory {
+            network: self.clone(),
+            ip,
+        }
+    }
+}
+
+pub struct Memory {
+    network: MemoryNetwork,
+    ip: IpAddr,
+}
+
+impl Transport for Memory {
+    fn listen(&self, addr: SocketAddr) -> io::Result<Box<dyn Listener>> {
+        let mut listeners = self.network.listeners.lock().unwrap();
+        if listeners.contains_key(&addr) {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("{} is in use", addr),
+            ));
+        }
+        let (sender, receiver) = smol::channel::unbounded();
+        listeners.insert(addr, sender);
+        Ok(Box::new(MemoryListener(receiver)))
+    }
+
+    fn dial(&self, addr: SocketAddr) -> BoxFuture<'static, io::Result<Connection>> {
+        let listener = self.network.listeners.lock().unwrap().get(&addr).cloned();
+        let port = self.network.next_port.fetch_add(1, Ordering::Relaxed);
+        let from = SocketAddr::new(self.ip, port);
+        Box::pin(async move {
+            let refused = || io::Error::new(io::ErrorKind::ConnectionRefused, "connection refused");
+            let listener = listener.ok_or_else(refused)?;
+            let (outgoing, incoming) = (mpsc::unbounded(), mpsc::unbounded());
+            let (dialer_closer, listener_closer) = (
+                pipe_closer(&outgoing.0, &incoming.0),
+                pipe_closer(&outgoing.0, &incoming.0),
+            );
+            let accepted = Connection::new(
+                from,
+                Box::new(PipeReader::new(outgoing.1)),
+                Box::new(PipeWriter(incoming.0)),
+                listener_closer,
+            );
+            listener.send(accepted).await.map_err(|_| refused())?;
+            Ok(Connection::new(
+                addr,
+                Box::new(PipeReader::new(incoming.1)),
+                Box::new(PipeWriter(outgoing.0)),
+                dialer_closer,
+            ))
+        })
+    }
+}
+
+struct MemoryListener(smol::channel::Receiver<Connection>);
+
+impl Listener for MemoryListener {
+    fn accept(&mut self) -> BoxFuture<'_, io::Result<Connection>> {
+        Box::pin(async move {
+            self.0
+                .recv()
+                .await
+                .map_err(|e| io::Error::new(io::ErrorKind::NotConnected, e))
+        })
+    }
+}
+
+/// Close both pipes of a connection. Bytes already written can still be read.
+fn pipe_closer(a: &mpsc::UnboundedSender<Vec<u8>>, b: &mpsc::UnboundedSender<Vec<u8>>) -> Closer {
+    let (a, b) = (a.clone(), b.clone());
+    Box::new(move || {
+        a.close_channel();
+        b.close_channel();
+    })
+}
+
+struct PipeWriter(mpsc::UnboundedSender<Vec<u8>>);
+
+impl AsyncWrite for PipeWriter {
+    fn poll_write(self: Pin<&mut Self>, _: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        match self.0.unbounded_send(buf.to_vec()) {
+            Ok(()) => Poll::Ready(Ok(buf.len())),
+            Err(_) => Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.0.close_channel();
+        Poll::Ready(Ok(()))
+    }
+}
+
+struct PipeReader {
+    chunks: mpsc::UnboundedReceiver<Vec<u8>>,
+    /// The chunk being read, and how much of it was read
+    chunk: Vec<u8>,
+    read: usize,
+}
+
+impl PipeReader {
+    fn new(chunks: mpsc::UnboundedReceiver<Vec<u8>>) -> Self {
+        Self {
+            chunks,
+            chunk: Vec::new(),
+            read: 0,
+        }
+    }
+}
+
+impl AsyncRead for PipeReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.read == self.chunk.len() {
+            match self.chunks.poll_next_unpin(cx) {
+                Poll::Ready(Some(chunk)) => {
+                    self.chunk = chunk;
+                    self.read = 0;
+                }
+                // closed, the end of the stream
+                Poll::Ready(None) => return Poll::Ready(Ok(0)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        let n = buf.len().min(self.chunk.len() - self.read);
+        buf[..n].copy_from_slice(&self.chunk[self.read..self.read + n]);
+        self.read += n;
+        Poll::Ready(Ok(n))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::message::Message;
+
+    #[test]
+    fn memory_connection() {
+        let network = MemoryNetwork::new();
+        let a = network.transport([10, 0, 0, 1].into());
+        let b = network.transport([10, 0, 0, 2].into());
+        let addr = SocketAddr::from(([10, 0, 0, 1], 6000));
+        let mut listener = a.listen(addr).unwrap();
+        assert!(a.listen(addr).is_err());
+        smol::block_on(async {
+            assert!(b.dial(SocketAddr::from(([10, 0, 0, 3], 6000))).await.is_err());
+            let dialed = b.dial(addr).await.unwrap();
+            let accepted = listener.accept().await.unwrap();
+            assert_eq!(dialed.addr(), addr);
+            assert_eq!(accepted.addr().ip(), IpAddr::from([10, 0, 0, 2]));
+
+            let magic = envelope::DEFAULT_MAGIC;
+            let (mut reader, _writer, _) = accepted.split();
+            let (_, mut writer, closer) = dialed.split();
+            let msg = Message::Ping("hello".to_string());
+            write_frame(&mut writer, &envelope::seal(magic, &msg).unwrap()).await.unwrap();
+            let payload = match read_frame(&mut reader, None, magic).await {
+                Ok((header, payload)) => {
+                    assert_eq!(header.kind, msg.kind());
+                    payload
+                }
+                Err(_) => panic!("cannot read the frame"),
+            };
+            match Message::decode(&payload).unwrap() {
+                Message::Ping(nonce) => assert_eq!(nonce, "hello"),
+                _ => panic!(),
+            }
+            // the other side sees the end of the stream once the connection is closed
+            closer();
+            assert!(write_frame(&mut writer, &[0u8]).await.is_err());
+            let mut byte = [0u8; 1];
+            assert_eq!(reader.read(&mut byte).await.unwrap(), 0);
+        });
+    }
+}
@@ -1,10 +1,12 @@
+use super::ban::Misbehavior;
 use super::message::Message;
 use super::peer;
 use super::server::Handle as ServerHandle;
+use crate::blockchain::pruner::{add_orphan, take_orphans};
 use crate::blockchain::{self, Blockchain};
 use crate::types::block::Block;
 use crate::types::hash::{Hashable, H256};
-use crate::types::transaction::{verify, Mempool};
+use crate::types::transaction::Mempool;
 
 use log::{debug, error, warn};
 
@@ -16,6 +18,10 @@ use std::thread;
 use super::peer::TestReceiver as PeerTestReceiver;
 #[cfg(any(test, test_utilities))]
 use super::server::TestReceiver as ServerTestReceiver;
+
+/// Maximum number of hashes, blocks or transactions in a single message.
+const MAX_INVENTORY: usize = 50_000;
+
 #[derive(Clone)]
 pub struct Worker {
     msg_chan: smol::channel::Receiver<(Vec<u8>, peer::Handle)>,
@@ -58,130 +64,180 @@ impl Worker {
 
     fn worker_loop(&self) {
         loop {
-            let result = smol::block_on(self.msg_chan.recv());
-            if let Err(e) = result {
-                error!("network worker terminated {}", e);
-                break;
-            }
-            let msg = result.unwrap();
-            let (msg, mut peer) = msg;
-            let msg: Message = bincode::deserialize(&msg).unwrap();
-            let mut chain_unwrapped = self.blockchain.lock().unwrap();
-            let mut mempool_unwrapped = self.mempool.lock().unwrap();
-            match msg {
-                Message::Ping(nonce) => {
-                    debug!("Ping: {}", nonce);
-                    peer.write(Message::Pong(nonce.to_string()));
+            let (msg, mut peer) = match smol::block_on(self.msg_chan.recv()) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    error!("network worker terminated {}", e);
+                    break;
                 }
-                Message::Pong(nonce) => {
-                    debug!("Pong: {}", nonce);
+            };
+            let msg = match Message::decode(&msg) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    warn!(peer:% = peer.addr(); "Undecodable message from {}: {}", peer.addr(), e);
+                    self.server
+                        .report(*peer.addr(), Misbehavior::MalformedMessage);
+                    continue;
                 }
-                Message::NewBlockHashes(hashes) => {
-                    let mut hashes_need_blocks = Vec::new();
-                    for hash in hashes.clone() {
-                        if !chain_unwrapped.block_map.contains_key(&hash) {
-                            // if no block contains this hash, then we ask by sending GetBlocks
-                            hashes_need_blocks.push(hash);
-                        }
+            };
+            if msg.inventory_size() > MAX_INVENTORY {
+                warn!(peer:% = peer.addr(); "Oversized inventory message from {}", peer.addr());
+                self.server
+                    .report(*peer.addr(), Misbehavior::ProtocolViolation);
+                continue;
+            }
+            self.handle_message(msg, &mut peer);
+        }
+    }
+
+    /// Process one decoded message from a peer. Must not panic on any input.
+    fn handle_message(&self, msg: Message, peer: &mut peer::Handle) {
+        let mut chain_unwrapped = self.blockchain.lock().unwrap();
+        let mut mempool_unwrapped = self.mempool.lock().unwrap();
+        match msg {
+            Message::Ping(nonce) => {
+                debug!("Ping: {}", nonce);
+                peer.write(Message::Pong(nonce.to_string()));
+            }
+            Message::Pong(nonce) => {
+                debug!("Pong: {}", nonce);
+            }
+            Message::NewBlockHashes(hashes) => {
+                peer.mark_known(hashes.iter().copied());
+                let mut hashes_need_blocks = Vec::new();
+                for hash in hashes.clone() {
+                    if !chain_unwrapped.block_map.contains_key(&hash) {
+                        // if no block contains this hash, then we ask by sending GetBlocks
+                        hashes_need_blocks.push(hash);
                     }
+                }
+                if !hashes_need_blocks.is_empty() {
                     peer.write(Message::GetBlocks(hashes_need_blocks));
                 }
-                Message::GetBlocks(hashes) => {
-                    let mut blocks_with_hashes = Vec::new();
-                    for hash in hashes.clone() {
-                        if chain_unwrapped.block_map.contains_key(&hash) {
-                            let block = chain_unwrapped.block_map[&hash].clone();
-                            blocks_with_hashes.push(block);
-                        }
+            }
+            Message::GetBlocks(hashes) => {
+                let mut blocks_with_hashes = Vec::new();
+                for hash in hashes.clone() {
+                    if let Some(block) = chain_unwrapped.block(&hash) {
+                        blocks_with_hashes.push(block.clone());
                     }
+                }
+                if !blocks_with_hashes.is_empty() {
+                    peer.mark_known(blocks_with_hashes.iter().map(|b| b.hash()));
                     peer.write(Message::Blocks(blocks_with_hashes));
                 }
-                Message::Blocks(blocks) => {
-                    let mut new_blocks = Vec::new();
-                    let mut parent_blocks_missing = Vec::new();
-                    for block in blocks.clone() {
-                        let difficulty = block.get_difficulty();
-                        let mut hash = block.hash();
-                        // check if curr block hash contained in chain. If not, we insert it
-                        if !chain_unwrapped.block_map.contains_key(&hash) {
-                            // check if blocks parent is missing
-                            let parent_block_hash = block.get_parent();
-                            // let parent_block= chain_unwrapped.block_map[parent_block_hash];
-                            let mut orphan_buffer_unwrapped = self.orphan_buffer.lock().unwrap();
-                            if !chain_unwrapped.block_map.contains_key(&parent_block_hash) {
-                                parent_blocks_missing.push(parent_block_hash);
-                                orphan_buffer_unwrapped.insert(parent_block_hash, block.clone());
-                            } else {
-                                // do PoW checks
-                                let parent_difficulty =
-                                    chain_unwrapped.block_map[&parent_block_hash].get_difficulty();
-                                if hash <= difficulty && difficulty == parent_difficulty {
-                                    chain_unwrapped.insert(&block);
+            }
+            Message::Blocks(blocks) => {
+                peer.mark_known(blocks.iter().map(|b| b.hash()));
+                let stats = self.server.stats();
+                let mut new_blocks = Vec::new();
+                let mut parent_blocks_missing = Vec::new();
+                for block in blocks.clone() {
+                    stats.block_received();
+                    let hash = block.hash();
+                    // check if curr block hash contained in chain. If not, we insert it
+                    if !chain_unwrapped.block_map.contains_key(&hash) {
+                        // check if blocks parent is missing
+                        let parent_block_hash = block.get_parent();
+                        let mut orphan_buffer_unwrapped = self.orphan_buffer.lock().unwrap();
+                        if !chain_unwrapped.block_map.contains_key(&parent_block_hash) {
+                            parent_blocks_missing.push(parent_block_hash);
+                            // the parents arrive after their children, from a checkpoint down
+                            chain_unwrapped.vouch(&block);
+                            add_orphan(&mut orphan_buffer_unwrapped, block.clone());
+                        } else {
+                            // checks PoW and executes the transactions
+                            if let Err(e) = chain_unwrapped.insert(&block) {
+                                warn!(peer:% = peer.addr(), hash:% = hash; "Block {} from {} is invalid: {}", hash, peer.addr(), e);
+                                stats.block_rejected(e.reason());
+                                if e.is_misbehavior() {
+                                    self.server.report(*peer.addr(), Misbehavior::InvalidBlock);
                                 }
+                                continue;
+                            }
+                            stats.block_accepted();
+                            new_blocks.push(hash);
 
-                                // check if block is a parent an orphan is waiting for
-                                loop {
-                                    if orphan_buffer_unwrapped.contains_key(&hash) {
-                                        let orphan_block =
-                                            orphan_buffer_unwrapped.remove(&hash).unwrap();
-                                        chain_unwrapped.insert(&orphan_block);
-                                        new_blocks.push(orphan_block.hash());
-                                        self.server.broadcast(Message::NewBlockHashes(vec![
-                                            orphan_block.hash(),
-                                        ]));
-                                        hash = orphan_block.hash();
-                                    } else {
-                                        break;
+                            // insert the orphans waiting for this block, then the ones waiting
+                            // for them
+                            let mut parents = vec![hash];
+                            while let Some(parent) = parents.pop() {
+                                for orphan_block in take_orphans(&mut orphan_buffer_unwrapped, &parent) {
+                                    let orphan_hash = orphan_block.hash();
+                                    if let Err(e) = chain_unwrapped.insert(&orphan_block) {
+                                        warn!(hash:% = orphan_hash; "Orphan block {} is invalid: {}", orphan_hash, e);
+                                        stats.block_rejected(e.reason());
+                                        continue;
                                     }
+                                    stats.block_accepted();
+                                    new_blocks.push(orphan_hash);
+                                    parents.push(orphan_hash);
                                 }
                             }
-
-                            new_blocks.push(hash);
                         }
                     }
-                    if !parent_blocks_missing.is_empty() {
-                        peer.write(Message::GetBlocks(parent_blocks_missing));
-                    }
-                    if !new_blocks.is_empty() {
-                        self.server.broadcast(Message::NewBlockHashes(new_blocks));
-                    }
                 }
-                Message::NewTransactionHashes(hashes) => {
-                    let mut new_txs = Vec::new();
-                    for hash in hashes.clone() {
-                        if !mempool_unwrapped.tx_map.contains_key(&hash) {
-                            // if no block contains this hash, then we ask by sending GetBlocks
-                            new_txs.push(hash);
-                        }
+                if !parent_blocks_missing.is_empty() {
+                    peer.write(Message::GetBlocks(parent_blocks_missing));
+                }
+                if !new_blocks.is_empty() {
+                    // drop the transactions the new blocks executed, or made impossible
+                    mempool_unwrapped.prune(chain_unwrapped.tip_state());
+                    self.server.broadcast(Message::NewBlockHashes(new_blocks));
+                }
+            }
+            Message::NewTransactionHashes(hashes) => {
+                peer.mark_known(hashes.iter().copied());
+                let mut new_txs = Vec::new();
+                for hash in hashes.clone() {
+                    if !mempool_unwrapped.tx_map.contains_key(&hash) {
+                        // if the mempool does not have this hash, then we ask by sending GetTransactions
+                        new_txs.push(hash);
                     }
+                }
+                if !new_txs.is_empty() {
                     peer.write(Message::GetTransactions(new_txs));
                 }
-                Message::GetTransactions(hashes) => {
-                    let mut txs_ready_for_mempool = Vec::new();
-                    for hash in hashes.clone() {
-                        if mempool_unwrapped.tx_map.contains_key(&hash) {
-                            let tx = mempool_unwrapped.tx_map[&hash].clone();
-                            txs_ready_for_mempool.push(tx);
-                        }
+            }
+            Message::GetTransactions(hashes) => {
+                let mut txs_ready_for_mempool = Vec::new();
+                for hash in hashes.clone() {
+                    if let Some(tx) = mempool_unwrapped.tx_map.get(&hash) {
+                        txs_ready_for_mempool.push(tx.clone());
                     }
+                }
+                if !txs_ready_for_mempool.is_empty() {
+                    peer.mark_known(txs_ready_for_mempool.iter().map(|t| t.hash()));
                     peer.write(Message::Transactions(txs_ready_for_mempool));
                 }
-                Message::Transactions(txs) => {
-                    for signed_tx in txs.clone() {
-                        let transaction = &signed_tx.transaction;
-                        let pub_key = &signed_tx.public_key;
-                        let signature = &signed_tx.signature;
-                        let sender_pub_key = &transaction.sender.0;
-                        if verify(&transaction, &pub_key, &signature) && pub_key == sender_pub_key {
+            }
+            Message::Transactions(txs) => {
+                peer.mark_known(txs.iter().map(|t| t.hash()));
+                let mut new_txs = Vec::new();
+                for signed_tx in txs.clone() {
+                    if mempool_unwrapped.tx_map.contains_key(&signed_tx.hash()) {
+                        // already have it, and already announced it
+                        continue;
+                    }
+                    // transactions waiting for earlier ones of the same sender are kept too
+                    match mempool_unwrapped.admit(&signed_tx, chain_unwrapped.tip_state()) {
+                        Ok(()) => {
+                            new_txs.push(signed_tx.hash());
+                        }
+                        Err(e) if e.is_malformed() => {
+                            warn!(peer:% = peer.addr(), hash:% = signed_tx.hash(); "Transaction {} from {} is invalid: {}", signed_tx.hash(), peer.addr(), e);
                             self.server
-                                .broadcast(Message::NewTransactionHashes(vec![signed_tx
-                                    .hash()
-                                    .clone()]));
-                            mempool_unwrapped.insert(&signed_tx);
+                                .report(*peer.addr(), Misbehavior::InvalidTransaction);
+                        }
+                        Err(e) => {
+                            debug!(peer:% = peer.addr(), hash:% = signed_tx.hash(); "Dropping transaction {} from {}: {}", signed_tx.hash(), peer.addr(), e);
                         }
                     }
                 }
-                _ => unimplemented!(),
+                if !new_txs.is_empty() {
+                    self.server
+                        .broadcast(Message::NewTransactionHashes(new_txs));
+                }
             }
         }
     }
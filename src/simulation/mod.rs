@@ -0,0 +1,291 @@
+//! Several complete nodes in one process, so that consensus can be tested without launching
+//! processes and curling their APIs.
+//!
+//! Every node has its own blockchain, mempool, P2P server, network worker and miner, like the
+//! `bitcoin` binary, but no sockets: the servers talk over an in-memory transport, and the
+//! faults they inject into their traffic delay or drop messages. The chain is the one of
+//! regtest, where any nonce solves a block.
+
+use log::info;
+use ring::signature::KeyPair;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::blockchain::spec::ChainSpec;
+use crate::blockchain::Blockchain;
+use crate::miner;
+use crate::miner::policy::Policy;
+use crate::network::ban::BanList;
+use crate::network::envelope;
+use crate::network::peer;
+use crate::network::server;
+use crate::network::server::Handle as ServerHandle;
+use crate::network::transport::MemoryNetwork;
+use crate::network::worker::Worker as NetworkWorker;
+use crate::types::address::Address;
+use crate::types::hash::H256;
+use crate::types::key_pair;
+use crate::types::transaction::Mempool;
+
+/// How often `wait_for_convergence` and `wait_for_block` look at the nodes.
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Port every simulated node listens at, each one on an IP of its own.
+const P2P_PORT: u16 = 6000;
+
+pub struct Node {
+    pub addr: SocketAddr,
+    pub blockchain: Arc<Mutex<Blockchain>>,
+    pub mempool: Arc<Mutex<Mempool>>,
+    pub server: ServerHandle,
+    pub miner: miner::Handle,
+    pub external_miner: miner::external::Handle,
+    /// Messages from the server to the network worker, closed to stop the worker
+    inbox: smol::channel::Sender<(Vec<u8>, peer::Handle)>,
+}
+
+impl Node {
+    fn start(index: usize, spec: &ChainSpec, network: &MemoryNetwork) -> Self {
+        let ip = [10, 0, (index / 250) as u8, (index % 250) as u8 + 1];
+        let addr = SocketAddr::from((ip, P2P_PORT));
+        let blockchain = Arc::new(Mutex::new(Blockchain::from_spec(spec)));
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        let (inbox, msg_rx) = smol::channel::bounded(10000);
+        let (server_ctx, server) = server::new(
+            addr,
+            inbox.clone(),
+            BanList::new(100, Duration::from_secs(3600)),
+            envelope::REGTEST_MAGIC,
+            None,
+            Arc::new(network.transport(ip.into())),
+        )
+        .unwrap();
+        server_ctx.start().unwrap();
+        let orphan_buffer = Arc::new(Mutex::new(HashMap::new()));
+        NetworkWorker::new(1, msg_rx, &server, &blockchain, &mempool, &orphan_buffer).start();
+
+        // every node mines to an address of its own, so that their blocks differ
+        let coinbase = Address::from_public_key_bytes(key_pair::random().public_key().as_ref());
+        let (miner_ctx, miner, finished_block_chan) =
+            miner::new(&blockchain, &mempool, 1, coinbase, Policy::default());
+        let miner_worker =
+            miner::worker::Worker::new(&server, finished_block_chan, &blockchain, &mempool);
+        let external_miner = miner::external::Handle::new(
+            &blockchain,
+            &mempool,
+            &miner_worker,
+            coinbase,
+            Policy::default(),
+            true,
+        );
+        miner_ctx.start();
+        miner_worker.start();
+
+        Self {
+            addr,
+            blockchain,
+            mempool,
+            server,
+            miner,
+            external_miner,
+            inbox,
+        }
+    }
+
+    pub fn tip(&self) -> H256 {
+        self.blockchain.lock().unwrap().tip()
+    }
+}
+
+pub struct Simulation {
+    nodes: Vec<Node>,
+}
+
+impl Simulation {
+    /// Start `nodes` nodes, each one connected to all the others. Every node delays its messages
+    /// by `latency_ms` and loses `drop_percent` percent of them, see `set_faults`.
+    pub fn new(nodes: usize, latency_ms: u64, drop_percent: f64) -> Self {
+        let spec = ChainSpec {
+            difficulty: [255u8; 32].into(),
+            ..Default::default()
+        };
+        let network = MemoryNetwork::new();
+        let simulation = Self {
+            nodes: (0..nodes).map(|i| Node::start(i, &spec, &network)).collect(),
+        };
+        simulation.set_faults(latency_ms, drop_percent);
+        for a in 0..nodes {
+            for b in a + 1..nodes {
+                simulation.connect(a, b);
+            }
+        }
+        info!("Started a simulation of {} nodes", nodes);
+        simulation
+    }
+
+    /// Connect two nodes, and wait until both of them have registered the other one.
+    pub fn connect(&self, a: usize, b: usize) {
+        let (a, b) = (&self.nodes[a], &self.nodes[b]);
+        a.server.connect(b.addr).unwrap();
+        // b registers a once its server gets to the connection, from a port of a's IP
+        while !b
+            .server
+            .peers()
+            .iter()
+            .any(|p| p.addr.parse::<SocketAddr>().unwrap().ip() == a.addr.ip())
+        {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    pub fn node(&self, index: usize) -> &Node {
+        &self.nodes[index]
+    }
+
+    /// Change the faults of every node. A message gets the faults of both its sender and its
+    /// receiver, so it is delayed by twice `latency_ms`. Messages already on their way keep
+    /// their delay.
+    pub fn set_faults(&self, latency_ms: u64, drop_percent: f64) {
+        for node in &self.nodes {
+            node.server
+                .faults()
+                .set(None, Some(latency_ms), Some(drop_percent));
+        }
+    }
+
+    /// Mine `n` blocks one after the other on the tip of a node, and broadcast them. Returns
+    /// their hashes.
+    pub fn mine(&self, node: usize, n: usize) -> Vec<H256> {
+        self.nodes[node].external_miner.generate(n).unwrap()
+    }
+
+    /// Start the miner of every node, each one trying a nonce, and so finding a block, every
+    /// `lambda` microseconds.
+    pub fn start_mining(&self, lambda: u64) {
+        for node in &self.nodes {
+            node.miner.start(lambda);
+        }
+    }
+
+    pub fn stop_mining(&self) {
+        for node in &self.nodes {
+            node.miner.pause();
+        }
+    }
+
+    pub fn tips(&self) -> Vec<H256> {
+        self.nodes.iter().map(|node| node.tip()).collect()
+    }
+
+    /// Wait until every node has the same tip, and return it, or `None` on timeout.
+    pub fn wait_for_convergence(&self, timeout: Duration) -> Option<H256> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let tips = self.tips();
+            if tips.iter().all(|tip| *tip == tips[0]) {
+                return Some(tips[0]);
+            }
+            if Instant::now() > deadline {
+                return None;
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    /// Wait until every node has the same tip, and return it. Panics with the tips of the nodes
+    /// on timeout.
+    pub fn assert_converged(&self, timeout: Duration) -> H256 {
+        match self.wait_for_convergence(timeout) {
+            Some(tip) => tip,
+            None => panic!("nodes did not converge, tips are {:?}", self.tips()),
+        }
+    }
+
+    /// Wait until every node has a block, returning false on timeout.
+    pub fn wait_for_block(&self, hash: &H256, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let everywhere = self
+                .nodes
+                .iter()
+                .all(|node| node.blockchain.lock().unwrap().block_map.contains_key(hash));
+            if everywhere {
+                return true;
+            }
+            if Instant::now() > deadline {
+                return false;
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    /// Pause the miners and stop the network workers. The nodes no longer take in blocks, and
+    /// their chains are left as they are.
+    pub fn stop(&self) {
+        for node in &self.nodes {
+            node.miner.pause();
+            node.inbox.close();
+        }
+    }
+}
+
+impl Drop for Simulation {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ntest::timeout;
+
+    #[test]
+    #[timeout(60000)]
+    fn converge_after_mining() {
+        let simulation = Simulation::new(3, 10, 0.0);
+        simulation.mine(0, 5);
+        let tip = simulation.assert_converged(Duration::from_secs(10));
+        let hashes = simulation.mine(2, 3);
+        assert_eq!(hashes.len(), 3);
+        assert_eq!(simulation.assert_converged(Duration::from_secs(10)), hashes[2]);
+        let chain = simulation.node(1).blockchain.lock().unwrap();
+        assert_eq!(chain.block_heights[&hashes[2]], 8);
+        assert_eq!(chain.ancestor(&hashes[2], 5), Some(tip));
+        drop(chain);
+
+        // once stopped, the nodes no longer take in the blocks of the others
+        simulation.stop();
+        let late = simulation.mine(0, 1)[0];
+        assert!(!simulation.wait_for_block(&late, Duration::from_millis(500)));
+    }
+
+    #[test]
+    #[timeout(120000)]
+    fn converge_despite_forks_and_drops() {
+        let simulation = Simulation::new(4, 25, 5.0);
+        simulation.start_mining(100_000);
+        thread::sleep(Duration::from_secs(2));
+        simulation.stop_mining();
+        let mined = {
+            let chain = simulation.node(0).blockchain.lock().unwrap();
+            chain.block_heights[&chain.tip()]
+        };
+        assert!(mined > 0);
+
+        // once the links are reliable, a block from every node in turn leaves a single longest
+        // chain, which every node fetches
+        simulation.set_faults(25, 0.0);
+        let faults = simulation.node(0).server.faults().get(&simulation.node(1).addr);
+        assert_eq!(faults.drop_percent, 0.0);
+        let mut last = H256::default();
+        for node in 0..4 {
+            last = simulation.mine(node, 1)[0];
+            assert!(simulation.wait_for_block(&last, Duration::from_secs(20)));
+        }
+        assert_eq!(simulation.assert_converged(Duration::from_secs(20)), last);
+    }
+}
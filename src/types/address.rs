@@ -1,9 +1,8 @@
 use ring::digest;
 use serde::{Deserialize, Serialize};
-use std::convert::TryInto;
 
 // 20-byte address
-#[derive(Eq, PartialEq, Serialize, Deserialize, Clone, Hash, Default, Copy)]
+#[derive(Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize, Clone, Hash, Default, Copy)]
 pub struct Address(pub [u8; 20]);
 
 impl std::convert::From<&[u8; 20]> for Address {
@@ -38,6 +37,17 @@ impl std::fmt::Display for Address {
     }
 }
 
+impl std::str::FromStr for Address {
+    type Err = hex::FromHexError;
+
+    /// Parse the hex format produced by `Display`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut bytes = [0u8; 20];
+        hex::decode_to_slice(s, &mut bytes)?;
+        Ok(Address(bytes))
+    }
+}
+
 impl std::fmt::Debug for Address {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
@@ -49,12 +59,12 @@ impl std::fmt::Debug for Address {
 }
 
 impl Address {
+    /// The last 20 bytes of the SHA256 of a public key, whatever its length.
     pub fn from_public_key_bytes(bytes: &[u8]) -> Address {
-        Address(
-            digest::digest(&digest::SHA256, bytes).as_ref()[bytes.len() - 20..]
-                .try_into()
-                .unwrap(),
-        )
+        let digest = digest::digest(&digest::SHA256, bytes);
+        let mut address = [0u8; 20];
+        address.copy_from_slice(&digest.as_ref()[12..]);
+        Address(address)
     }
 }
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
@@ -2,6 +2,7 @@ use crate::types::hash::{Hashable, H256};
 use ring::digest;
 use serde::{Deserialize, Serialize};
 
+use super::address::Address;
 use super::transaction::SignedTransaction;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -9,8 +10,11 @@ pub struct Header {
     pub(crate) parent: H256,
     pub(crate) nonce: u32,
     pub(crate) difficulty: H256,
-    pub(crate) timestamp: u32,
+    /// Unix time in milliseconds
+    pub(crate) timestamp: u64,
     pub(crate) merkle_root: H256,
+    /// Account credited with the block reward
+    pub(crate) coinbase: Address,
 }
 
 impl Hashable for Header {
@@ -36,6 +40,15 @@ impl Hashable for Block {
     }
 }
 
+/// Current Unix time in milliseconds.
+pub fn unix_millis() -> u64 {
+    use std::time::{SystemTime, UNIX_EPOCH};
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
 impl Block {
     pub fn get_parent(&self) -> H256 {
         self.header.parent
@@ -51,20 +64,30 @@ pub fn generate_random_block(parent: &H256) -> Block {
     use crate::types::merkle::MerkleTree;
 
     let tx: Vec<H256> = Vec::new();
-    let mut nonce: u32 = rand::random();
-    let mut bytes = [u8::MAX; 32];
-    bytes[0] = 0;
-    bytes[1] = 0;
-    let difficulty: H256 = bytes.into();
+    let nonce: u32 = rand::random();
+    // same difficulty as the genesis block, so that any nonce is a valid PoW
+    let difficulty: H256 = [u8::MAX; 32].into();
     let empty_tree = MerkleTree::new(&tx);
     let merkle_root = empty_tree.root();
+    // strictly increasing, so that a chain of random blocks passes the median time past check
+    static LAST_TIMESTAMP: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+    let now = unix_millis();
+    let timestamp = LAST_TIMESTAMP
+        .fetch_update(
+            std::sync::atomic::Ordering::SeqCst,
+            std::sync::atomic::Ordering::SeqCst,
+            |last| Some(now.max(last + 1)),
+        )
+        .map(|last| now.max(last + 1))
+        .unwrap();
     Block {
         header: Header {
             parent: *parent,
             nonce,
             difficulty,
-            timestamp: 0,
+            timestamp,
             merkle_root: merkle_root,
+            coinbase: Address::default(),
         },
         data: { Vec::new() },
     }
@@ -1,5 +1,6 @@
 use ring::rand;
 use ring::signature::Ed25519KeyPair;
+use ring::digest;
 
 /// Generate a random key pair.
 pub fn random() -> Ed25519KeyPair {
@@ -7,3 +8,10 @@ pub fn random() -> Ed25519KeyPair {
     let pkcs8_bytes = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
     Ed25519KeyPair::from_pkcs8(pkcs8_bytes.as_ref().into()).unwrap()
 }
+
+/// Derive a key pair from a seed. The same seed always gives the same key pair, so this must
+/// only be used for well-known keys such as the ICO accounts of a test network.
+pub fn from_seed(seed: &[u8]) -> Ed25519KeyPair {
+    let seed = digest::digest(&digest::SHA256, seed);
+    Ed25519KeyPair::from_seed_unchecked(seed.as_ref()).unwrap()
+}
@@ -1,9 +1,8 @@
-use core::time;
-use std::sync::{Arc, Mutex};
-use std::{collections::HashMap, thread};
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
 
-use crate::{network::message, types::address::Address};
-use rand::Rng;
+use crate::blockchain::state::{State, TxError};
+use crate::types::address::Address;
 use ring::{
     digest,
     error::Unspecified,
@@ -13,17 +12,27 @@ use serde::{Deserialize, Serialize};
 
 use super::hash::{Hashable, H256};
 
-use crate::network::server::Handle as NetworkServerHandle;
+/// How far ahead of the nonce of its sender a transaction from a peer may be.
+const MAX_NONCE_GAP: u32 = 64;
+/// Most transactions of one sender that wait for earlier ones.
+const MAX_FUTURE_PER_SENDER: usize = 64;
+/// How long a transaction may wait for a missing earlier one before it is dropped.
+const FUTURE_TX_EXPIRY: Duration = Duration::from_secs(600);
 
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct Mempool {
     pub tx_map: HashMap<H256, SignedTransaction>,
+    /// Order in which the transactions arrived
+    arrival: HashMap<H256, u64>,
+    next_arrival: u64,
+    /// When the transactions arrived
+    #[serde(skip)]
+    received: HashMap<H256, Instant>,
 }
 
 impl Mempool {
     pub fn new() -> Self {
-        let tx_map = HashMap::new();
-        Mempool { tx_map }
+        Default::default()
     }
 
     pub fn insert(&mut self, tx: &SignedTransaction) {
@@ -31,15 +40,104 @@ impl Mempool {
         let tx_hash = tx.hash();
         if !self.tx_map.contains_key(&tx_hash) {
             self.tx_map.insert(tx_hash.clone(), tx.clone());
+            self.arrival.insert(tx_hash, self.next_arrival);
+            self.received.insert(tx_hash, Instant::now());
+            self.next_arrival += 1;
         }
     }
 
+    /// Insert a transaction from a peer if it can be executed on top of `state`, now or after
+    /// transactions of its sender with lower nonces. A transaction that waits is refused if it
+    /// is too far ahead, if its sender has too many waiting already, or if its sender cannot
+    /// pay for it on top of the transactions it has in the mempool.
+    pub fn admit(&mut self, tx: &SignedTransaction, state: &State) -> Result<(), TxError> {
+        match state.check(tx) {
+            Ok(()) => {}
+            Err(TxError::FutureNonce) => {
+                let transaction = &tx.transaction;
+                let (nonce, balance) = state.account(&transaction.sender);
+                if transaction.account_nonce - nonce > MAX_NONCE_GAP {
+                    return Err(TxError::FutureNonce);
+                }
+                let mut waiting = 0;
+                let mut spent = transaction.value;
+                for pending in self.tx_map.values() {
+                    if pending.transaction.sender == transaction.sender {
+                        if pending.transaction.account_nonce > nonce + 1 {
+                            waiting += 1;
+                        }
+                        spent = spent.saturating_add(pending.transaction.value);
+                    }
+                }
+                if waiting >= MAX_FUTURE_PER_SENDER {
+                    return Err(TxError::FutureNonce);
+                }
+                if spent > balance {
+                    return Err(TxError::InsufficientBalance);
+                }
+            }
+            Err(e) => return Err(e),
+        }
+        self.insert(tx);
+        Ok(())
+    }
+
     pub fn remove(&mut self, tx: &SignedTransaction) {
         // remove a tx from the mempool
         let tx_hash = tx.hash();
-        if !self.tx_map.contains_key(&tx_hash) {
-            self.tx_map.remove(&tx_hash);
+        self.tx_map.remove(&tx_hash);
+        self.arrival.remove(&tx_hash);
+        self.received.remove(&tx_hash);
+    }
+
+    /// Drop the transactions that can never be executed on top of `state`, because their sender
+    /// already used their nonce, and those that waited more than `FUTURE_TX_EXPIRY` for an
+    /// earlier transaction that never came. Called whenever the tip moves.
+    pub fn prune(&mut self, state: &State) {
+        let mut expired = Vec::new();
+        for (sender, txs) in self.by_sender() {
+            // the highest nonce the transactions in the mempool lead to
+            let (mut next, _) = state.account(&sender);
+            for tx in txs {
+                let nonce = tx.transaction.account_nonce;
+                if nonce <= next + 1 {
+                    next = next.max(nonce);
+                    continue;
+                }
+                let hash = tx.hash();
+                let waited = self.received.get(&hash).map_or(Duration::default(), |t| t.elapsed());
+                if waited > FUTURE_TX_EXPIRY {
+                    expired.push(hash);
+                }
+            }
+        }
+        for hash in expired {
+            self.tx_map.remove(&hash);
         }
+        self.tx_map.retain(|_, tx| {
+            let (nonce, _) = state.account(&tx.transaction.sender);
+            tx.transaction.account_nonce > nonce
+        });
+        let tx_map = &self.tx_map;
+        self.arrival.retain(|hash, _| tx_map.contains_key(hash));
+        self.received.retain(|hash, _| tx_map.contains_key(hash));
+    }
+
+    /// Position of a transaction in the arrival order, lower is earlier.
+    pub fn arrival(&self, hash: &H256) -> u64 {
+        self.arrival.get(hash).copied().unwrap_or(u64::MAX)
+    }
+
+    /// The transactions of each sender, ordered by nonce.
+    pub fn by_sender(&self) -> BTreeMap<Address, Vec<&SignedTransaction>> {
+        let mut by_sender: BTreeMap<Address, Vec<&SignedTransaction>> = BTreeMap::new();
+        for tx in self.tx_map.values() {
+            by_sender.entry(tx.transaction.sender).or_default().push(tx);
+        }
+        for txs in by_sender.values_mut() {
+            txs.sort_by_key(|tx| tx.transaction.account_nonce);
+        }
+        by_sender
     }
 }
 
@@ -47,46 +145,21 @@ impl Mempool {
 pub struct Transaction {
     pub sender: Address,
     pub receiver: Address,
-    pub value: u8,
+    pub value: u64,
+    /// Must be one more than the nonce of the sender account when the transaction is executed.
+    pub account_nonce: u32,
 }
 
-pub struct TransactionGenerator {}
-
-impl TransactionGenerator {
-    pub fn start(theta: u64, network: NetworkServerHandle, mempool: Arc<Mutex<Mempool>>) {
-        use crate::types::key_pair;
-        thread::spawn(move || loop {
-            let mut rng = rand::thread_rng();
-            let random_bytes: Vec<u8> = (0..32).map(|_| rng.gen()).collect();
-            let random_bytes1: Vec<u8> = (0..32).map(|_| rng.gen()).collect();
-            let sender_addr = Address::from_public_key_bytes(&random_bytes);
-            let receiver_addr = Address::from_public_key_bytes(&random_bytes1);
-            let transaction = Transaction {
-                sender: sender_addr,
-                receiver: receiver_addr,
-                value: 10,
-            };
-            let key = key_pair::random();
-            let signature = sign(&transaction, &key).as_ref().to_vec();
-            let public_key = key.public_key().as_ref().to_vec();
-            let signed_tx = SignedTransaction {
-                public_key,
-                signature,
-                transaction,
-            };
-            // add tx to mempool
-            let mut unwrapped_mempool = mempool.lock().unwrap();
-            unwrapped_mempool.insert(&signed_tx);
-            drop(unwrapped_mempool);
-            // broadcast the tx hash to network
-            network.broadcast(message::Message::NewTransactionHashes(vec![
-                signed_tx.hash()
-            ]));
-            if theta != 0 {
-                let interval = time::Duration::from_micros(theta as u64);
-                thread::sleep(interval);
-            }
-        });
+impl Transaction {
+    /// The bytes covered by the signature.
+    fn to_bytes(&self) -> Vec<u8> {
+        [
+            &self.sender.0[..],
+            &self.receiver.0[..],
+            &self.value.to_be_bytes(),
+            &self.account_nonce.to_be_bytes(),
+        ]
+        .concat()
     }
 }
 
@@ -97,6 +170,18 @@ pub struct SignedTransaction {
     pub public_key: Vec<u8>,
 }
 
+impl SignedTransaction {
+    /// Sign a transaction with the key of its sender.
+    pub fn new(transaction: Transaction, key: &Ed25519KeyPair) -> Self {
+        let signature = sign(&transaction, key).as_ref().to_vec();
+        SignedTransaction {
+            transaction,
+            signature,
+            public_key: key.public_key().as_ref().to_vec(),
+        }
+    }
+}
+
 impl Hashable for SignedTransaction {
     fn hash(&self) -> H256 {
         // serialize SignedTransaction into bytes
@@ -109,18 +194,13 @@ impl Hashable for SignedTransaction {
 
 /// Create digital signature of a transaction
 pub fn sign(t: &Transaction, key: &Ed25519KeyPair) -> Signature {
-    let sender = t.sender.0.as_ref();
-    let receiver = t.receiver.0.as_ref();
-    let tx_array = [&sender[..], &receiver[..], &[t.value]].concat();
-    key.sign(&tx_array[..])
+    key.sign(&t.to_bytes())
 }
 
 /// Verify digital signature of a transaction, using public key instead of secret key
 pub fn verify(t: &Transaction, public_key: &[u8], signature: &[u8]) -> bool {
     // create tx message byte array
-    let sender = t.sender.0.as_ref();
-    let receiver = t.receiver.0.as_ref();
-    let tx_array = [&sender[..], &receiver[..], &[t.value]].concat();
+    let tx_array = t.to_bytes();
 
     let pk_vector: Vec<u8> = public_key.as_ref().to_vec();
     let signature_vector: Vec<u8> = signature.as_ref().to_vec();
@@ -139,6 +219,8 @@ pub fn verify(t: &Transaction, public_key: &[u8], signature: &[u8]) -> bool {
 
 #[cfg(any(test, test_utilities))]
 pub fn generate_random_transaction() -> Transaction {
+    use rand::Rng;
+
     let mut rng = rand::thread_rng();
     let random_bytes: Vec<u8> = (0..32).map(|_| rng.gen()).collect();
     let random_bytes1: Vec<u8> = (0..32).map(|_| rng.gen()).collect();
@@ -149,6 +231,7 @@ pub fn generate_random_transaction() -> Transaction {
         sender: sender_addr,
         receiver: receiver_addr,
         value: 0,
+        account_nonce: 0,
     }
 }
 
@@ -177,6 +260,49 @@ mod tests {
         assert!(!verify(&t_2, key.public_key().as_ref(), signature.as_ref()));
         assert!(!verify(&t, key_2.public_key().as_ref(), signature.as_ref()));
     }
+
+    #[test]
+    fn admit_waiting_transactions() {
+        use crate::blockchain::spec::ChainSpec;
+        use crate::blockchain::state::{ico_key, ICO_BALANCE};
+
+        let state = ChainSpec::default().genesis_state();
+        let key = ico_key(0);
+        let transfer = |value, nonce| {
+            let transaction = Transaction {
+                sender: Address::from_public_key_bytes(key.public_key().as_ref()),
+                receiver: Address::default(),
+                value,
+                account_nonce: nonce,
+            };
+            SignedTransaction::new(transaction, &key)
+        };
+        let mut mempool = Mempool::new();
+        assert_eq!(mempool.admit(&transfer(1, 0), &state), Err(TxError::StaleNonce));
+        assert_eq!(mempool.admit(&transfer(1, MAX_NONCE_GAP + 1), &state), Err(TxError::FutureNonce));
+        assert_eq!(mempool.admit(&transfer(1, MAX_NONCE_GAP), &state), Ok(()));
+        // the waiting transactions together cannot spend more than the balance
+        assert_eq!(mempool.admit(&transfer(ICO_BALANCE, 2), &state), Err(TxError::InsufficientBalance));
+        for nonce in 2..MAX_NONCE_GAP {
+            assert_eq!(mempool.admit(&transfer(1, nonce), &state), Ok(()));
+        }
+        // transactions with a nonce already taken count too, up to the limit of the sender
+        assert_eq!(mempool.admit(&transfer(2, 2), &state), Ok(()));
+        assert_eq!(mempool.admit(&transfer(3, 2), &state), Err(TxError::FutureNonce));
+        assert_eq!(mempool.admit(&transfer(1, 1), &state), Ok(()));
+        assert_eq!(mempool.tx_map.len(), MAX_FUTURE_PER_SENDER + 1);
+
+        // without nonce 1, the others wait for it until they expire
+        mempool.remove(&transfer(1, 1));
+        mempool.prune(&state);
+        assert_eq!(mempool.tx_map.len(), MAX_FUTURE_PER_SENDER);
+        let long_ago = Instant::now().checked_sub(FUTURE_TX_EXPIRY * 2).unwrap();
+        for received in mempool.received.values_mut() {
+            *received = long_ago;
+        }
+        mempool.prune(&state);
+        assert!(mempool.tx_map.is_empty());
+    }
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST